@@ -0,0 +1,132 @@
+// Time and sales storage against the Vec it replaced: every reconnect appended with extend and
+// every query scanned the whole Vec.
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use tradovate_api::{
+    clock::ManualClock,
+    models::SimpleQuote,
+    time_and_sales::{OrderAction, TimeAndSalesCollection, TimeAndSalesItem, TimeWindow},
+    trade_classifier::ClassificationRule,
+};
+
+const CONTRACTS: i64 = 2;
+// one print every 10ms
+const SPACING_MILLIS: i64 = 10;
+
+fn items(count: i64) -> Vec<TimeAndSalesItem> {
+    (0..count)
+        .map(|n| TimeAndSalesItem {
+            historical_id: 1,
+            contract_id: n % CONTRACTS,
+            tick_id: n,
+            action: if n % 3 == 0 {
+                OrderAction::Sell
+            } else {
+                OrderAction::Buy
+            },
+            qty: 1 + n % 7,
+            price: 4500.0 + (n % 40) as f64 * 0.25,
+            bid: 4500.0,
+            ask: 4500.25,
+            timestamp: n * SPACING_MILLIS,
+            receipt_delay: 0,
+            trade_date: 0,
+            classified_by: ClassificationRule::Quote,
+            confidence: 1.0,
+        })
+        .collect()
+}
+
+// The storage before the redesign
+struct VecStorage {
+    items: Vec<TimeAndSalesItem>,
+}
+impl VecStorage {
+    fn window_volume(&self, contract_id: i64, from: i64) -> i64 {
+        self.items
+            .iter()
+            .rev()
+            .filter(|item| item.contract_id == contract_id && item.timestamp >= from)
+            .map(|item| item.qty)
+            .sum()
+    }
+    fn quote(&self, contract_id: i64) -> Option<SimpleQuote> {
+        self.items
+            .iter()
+            .rev()
+            .find(|item| item.contract_id == contract_id)
+            .map(|item| SimpleQuote {
+                bid: item.bid,
+                ask: item.ask,
+            })
+    }
+}
+
+fn ingest(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ingest");
+    for count in [10_000, 100_000] {
+        let batch = items(count);
+        group.bench_with_input(BenchmarkId::new("vec", count), &batch, |b, batch| {
+            b.iter(|| {
+                let mut storage = VecStorage { items: Vec::new() };
+                // a reconnect delivers the same ticks again
+                storage.items.extend(batch.iter().cloned());
+                storage.items.extend(batch.iter().cloned());
+                black_box(storage.items.len())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("partitioned", count), &batch, |b, batch| {
+            b.iter(|| {
+                let mut storage = TimeAndSalesCollection::new();
+                storage.merge(batch.clone());
+                storage.merge(batch.clone());
+                black_box(storage.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn one_second_window(c: &mut Criterion) {
+    let mut group = c.benchmark_group("one_second_window");
+    for count in [10_000, 100_000] {
+        let batch = items(count);
+        let now = count * SPACING_MILLIS;
+        let vec_storage = VecStorage {
+            items: batch.clone(),
+        };
+        let mut storage = TimeAndSalesCollection::new();
+        storage.merge(batch);
+        let clock = ManualClock::new(now);
+        group.bench_function(BenchmarkId::new("vec", count), |b| {
+            b.iter(|| black_box(vec_storage.window_volume(0, now - 1000)))
+        });
+        group.bench_function(BenchmarkId::new("partitioned", count), |b| {
+            b.iter(|| black_box(storage.get_pressure(0, TimeWindow::OneSecond, &clock)))
+        });
+    }
+    group.finish();
+}
+
+fn quote(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quote");
+    for count in [10_000, 100_000] {
+        let batch = items(count);
+        let vec_storage = VecStorage {
+            items: batch.clone(),
+        };
+        let mut storage = TimeAndSalesCollection::new();
+        storage.merge(batch);
+        // the contract that did not trade last
+        let contract_id = (count - 2) % CONTRACTS;
+        group.bench_function(BenchmarkId::new("vec", count), |b| {
+            b.iter(|| black_box(vec_storage.quote(contract_id)))
+        });
+        group.bench_function(BenchmarkId::new("partitioned", count), |b| {
+            b.iter(|| black_box(storage.get_quote(contract_id)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, ingest, one_second_window, quote);
+criterion_main!(benches);
@@ -0,0 +1,260 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    accounting::Ledger,
+    api_client::ResourceType,
+    md_socket::MarketDataRWL,
+    models::{AcctCommChannelRWL, SimpleQuote, UserData},
+    reporting::{program_log, send_txt_message, ReportRWL},
+    settings::{
+        ACCOUNT_MONITOR_MILLIS, ALERT_PHONE_NUMBER, AUTO_LIQ_DISTANCE_ALERT, DAILY_LOSS_ALERTS,
+        MARGIN_UTILIZATION_ALERTS,
+    },
+};
+
+pub type AccountRiskMonitorRWL = Arc<tokio::sync::RwLock<AccountRiskMonitor>>;
+
+// Balance, P&L and margin of one account as of the last update
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountSnapshot {
+    pub account_id: i64,
+    pub name: String,
+    // cash balance, the day's realized pnl is already in it
+    pub balance: f64,
+    pub realized_pnl: f64,
+    pub open_pnl: f64,
+    pub initial_margin: f64,
+    pub maintenance_margin: f64,
+    pub total_used_margin: f64,
+    // equity levels at which Tradovate makes the account liquidate only or liquidates it
+    pub liq_only_level: f64,
+    pub auto_liq_level: f64,
+    // the day's loss at which Tradovate liquidates the account
+    pub daily_loss_auto_liq: Option<f64>,
+    pub daily_loss_alert: Option<f64>,
+    pub liquidate_only: bool,
+}
+impl AccountSnapshot {
+    pub fn equity(&self) -> f64 {
+        self.balance + self.open_pnl
+    }
+    pub fn daily_pnl(&self) -> f64 {
+        self.realized_pnl + self.open_pnl
+    }
+    pub fn available_margin(&self) -> f64 {
+        self.equity() - self.total_used_margin
+    }
+    // share of equity held as margin, None without equity
+    pub fn margin_utilization(&self) -> Option<f64> {
+        let equity = self.equity();
+        (equity > 0.0).then(|| self.total_used_margin / equity)
+    }
+    pub fn distance_to_auto_liq(&self) -> Option<f64> {
+        let by_equity = (self.auto_liq_level > 0.0).then(|| self.equity() - self.auto_liq_level);
+        let by_daily_loss = self
+            .daily_loss_auto_liq
+            .map(|limit| limit + self.daily_pnl());
+        match (by_equity, by_daily_loss) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+    pub fn distance_to_liq_only(&self) -> Option<f64> {
+        (self.liq_only_level > 0.0).then(|| self.equity() - self.liq_only_level)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    MarginUtilization,
+    DailyLoss,
+    AutoLiqDistance,
+    LiquidateOnly,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountAlert {
+    pub account_id: i64,
+    pub account_name: String,
+    pub kind: AlertKind,
+    pub level: f64,
+    pub value: f64,
+}
+impl std::fmt::Display for AccountAlert {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            AlertKind::MarginUtilization => write!(
+                f,
+                "Account {} is using {:.0}% of its equity as margin, above {:.0}%",
+                self.account_name,
+                self.value * 100.0,
+                self.level * 100.0
+            ),
+            AlertKind::DailyLoss => write!(
+                f,
+                "Account {} is down {:.2} today, past {:.2}",
+                self.account_name, self.value, self.level
+            ),
+            AlertKind::AutoLiqDistance => write!(
+                f,
+                "Account {} is {:.2} away from auto liquidation",
+                self.account_name, self.value
+            ),
+            AlertKind::LiquidateOnly => {
+                write!(f, "Account {} is liquidate only", self.account_name)
+            }
+        }
+    }
+}
+
+// Snapshots of every synced account and the alerts raised on them. An alert is raised once when
+// its level is crossed and again only after the account has come back under it.
+#[derive(Debug, Default)]
+pub struct AccountRiskMonitor {
+    snapshots: HashMap<i64, AccountSnapshot>,
+    raised: HashSet<(i64, AlertKind, u64)>,
+}
+impl AccountRiskMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn snapshot(&self, account_id: i64) -> Option<&AccountSnapshot> {
+        self.snapshots.get(&account_id)
+    }
+    pub fn snapshot_by_name(&self, name: &str) -> Option<&AccountSnapshot> {
+        self.snapshots.values().find(|snapshot| snapshot.name == name)
+    }
+    pub fn snapshots(&self) -> Vec<AccountSnapshot> {
+        let mut snapshots: Vec<AccountSnapshot> = self.snapshots.values().cloned().collect();
+        snapshots.sort_by_key(|snapshot| snapshot.account_id);
+        snapshots
+    }
+    pub fn update(
+        &mut self,
+        user_data: &UserData,
+        ledger: &Ledger,
+        quote_of: impl Fn(i64) -> Option<SimpleQuote>,
+    ) -> Vec<AccountAlert> {
+        let mut alerts = Vec::new();
+        for account in &user_data.accounts {
+            let mut snapshot = AccountSnapshot {
+                account_id: account.id,
+                name: account.name.clone(),
+                open_pnl: ledger.account_pnl(account.id, &quote_of).unrealized,
+                ..Default::default()
+            };
+            if let Some(balance) = user_data
+                .cash_balances
+                .iter()
+                .find(|balance| balance.account_id == account.id)
+            {
+                snapshot.balance = balance.amount;
+                snapshot.realized_pnl = balance.realized_pn_l;
+            }
+            if let Some(margin) = user_data
+                .margin_snapshots
+                .iter()
+                .find(|margin| margin.id == account.id)
+            {
+                snapshot.initial_margin = margin.initial_margin;
+                snapshot.maintenance_margin = margin.maintenance_margin;
+                snapshot.total_used_margin = margin.total_used_margin;
+                snapshot.liq_only_level = margin.liq_only_level;
+                snapshot.auto_liq_level = margin.auto_liq_level;
+            }
+            if let Some(auto_liq) = user_data
+                .user_account_auto_liqs
+                .iter()
+                .find(|auto_liq| auto_liq.id == account.id)
+            {
+                snapshot.daily_loss_auto_liq = auto_liq.daily_loss_auto_liq;
+                snapshot.daily_loss_alert = auto_liq.daily_loss_alert;
+            }
+            if let Some(status) = user_data
+                .account_risk_statuses
+                .iter()
+                .find(|status| status.id == account.id)
+            {
+                snapshot.liquidate_only =
+                    status.liquidate_only.is_some() || status.user_triggered_liq_only;
+            }
+            alerts.extend(self.check(&snapshot));
+            self.snapshots.insert(account.id, snapshot);
+        }
+        alerts
+    }
+    fn check(&mut self, snapshot: &AccountSnapshot) -> Vec<AccountAlert> {
+        let mut crossings = Vec::new();
+        if let Some(utilization) = snapshot.margin_utilization() {
+            for level in MARGIN_UTILIZATION_ALERTS {
+                crossings.push((AlertKind::MarginUtilization, level, utilization, utilization >= level));
+            }
+        }
+        let loss = -snapshot.daily_pnl();
+        for level in DAILY_LOSS_ALERTS.iter().copied().chain(snapshot.daily_loss_alert) {
+            crossings.push((AlertKind::DailyLoss, level, loss, loss >= level));
+        }
+        if let Some(distance) = snapshot.distance_to_auto_liq() {
+            crossings.push((
+                AlertKind::AutoLiqDistance,
+                AUTO_LIQ_DISTANCE_ALERT,
+                distance,
+                distance <= AUTO_LIQ_DISTANCE_ALERT,
+            ));
+        }
+        crossings.push((AlertKind::LiquidateOnly, 0.0, 0.0, snapshot.liquidate_only));
+        let mut alerts = Vec::new();
+        for (kind, level, value, crossed) in crossings {
+            let key = (snapshot.account_id, kind, level.to_bits());
+            if !crossed {
+                self.raised.remove(&key);
+            } else if self.raised.insert(key) {
+                alerts.push(AccountAlert {
+                    account_id: snapshot.account_id,
+                    account_name: snapshot.name.clone(),
+                    kind,
+                    level,
+                    value,
+                });
+            }
+        }
+        alerts
+    }
+}
+
+// Refreshes the snapshots from the synced user data, the fill ledger and the latest quotes,
+// and logs and texts every alert
+pub async fn watch_accounts(
+    monitor_rwl: AccountRiskMonitorRWL,
+    market_data_rwl: MarketDataRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+    report_rwl: ReportRWL,
+) {
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_millis(ACCOUNT_MONITOR_MILLIS));
+    loop {
+        interval.tick().await;
+        let market_data = market_data_rwl.read().await;
+        let account_data = acct_comms_channel.read().await;
+        let user_data = match &account_data.user_data {
+            Some(user_data) => user_data,
+            None => continue,
+        };
+        let alerts = monitor_rwl
+            .write()
+            .await
+            .update(&user_data.data, &account_data.ledger, |contract_id| {
+                market_data.time_and_sales.get_quote(contract_id)
+            });
+        drop(account_data);
+        drop(market_data);
+        for alert in alerts {
+            let message = alert.to_string();
+            program_log(&message, ResourceType::Trading, report_rwl.clone()).await;
+            let _ = send_txt_message(ALERT_PHONE_NUMBER, &message).await;
+        }
+    }
+}
@@ -0,0 +1,564 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    instrument_catalog::InstrumentCatalog,
+    models::{Fill, PropsEvent, SimpleQuote, UserData},
+    price::{PriceSpec, Rounding},
+    settings::{DEFAULT_FEES, FEE_SCHEDULES, RECONCILE_TOLERANCE},
+};
+
+// Per contract, per side
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FeeSchedule {
+    pub exchange: f64,
+    pub clearing: f64,
+    pub nfa: f64,
+    pub commission: f64,
+}
+impl FeeSchedule {
+    pub fn per_side(&self) -> f64 {
+        self.exchange + self.clearing + self.nfa + self.commission
+    }
+    pub fn for_product(product: &str) -> Self {
+        FEE_SCHEDULES
+            .iter()
+            .find(|(name, _)| *name == product)
+            .map_or(DEFAULT_FEES, |(_, fees)| *fees)
+    }
+}
+
+// What the ledger needs to value a contract's fills
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContractTerms {
+    pub symbol: String,
+    pub product: String,
+    pub spec: PriceSpec,
+    pub fees: FeeSchedule,
+    pub initial_margin: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lot {
+    pub fill_id: i64,
+    // negative for a short
+    pub qty: i64,
+    pub price: f64,
+}
+
+// The fills of one account in one contract, matched first in first out
+#[derive(Debug, Clone, Default)]
+pub struct ContractBook {
+    pub account_id: i64,
+    pub contract_id: i64,
+    // open lots, oldest first and all on the same side
+    pub lots: VecDeque<Lot>,
+    // exit minus entry times qty over every matched lot, only counted for contracts with terms
+    pub realized_ticks: i64,
+    pub contracts_filled: i64,
+}
+impl ContractBook {
+    fn apply(&mut self, fill: &Fill, spec: Option<&PriceSpec>) {
+        self.contracts_filled += fill.qty;
+        let mut remaining = fill.signed_qty();
+        while remaining != 0 {
+            let lot = match self.lots.front_mut() {
+                Some(lot) if lot.qty.signum() != remaining.signum() => lot,
+                _ => break,
+            };
+            // signum of the lot, a long closed by a sell earns exit minus entry
+            let side = lot.qty.signum();
+            let matched = lot.qty.abs().min(remaining.abs()) * side;
+            if let Some(spec) = spec {
+                self.realized_ticks += spec.ticks_between(lot.price, fill.price) * matched;
+            }
+            lot.qty -= matched;
+            remaining += matched;
+            if lot.qty == 0 {
+                self.lots.pop_front();
+            }
+        }
+        if remaining != 0 {
+            self.lots.push_back(Lot {
+                fill_id: fill.id,
+                qty: remaining,
+                price: fill.price,
+            });
+        }
+    }
+    pub fn net_pos(&self) -> i64 {
+        self.lots.iter().map(|lot| lot.qty).sum()
+    }
+    pub fn avg_entry(&self) -> Option<f64> {
+        let net_pos = self.net_pos();
+        if net_pos == 0 {
+            return None;
+        }
+        let cost: f64 = self.lots.iter().map(|lot| lot.price * lot.qty as f64).sum();
+        Some(cost / net_pos as f64)
+    }
+    pub fn gross_realized(&self, terms: &ContractTerms) -> f64 {
+        round_cents(self.realized_ticks as f64 * terms.spec.tick_value())
+    }
+    pub fn fees(&self, terms: &ContractTerms) -> f64 {
+        round_cents(self.contracts_filled as f64 * terms.fees.per_side())
+    }
+    pub fn net_realized(&self, terms: &ContractTerms) -> f64 {
+        self.gross_realized(terms) - self.fees(terms)
+    }
+    // Longs are marked at the bid and shorts at the ask
+    pub fn unrealized(&self, quote: &SimpleQuote, terms: &ContractTerms) -> f64 {
+        let mark = match self.net_pos().cmp(&0) {
+            std::cmp::Ordering::Less => quote.ask,
+            std::cmp::Ordering::Equal => return 0.0,
+            std::cmp::Ordering::Greater => quote.bid,
+        };
+        let mark = terms.spec.price(mark, Rounding::Nearest);
+        self.lots
+            .iter()
+            .map(|lot| {
+                terms
+                    .spec
+                    .price(lot.price, Rounding::Nearest)
+                    .dollars_to(&mark, lot.qty)
+            })
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AccountPnl {
+    pub gross_realized: f64,
+    pub fees: f64,
+    pub unrealized: f64,
+}
+impl AccountPnl {
+    pub fn net_realized(&self) -> f64 {
+        self.gross_realized - self.fees
+    }
+    pub fn total(&self) -> f64 {
+        self.net_realized() + self.unrealized
+    }
+}
+
+// Where our books disagree with Tradovate's
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub account_id: i64,
+    pub contract_id: Option<i64>,
+    pub source: &'static str,
+    pub ours: f64,
+    pub theirs: f64,
+}
+impl std::fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Realized pnl of account {}{} differs from {}: ours {:.2}, theirs {:.2}",
+            self.account_id,
+            self.contract_id
+                .map_or(String::new(), |id| format!(" contract {}", id)),
+            self.source,
+            self.ours,
+            self.theirs
+        )
+    }
+}
+
+// Positions and P&L of every account built from individual fills
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    pub terms: HashMap<i64, ContractTerms>,
+    pub books: HashMap<(i64, i64), ContractBook>,
+    // fills only name their order, the account comes from the order
+    order_accounts: HashMap<i64, i64>,
+    // (account, contract) of every applied fill, to place fill pairs
+    fill_owners: HashMap<i64, (i64, i64)>,
+    // fills whose order has not been seen yet
+    unassigned: Vec<Fill>,
+}
+impl Ledger {
+    pub fn from_catalog(catalog: &InstrumentCatalog) -> Self {
+        let terms = catalog
+            .contracts
+            .keys()
+            .filter_map(|contract_id| catalog.instrument(*contract_id))
+            .map(|instrument| {
+                let terms = ContractTerms {
+                    symbol: instrument.contract.symbol.clone(),
+                    product: instrument.product.symbol.clone(),
+                    spec: PriceSpec::from_instrument(&instrument),
+                    fees: FeeSchedule::for_product(&instrument.product.symbol),
+                    initial_margin: instrument.initial_margin(),
+                };
+                (instrument.contract.id, terms)
+            })
+            .collect();
+        Self {
+            terms,
+            ..Default::default()
+        }
+    }
+    // Rebuilds the books from a user/syncrequest snapshot, keeping the contract terms
+    pub fn sync(&mut self, user_data: &UserData) {
+        let terms = std::mem::take(&mut self.terms);
+        *self = Self {
+            terms,
+            ..Default::default()
+        };
+        for order in &user_data.orders {
+            if let (Some(order_id), Some(account_id)) =
+                (order["id"].as_i64(), order["accountId"].as_i64())
+            {
+                self.order_accounts.insert(order_id, account_id);
+            }
+        }
+        let mut fills = user_data.fills.clone();
+        fills.sort_by_key(|fill| fill.id);
+        for fill in &fills {
+            self.apply_fill(fill);
+        }
+    }
+    pub fn record_order(&mut self, order_id: i64, account_id: i64) {
+        if self.order_accounts.insert(order_id, account_id).is_some() {
+            return;
+        }
+        let (ready, waiting): (Vec<Fill>, Vec<Fill>) = std::mem::take(&mut self.unassigned)
+            .into_iter()
+            .partition(|fill| fill.order_id == order_id);
+        self.unassigned = waiting;
+        for fill in &ready {
+            self.apply_fill(fill);
+        }
+    }
+    pub fn apply_fill(&mut self, fill: &Fill) {
+        if self.fill_owners.contains_key(&fill.id) {
+            return;
+        }
+        let account_id = match self.order_accounts.get(&fill.order_id) {
+            Some(account_id) => *account_id,
+            None => {
+                if !self.unassigned.iter().any(|waiting| waiting.id == fill.id) {
+                    self.unassigned.push(fill.clone());
+                }
+                return;
+            }
+        };
+        self.fill_owners
+            .insert(fill.id, (account_id, fill.contract_id));
+        let spec = self.terms.get(&fill.contract_id).map(|terms| terms.spec);
+        self.books
+            .entry((account_id, fill.contract_id))
+            .or_insert_with(|| ContractBook {
+                account_id,
+                contract_id: fill.contract_id,
+                ..Default::default()
+            })
+            .apply(fill, spec.as_ref());
+    }
+    // Orders and fills are the only props entities that change the books
+    pub fn apply_props(&mut self, event: &PropsEvent) {
+        if event.is_deleted() {
+            return;
+        }
+        match event.entity_type.as_str() {
+            "order" => {
+                if let (Some(order_id), Some(account_id)) =
+                    (event.entity_id(), event.entity["accountId"].as_i64())
+                {
+                    self.record_order(order_id, account_id);
+                }
+            }
+            "fill" => {
+                if let Ok(fill) = serde_json::from_value::<Fill>(event.entity.clone()) {
+                    self.apply_fill(&fill);
+                }
+            }
+            _ => {}
+        }
+    }
+    pub fn book(&self, account_id: i64, contract_id: i64) -> Option<&ContractBook> {
+        self.books.get(&(account_id, contract_id))
+    }
+    // Zero before the first fill. None for contracts without terms.
+    pub fn gross_realized(&self, account_id: i64, contract_id: i64) -> Option<f64> {
+        let terms = self.terms.get(&contract_id)?;
+        Some(
+            self.book(account_id, contract_id)
+                .map_or(0.0, |book| book.gross_realized(terms)),
+        )
+    }
+    pub fn unrealized(&self, account_id: i64, contract_id: i64, quote: &SimpleQuote) -> Option<f64> {
+        let terms = self.terms.get(&contract_id)?;
+        Some(
+            self.book(account_id, contract_id)
+                .map_or(0.0, |book| book.unrealized(quote, terms)),
+        )
+    }
+    pub fn account_ids(&self) -> Vec<i64> {
+        let mut ids: Vec<i64> = self.books.keys().map(|(account_id, _)| *account_id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+    // Books of contracts without terms can not be valued and are left out
+    pub fn account_pnl(
+        &self,
+        account_id: i64,
+        quote_of: impl Fn(i64) -> Option<SimpleQuote>,
+    ) -> AccountPnl {
+        let mut pnl = AccountPnl::default();
+        for book in self.books.values().filter(|book| book.account_id == account_id) {
+            if let Some(terms) = self.terms.get(&book.contract_id) {
+                pnl.gross_realized += book.gross_realized(terms);
+                pnl.fees += book.fees(terms);
+                if let Some(quote) = quote_of(book.contract_id) {
+                    pnl.unrealized += book.unrealized(&quote, terms);
+                }
+            }
+        }
+        pnl
+    }
+    // Compares gross realized pnl with Tradovate's fill pairs per contract and with the realized
+    // pnl of each account's cash balance, which is also before fees
+    pub fn reconcile(&self, user_data: &UserData) -> Vec<Discrepancy> {
+        let mut discrepancies = Vec::new();
+        let mut paired_ticks: HashMap<(i64, i64), i64> = HashMap::new();
+        for pair in user_data.fill_pairs.iter().filter(|pair| pair.active) {
+            let owner = match self.fill_owners.get(&pair.buy_fill_id) {
+                Some(owner) => owner,
+                None => continue,
+            };
+            if let Some(terms) = self.terms.get(&owner.1) {
+                *paired_ticks.entry(*owner).or_default() +=
+                    terms.spec.ticks_between(pair.buy_price, pair.sell_price) * pair.qty;
+            }
+        }
+        let mut account_realized: HashMap<i64, Option<f64>> = HashMap::new();
+        for (key, book) in &self.books {
+            let terms = match self.terms.get(&book.contract_id) {
+                Some(terms) => terms,
+                None => {
+                    account_realized.insert(book.account_id, None);
+                    continue;
+                }
+            };
+            let ours = book.gross_realized(terms);
+            let theirs = round_cents(
+                paired_ticks.get(key).copied().unwrap_or(0) as f64 * terms.spec.tick_value(),
+            );
+            if (ours - theirs).abs() > RECONCILE_TOLERANCE {
+                discrepancies.push(Discrepancy {
+                    account_id: book.account_id,
+                    contract_id: Some(book.contract_id),
+                    source: "fill pairs",
+                    ours,
+                    theirs,
+                });
+            }
+            if let Some(total) = account_realized.entry(book.account_id).or_insert(Some(0.0)) {
+                *total += ours;
+            }
+        }
+        for balance in &user_data.cash_balances {
+            let ours = match account_realized.get(&balance.account_id) {
+                Some(Some(ours)) => *ours,
+                Some(None) => continue,
+                None => 0.0,
+            };
+            if (ours - balance.realized_pn_l).abs() > RECONCILE_TOLERANCE {
+                discrepancies.push(Discrepancy {
+                    account_id: balance.account_id,
+                    contract_id: None,
+                    source: "cash balance",
+                    ours,
+                    theirs: balance.realized_pn_l,
+                });
+            }
+        }
+        discrepancies
+    }
+}
+
+fn round_cents(dollars: f64) -> f64 {
+    (dollars * 100.0).round() / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CashBalance, FillPair},
+        time_and_sales::OrderAction,
+    };
+
+    const ES: i64 = 1;
+    const MES: i64 = 2;
+
+    fn terms(symbol: &str, product: &str, value_per_point: f64) -> ContractTerms {
+        ContractTerms {
+            symbol: symbol.to_string(),
+            product: product.to_string(),
+            spec: PriceSpec::new(0.25, value_per_point),
+            fees: FeeSchedule::for_product(product),
+            initial_margin: None,
+        }
+    }
+
+    // account 7 trades through order 10
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.terms.insert(ES, terms("ESZ2", "ES", 50.0));
+        ledger.terms.insert(MES, terms("MESZ2", "MES", 5.0));
+        ledger.record_order(10, 7);
+        ledger
+    }
+
+    fn fill(id: i64, contract_id: i64, action: OrderAction, qty: i64, price: f64) -> Fill {
+        Fill {
+            id,
+            order_id: 10,
+            contract_id,
+            action,
+            qty,
+            price,
+            active: true,
+            ..Fill::default()
+        }
+    }
+
+    fn book(ledger: &Ledger, contract_id: i64) -> &ContractBook {
+        ledger.book(7, contract_id).unwrap()
+    }
+
+    #[test]
+    fn scale_ins_are_closed_first_in_first_out() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, ES, OrderAction::Buy, 1, 4500.0));
+        ledger.apply_fill(&fill(2, ES, OrderAction::Buy, 2, 4502.0));
+        assert_eq!(book(&ledger, ES).net_pos(), 3);
+        assert_eq!(book(&ledger, ES).avg_entry(), Some(13504.0 / 3.0));
+        // one against the 4500 lot for 16 ticks and one against the 4502 lot for 8
+        ledger.apply_fill(&fill(3, ES, OrderAction::Sell, 2, 4504.0));
+        assert_eq!(book(&ledger, ES).realized_ticks, 24);
+        assert_eq!(ledger.gross_realized(7, ES), Some(300.0));
+        assert_eq!(book(&ledger, ES).net_pos(), 1);
+        assert_eq!(book(&ledger, ES).avg_entry(), Some(4502.0));
+    }
+
+    #[test]
+    fn partial_exits_leave_the_rest_of_the_lot_open() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, ES, OrderAction::Buy, 3, 4500.0));
+        ledger.apply_fill(&fill(2, ES, OrderAction::Sell, 1, 4501.0));
+        assert_eq!(ledger.gross_realized(7, ES), Some(50.0));
+        assert_eq!(book(&ledger, ES).net_pos(), 2);
+        assert_eq!(book(&ledger, ES).avg_entry(), Some(4500.0));
+        ledger.apply_fill(&fill(3, ES, OrderAction::Sell, 2, 4499.0));
+        assert_eq!(ledger.gross_realized(7, ES), Some(-50.0));
+        assert_eq!(book(&ledger, ES).net_pos(), 0);
+        assert_eq!(book(&ledger, ES).avg_entry(), None);
+    }
+
+    #[test]
+    fn reversals_open_the_remainder_on_the_other_side() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, ES, OrderAction::Buy, 1, 4500.0));
+        ledger.apply_fill(&fill(2, ES, OrderAction::Sell, 3, 4505.0));
+        assert_eq!(ledger.gross_realized(7, ES), Some(250.0));
+        assert_eq!(book(&ledger, ES).net_pos(), -2);
+        assert_eq!(book(&ledger, ES).avg_entry(), Some(4505.0));
+        // the short earns entry minus exit
+        ledger.apply_fill(&fill(3, ES, OrderAction::Buy, 2, 4503.0));
+        assert_eq!(ledger.gross_realized(7, ES), Some(450.0));
+        assert_eq!(book(&ledger, ES).net_pos(), 0);
+    }
+
+    #[test]
+    fn prices_off_the_tick_grid_realize_whole_ticks() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, ES, OrderAction::Buy, 1, 4512.2500001));
+        ledger.apply_fill(&fill(2, ES, OrderAction::Sell, 1, 4512.7499999));
+        assert_eq!(book(&ledger, ES).realized_ticks, 2);
+        assert_eq!(ledger.gross_realized(7, ES), Some(25.0));
+    }
+
+    #[test]
+    fn fees_follow_each_products_schedule() {
+        let mut ledger = ledger();
+        ledger.terms.insert(3, terms("ZNZ2", "ZN", 1000.0));
+        for (contract_id, first_fill) in [(ES, 1), (MES, 3), (3, 5)] {
+            ledger.apply_fill(&fill(first_fill, contract_id, OrderAction::Buy, 1, 100.0));
+            ledger.apply_fill(&fill(first_fill + 1, contract_id, OrderAction::Sell, 1, 100.25));
+        }
+        let fees = |contract_id| book(&ledger, contract_id).fees(&ledger.terms[&contract_id]);
+        // 1.38 + 0.19 + 0.02 + 0.46 a side
+        assert_eq!(fees(ES), 4.1);
+        // 0.35 + 0.19 + 0.02 + 0.13 a side
+        assert_eq!(fees(MES), 1.38);
+        // not listed, DEFAULT_FEES
+        assert_eq!(fees(3), round_cents(2.0 * DEFAULT_FEES.per_side()));
+        let pnl = ledger.account_pnl(7, |_| None);
+        assert_eq!(pnl.gross_realized, 12.5 + 1.25 + 250.0);
+        assert_eq!(pnl.net_realized(), pnl.gross_realized - pnl.fees);
+    }
+
+    fn synced(realized_pn_l: f64, sell_price: f64) -> UserData {
+        UserData {
+            orders: vec![serde_json::json!({"id": 10, "accountId": 7})],
+            fills: vec![
+                fill(1, ES, OrderAction::Buy, 1, 4500.0),
+                fill(2, ES, OrderAction::Sell, 1, 4502.0),
+            ],
+            fill_pairs: vec![FillPair {
+                buy_fill_id: 1,
+                sell_fill_id: 2,
+                qty: 1,
+                buy_price: 4500.0,
+                sell_price,
+                active: true,
+                ..FillPair::default()
+            }],
+            cash_balances: vec![CashBalance {
+                account_id: 7,
+                realized_pn_l,
+                ..CashBalance::default()
+            }],
+            ..UserData::default()
+        }
+    }
+
+    #[test]
+    fn reconcile_agrees_with_fill_pairs_and_the_cash_balance() {
+        let mut ledger = ledger();
+        let user_data = synced(100.0, 4502.0);
+        ledger.sync(&user_data);
+        assert_eq!(ledger.gross_realized(7, ES), Some(100.0));
+        assert!(ledger.reconcile(&user_data).is_empty());
+    }
+
+    #[test]
+    fn reconcile_reports_each_disagreement() {
+        let mut ledger = ledger();
+        ledger.sync(&synced(100.0, 4502.0));
+        let discrepancies = ledger.reconcile(&synced(90.0, 4503.0));
+        assert_eq!(
+            discrepancies,
+            vec![
+                Discrepancy {
+                    account_id: 7,
+                    contract_id: Some(ES),
+                    source: "fill pairs",
+                    ours: 100.0,
+                    theirs: 150.0,
+                },
+                Discrepancy {
+                    account_id: 7,
+                    contract_id: None,
+                    source: "cash balance",
+                    ours: 100.0,
+                    theirs: 90.0,
+                },
+            ]
+        );
+    }
+}
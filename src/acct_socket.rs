@@ -4,19 +4,26 @@ use tokio_tungstenite::tungstenite::{Error, Message};
 
 use crate::{
     api_client::ResourceType,
-    md_socket::WriteSocket,
-    models::{AcctCommChannelRWL, OrderIDMessage, OrderUpdateMessage, UserDataMessage},
+    md_socket::{RequestStatus, WriteSocket},
+    models::{
+        to_be_calculated_ts, AcctCommChannelRWL, OrderIDMessage, OrderUpdateMessage, PropsMessage,
+        UserDataMessage,
+    },
+    replay::{ReplayClock, REPLAY_REQUEST_ID},
     reporting::{program_log, send_email, ReportRWL},
     settings::REPORT_TO_EMAIL,
+    watchdog::FeedWatchdogRWL,
 };
 
 pub async fn incoming_acct_socket(
     acct_comms_channel: AcctCommChannelRWL,
+    watchdog_rwl: FeedWatchdogRWL,
     resource_type: ResourceType,
     msg: Result<Message, Error>,
     report_rwl: ReportRWL,
 ) -> bool {
     if let Ok(message) = msg {
+        watchdog_rwl.write().await.record_frame(resource_type);
         match message {
             Message::Text(mut message) => {
                 if let Some(first_char) = message.chars().next() {
@@ -52,6 +59,37 @@ pub async fn incoming_acct_socket(
                                     if let Some(array) = as_value.as_array() {
                                         for json in array {
                                             if let Some(obj) = json.as_object() {
+                                                if obj["i"].as_i64() == Some(REPLAY_REQUEST_ID)
+                                                    && acct_comms_channel
+                                                        .read()
+                                                        .await
+                                                        .replay
+                                                        .as_ref()
+                                                        .is_some_and(|replay| replay.is_awaiting_clock())
+                                                {
+                                                    acct_comms_channel
+                                                        .write()
+                                                        .await
+                                                        .replay_clock_initialized(obj["s"].as_i64() == Some(200));
+                                                    program_log(
+                                                        &format!("Replay clock initialization response {}", json),
+                                                        resource_type,
+                                                        report_rwl.clone(),
+                                                    )
+                                                    .await;
+                                                    continue;
+                                                }
+                                                if acct_comms_channel
+                                                    .read()
+                                                    .await
+                                                    .is_order_lookup(obj["i"].as_i64())
+                                                {
+                                                    let log = acct_comms_channel.write().await.on_order_lookup(json);
+                                                    for line in &log {
+                                                        program_log(line, resource_type, report_rwl.clone()).await;
+                                                    }
+                                                    continue;
+                                                }
                                                 if obj.keys().len() == 2 {
                                                     if obj.contains_key("i")
                                                         && obj.contains_key("s")
@@ -74,20 +112,49 @@ pub async fn incoming_acct_socket(
                                                                 }
                                                             }
                                                         }
+                                                    } else if obj["e"].as_str() == Some("clock") {
+                                                        // kept so a reconnect resumes this socket's replay where it stopped
+                                                        if let Some(clock) = ReplayClock::from_event(&obj["d"]) {
+                                                            if let Some(replay) = &mut acct_comms_channel.write().await.replay {
+                                                                replay.record_clock(&clock);
+                                                            }
+                                                        }
                                                     } else {
                                                         let parsed: Result<
                                                             OrderUpdateMessage,
                                                             serde_json::Error,
                                                         > = serde_json::from_value(json.clone());
-                                                        if let Ok(order_update) = parsed {
-                                                            match acct_comms_channel.try_write() {
-                                                                Ok(mut open) => {
-                                                                    open.order_update = Some(order_update);
-                                                                    program_log("Succesfully received order update",resource_type,report_rwl.clone()).await;
+                                                        if let Ok(mut order_update) = parsed {
+                                                            let mut open = acct_comms_channel.write().await;
+                                                            order_update.data.entity.parsing_ts =
+                                                                to_be_calculated_ts(open.clock.as_ref());
+                                                            open.ledger.record_order(
+                                                                order_update.data.entity.order_id,
+                                                                order_update.data.entity.account_id,
+                                                            );
+                                                            open.journal.on_execution(
+                                                                order_update.data.entity.order_id,
+                                                                &order_update.data.entity.ord_status,
+                                                            );
+                                                            open.order_update = Some(order_update);
+                                                            drop(open);
+                                                            program_log("Succesfully received order update",resource_type,report_rwl.clone()).await;
+                                                        } else if let Ok(props) =
+                                                            serde_json::from_value::<PropsMessage>(json.clone())
+                                                        {
+                                                            let mut open = acct_comms_channel.write().await;
+                                                            open.ledger.apply_props(&props.data);
+                                                            let mut discrepancies = Vec::new();
+                                                            let open = &mut *open;
+                                                            if let Some(user_data) = &mut open.user_data {
+                                                                user_data.data.apply_props(&props.data);
+                                                                // the cash balance moves once a trade's realized pnl is booked
+                                                                if props.data.entity_type == "cashBalance" {
+                                                                    discrepancies = open.ledger.reconcile(&user_data.data);
                                                                 }
-                                                                Err(_) => program_log(
-                                                                    "could not write to rwlock for order update message",
-                                                                    resource_type,report_rwl.clone()).await,
+                                                            }
+                                                            for discrepancy in discrepancies {
+                                                                program_log(&discrepancy.to_string(), resource_type, report_rwl.clone()).await;
                                                             }
                                                         }
                                                     }
@@ -97,44 +164,42 @@ pub async fn incoming_acct_socket(
                                                         serde_json::Error,
                                                     > = serde_json::from_value(json.clone());
                                                     if let Ok(order_id) = parsed {
-                                                        match acct_comms_channel.try_write() {
-                                                            Ok(mut open) => {
-                                                                program_log(
-                                                                    &format!(
-                                                                        "Succesfully received order id {:#?}",
-                                                                        order_id
-                                                                    ),
-                                                                    resource_type,report_rwl.clone()).await;
-                                                                open.order_id_messages.push(order_id);
-                                                                drop(open);
-                                                                return false
-                                                            }
-                                                            Err(_) => program_log(
-                                                                "could not write to rwlock for order id message",
-                                                                resource_type,report_rwl.clone()).await,
-                                                        }
+                                                        program_log(
+                                                            &format!(
+                                                                "Succesfully received order id {:#?}",
+                                                                order_id
+                                                            ),
+                                                            resource_type,report_rwl.clone()).await;
+                                                        let mut open = acct_comms_channel.write().await;
+                                                        open.journal.acknowledge(order_id.request_id, order_id.data.order_id);
+                                                        open.order_id_messages.push(order_id);
+                                                        drop(open);
+                                                        return false
                                                     }
                                                     let parsed: Result<
                                                         UserDataMessage,
                                                         serde_json::Error,
                                                     > = serde_json::from_value(json.clone());
                                                     if let Ok(user_data) = parsed {
-                                                        match acct_comms_channel.try_write() {
-                                                            Ok(mut open) => {
-                                                                program_log(
-                                                                    &format!(
-                                                                        "Succesfully received user data\n{:#?}",
-                                                                        user_data.data.positions
-                                                                    ),
-                                                                    resource_type,report_rwl.clone()).await;
-                                                                open.user_data = Some(user_data);
-                                                                drop(open);
-                                                                return false
-                                                            }
-                                                            Err(_) => program_log(
-                                                                "could not write to rwlock for user data message",
-                                                                resource_type,report_rwl.clone()).await,
+                                                        program_log(
+                                                            &format!(
+                                                                "Succesfully received user data\n{:#?}",
+                                                                user_data.data.positions
+                                                            ),
+                                                            resource_type,report_rwl.clone()).await;
+                                                        let mut open = acct_comms_channel.write().await;
+                                                        open.ledger.sync(&user_data.data);
+                                                        let discrepancies = open.ledger.reconcile(&user_data.data);
+                                                        open.user_data = Some(user_data);
+                                                        let lookups = open.reconcile_orders();
+                                                        drop(open);
+                                                        for discrepancy in discrepancies {
+                                                            program_log(&discrepancy.to_string(), resource_type, report_rwl.clone()).await;
+                                                        }
+                                                        for line in &lookups {
+                                                            program_log(line, resource_type, report_rwl.clone()).await;
                                                         }
+                                                        return false
                                                     }
                                                     program_log(
                                                         &format!("unrecognized message: {}", json),
@@ -184,7 +249,7 @@ pub async fn incoming_acct_socket(
             _ => return false,
         }
     }
-    return false;
+    false
 }
 
 pub async fn outgoing_acct_socket(
@@ -216,13 +281,17 @@ pub async fn outgoing_acct_socket(
                 }
             }
         }
-        if open.connection_established && !open.is_authorized && !open.unsent_requests.is_empty() {
+        if open.connection_established
+            && !open.is_authorized
+            && open.sent_requests.is_empty()
+            && !open.unsent_requests.is_empty()
+        {
             match pwrite
                 .send(Message::Text(open.unsent_requests[0].clone()))
                 .await
             {
                 Ok(_) => {
-                    let item = open.unsent_requests.swap_remove(0);
+                    let item = open.unsent_requests.remove(0);
                     open.sent_requests.push(item);
                 }
                 Err(e) => {
@@ -235,13 +304,39 @@ pub async fn outgoing_acct_socket(
                 }
             }
         }
-        if open.is_authorized && !open.unsent_requests.is_empty() {
+        if open.is_authorized {
+            if let Some(request) = open
+                .replay
+                .as_ref()
+                .filter(|replay| replay.status == RequestStatus::Unsent)
+                .map(|replay| replay.initialize_clock())
+            {
+                match pwrite.send(Message::Text(request)).await {
+                    Ok(_) => {
+                        if let Some(replay) = &mut open.replay {
+                            replay.status = RequestStatus::Sent;
+                        }
+                        program_log("succesfully sent replay clock initialization", resource_type, report_rwl.clone())
+                            .await;
+                    }
+                    Err(e) => {
+                        program_log(
+                            &format!("error sending replay clock initialization Error: {}", e),
+                            resource_type,
+                            report_rwl.clone(),
+                        )
+                        .await
+                    }
+                }
+            }
+        }
+        if open.is_authorized && open.ready_for_requests() && !open.unsent_requests.is_empty() {
             match pwrite
                 .send(Message::Text(open.unsent_requests[0].clone()))
                 .await
             {
                 Ok(_) => {
-                    let item = open.unsent_requests.swap_remove(0);
+                    let item = open.unsent_requests.remove(0);
                     open.sent_requests.push(item);
                 }
                 Err(e) => {
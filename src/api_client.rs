@@ -1,32 +1,99 @@
+use std::sync::Arc;
+
 use serde_json::{json, Value};
 
 use crate::{
     credentials::{CID, PASSWORD, SECRET, USERNAME},
-    models::{ContractID, ProductInfo},
-    others::{create_json_file, delete_file, open_json},
-    settings::{AUTH_FILENAME, DEMO_TRADING_URL, LIVE_MARKET_DATA_URL, LIVE_TRADING_URL},
+    models::{
+        ContractGroup, ContractID, ContractMaturity, Currency, Exchange, ProductInfo, ProductMargin,
+    },
+    settings::{
+        AUTH_FILENAME, CUSTOM_SERVER, DEMO_MARKET_DATA_URL, DEMO_TRADING_URL,
+        LIVE_MARKET_DATA_URL, LIVE_TRADING_URL, REPLAY_URL,
+    },
+    token_manager::{Token, TokenError, TokenManager, TokenManagerRWL},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Server {
     Live,
     Demo,
+    Replay,
+    // for a local mock of the api. Urls are host:port without a scheme.
+    Custom {
+        trading_url: &'static str,
+        market_data_url: &'static str,
+        secure: bool,
+    },
 }
-#[derive(Debug, Clone, Copy)]
+impl Server {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "live" => Some(Server::Live),
+            "demo" => Some(Server::Demo),
+            "replay" => Some(Server::Replay),
+            "custom" => Some(CUSTOM_SERVER),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Server::Live => "LIVE",
+            Server::Demo => "DEMO",
+            Server::Replay => "REPLAY",
+            Server::Custom { .. } => "CUSTOM",
+        }
+    }
+    pub fn is_live(&self) -> bool {
+        *self == Server::Live
+    }
+    // Replay sessions authenticate over the demo REST api and do everything else on the replay socket
+    pub fn host(&self, endpoint: Endpoint, protocol: &Protocol) -> &'static str {
+        match (self, endpoint, protocol) {
+            (Server::Live, Endpoint::Trading, _) => LIVE_TRADING_URL,
+            (Server::Live, Endpoint::MarketData, _) => LIVE_MARKET_DATA_URL,
+            (Server::Demo, Endpoint::Trading, _) => DEMO_TRADING_URL,
+            (Server::Demo, Endpoint::MarketData, _) => DEMO_MARKET_DATA_URL,
+            (Server::Replay, _, Protocol::Https) => DEMO_TRADING_URL,
+            (Server::Replay, _, Protocol::Wss) => REPLAY_URL,
+            (Server::Custom { trading_url, .. }, Endpoint::Trading, _) => trading_url,
+            (Server::Custom { market_data_url, .. }, Endpoint::MarketData, _) => {
+                market_data_url
+            }
+        }
+    }
+    pub fn is_secure(&self) -> bool {
+        match self {
+            Server::Custom { secure, .. } => *secure,
+            _ => true,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ResourceType {
     Trading,
     MarketData,
     Any,
 }
+// The apis requests are sent to. ResourceType::Any only labels logs and has no host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Trading,
+    MarketData,
+}
 pub enum Protocol {
     Https,
     Wss,
 }
 impl Protocol {
-    pub fn add_prefix(&self, base_url: &str) -> String {
-        match self {
-            Protocol::Https => format!("https://{}", base_url),
-            Protocol::Wss => url::Url::parse(&format!("wss://{}/v1/websocket", base_url))
+    pub fn add_prefix(&self, base_url: &str, secure: bool) -> String {
+        match (self, secure) {
+            (Protocol::Https, true) => format!("https://{}", base_url),
+            (Protocol::Https, false) => format!("http://{}", base_url),
+            (Protocol::Wss, true) => url::Url::parse(&format!("wss://{}/v1/websocket", base_url))
+                .unwrap()
+                .to_string(),
+            (Protocol::Wss, false) => url::Url::parse(&format!("ws://{}/v1/websocket", base_url))
                 .unwrap()
                 .to_string(),
         }
@@ -38,9 +105,7 @@ pub struct TradovateAPIClient {
     pub app_id: String,
     pub app_version: String,
     pub device_id: String,
-    pub access_token: String,
-    pub token_expiration_time: i64,
-    pub user_id: i64,
+    pub tokens: TokenManagerRWL,
 }
 impl TradovateAPIClient {
     pub fn new(server_type: Server, app_id: &str, app_version: &str) -> Self {
@@ -48,58 +113,20 @@ impl TradovateAPIClient {
             app_id: app_id.to_string(),
             app_version: app_version.to_string(),
             device_id: machine_uid::get().unwrap(),
-            access_token: String::new(),
-            token_expiration_time: 0,
+            tokens: Arc::new(tokio::sync::RwLock::new(TokenManager::new(AUTH_FILENAME))),
             server_type,
-            user_id: 0,
         }
     }
-    pub async fn get_auth(self) -> Result<Self, reqwest::Error> {
-        match open_json(AUTH_FILENAME) {
-            Ok(auth_file) => {
-                if chrono::Utc::now()
-                    >= chrono::DateTime::parse_from_rfc3339(
-                        auth_file["expirationTime"].as_str().unwrap(),
-                    )
-                    .unwrap()
-                {
-                    delete_file(AUTH_FILENAME);
-                    match self.request_token().await {
-                        Ok((t1, t2, t3)) => Ok(Self {
-                            access_token: t1,
-                            token_expiration_time: t2,
-                            user_id: t3,
-                            ..self
-                        }),
-                        Err(e) => {
-                            Err(e)
-                        }
-                    }
-                } else {
-                    Ok(Self {
-                        access_token: auth_file["accessToken"].as_str().unwrap().to_string(),
-                        token_expiration_time: chrono::DateTime::parse_from_rfc3339(
-                            auth_file["expirationTime"].as_str().unwrap(),
-                        )
-                        .unwrap()
-                        .timestamp_millis(),
-                        user_id: auth_file["userId"].as_i64().unwrap(),
-                        ..self
-                    })
-                }
-            }
-            Err(_) => match self.request_token().await {
-                Ok((t1, t2, t3)) => Ok(Self {
-                    access_token: t1,
-                    token_expiration_time: t2,
-                    user_id: t3,
-                    ..self
-                }),
-                Err(e) => Err(e),
-            },
+    // Uses the cached token when it is still good, otherwise requests a new one
+    pub async fn get_auth(self) -> Result<Self, TokenError> {
+        if !self.tokens.write().await.load_cached() {
+            let token = self.request_token().await?;
+            // a token that could not be cached is still good for this run
+            let _ = self.tokens.write().await.set_token(token);
         }
+        Ok(self)
     }
-    pub async fn request_token(&self) -> Result<(String, i64, i64), reqwest::Error> {
+    pub async fn request_token(&self) -> Result<Token, TokenError> {
         let body = json!({
             "name":       USERNAME,
             "password":   PASSWORD,
@@ -111,7 +138,7 @@ impl TradovateAPIClient {
         });
         let url = format!(
             "{}/v1/auth/accesstokenrequest",
-            self.url(ResourceType::Trading, Protocol::Https)
+            self.url(Endpoint::Trading, Protocol::Https)
         );
         let request = reqwest::Client::new()
             .post(url)
@@ -120,109 +147,136 @@ impl TradovateAPIClient {
             .body(body.to_string());
         let response = request.send().await?;
         let response_body = response.json::<Value>().await?;
-        create_json_file(AUTH_FILENAME, &response_body);
-        let exp_timestamp =
-            chrono::DateTime::parse_from_rfc3339(response_body["expirationTime"].as_str().unwrap())
-                .unwrap()
-                .timestamp_millis();
-        Ok((
-            response_body["accessToken"].as_str().unwrap().to_string(),
-            exp_timestamp,
-            response_body["userId"].as_i64().unwrap(),
-        ))
-    }
-    pub async fn get_products_list(&self) -> Result<Value, reqwest::Error> {
-        let response = reqwest::Client::new()
-            .get(format!(
-                "{}/v1/contract/deps",
-                self.url(ResourceType::Trading, Protocol::Https)
-            ))
-            .bearer_auth(&self.access_token)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .send()
-            .await?;
-        let response_body = response.json::<Value>().await?;
-        create_json_file("Contracts List.json", &response_body);
-        Ok(response_body)
-    }
-    pub fn url(&self, resource_type: ResourceType, protocol: Protocol) -> String {
-        match self.server_type {
-            Server::Live => match resource_type {
-                ResourceType::Trading => protocol.add_prefix(LIVE_TRADING_URL),
-                ResourceType::MarketData => protocol.add_prefix(LIVE_MARKET_DATA_URL),
-                ResourceType::Any => todo!(),
-            },
-            Server::Demo => match resource_type {
-                ResourceType::Trading => protocol.add_prefix(DEMO_TRADING_URL),
-                ResourceType::MarketData => protocol.add_prefix(LIVE_MARKET_DATA_URL),
-                ResourceType::Any => todo!(),
-            },
-        }
+        Token::from_response(&response_body)
+    }
+    pub async fn access_token(&self) -> String {
+        self.tokens.read().await.access_token()
+    }
+    pub async fn md_access_token(&self) -> String {
+        self.tokens.read().await.md_access_token()
+    }
+    pub fn url(&self, endpoint: Endpoint, protocol: Protocol) -> String {
+        protocol.add_prefix(
+            self.server_type.host(endpoint, &protocol),
+            self.server_type.is_secure(),
+        )
     }
-    pub async fn ws_auth_msg(&self, resource_type: ResourceType) -> String {
-        match resource_type {
-            ResourceType::Trading => format!("authorize\n1\n\n{}", self.access_token),
-            ResourceType::MarketData => format!("authorize\n1\n\n{}", self.access_token),
-            ResourceType::Any => todo!(),
+    pub async fn ws_auth_msg(&self, endpoint: Endpoint) -> String {
+        match endpoint {
+            Endpoint::Trading => format!("authorize\n1\n\n{}", self.access_token().await),
+            // the replay socket serves market data with the trading token
+            Endpoint::MarketData if self.server_type == Server::Replay => {
+                format!("authorize\n1\n\n{}", self.access_token().await)
+            }
+            Endpoint::MarketData => format!("authorize\n1\n\n{}", self.md_access_token().await),
         }
     }
-    pub fn get_user_sync_request(&self) -> String {
+    pub async fn get_user_sync_request(&self) -> String {
         let body = json!({
-            "users": [self.user_id],
+            "users": [self.tokens.read().await.user_id()],
         });
         format!("user/syncrequest\n2\n\n{}", body)
     }
     async fn get_contract_id(&self, symbol: &str) -> Result<ContractID, reqwest::Error> {
         let query = vec![("name", symbol)];
-        Ok(reqwest::Client::new()
-            .get(&format!(
-                "{}/contract/find",
-                self.url(ResourceType::Trading, Protocol::Https)
+        reqwest::Client::new()
+            .get(format!(
+                "{}/v1/contract/find",
+                self.url(Endpoint::Trading, Protocol::Https)
             ))
             .header("accept", "application/json")
-            .bearer_auth(self.access_token.clone())
+            .bearer_auth(self.access_token().await)
             .query(&query)
             .send()
             .await?
             .json::<ContractID>()
-            .await?)
+            .await
     }
-    async fn get_product_info(&self, symbol: &str) -> Result<ProductInfo, reqwest::Error> {
+    pub async fn get_product_info(&self, symbol: &str) -> Result<ProductInfo, reqwest::Error> {
         let query = vec![("name", symbol)];
         let url = &format!(
             "{}/v1/product/find",
-            self.url(ResourceType::Trading, Protocol::Https)
+            self.url(Endpoint::Trading, Protocol::Https)
         );
         let request = reqwest::Client::new()
             .get(url)
             .header("Accept", "application/json")
-            .bearer_auth(self.access_token.clone())
+            .bearer_auth(self.access_token().await)
             .query(&query);
         let response = request.send().await?;
         let body = response.json::<ProductInfo>().await?;
         Ok(body)
     }
+    // GET {endpoint}?{query} for the read only entity endpoints, like contractMaturity/item
+    async fn get_entity<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        query: &[(&str, String)],
+    ) -> Result<T, reqwest::Error> {
+        reqwest::Client::new()
+            .get(format!(
+                "{}/v1/{}",
+                self.url(Endpoint::Trading, Protocol::Https),
+                endpoint
+            ))
+            .header("Accept", "application/json")
+            .bearer_auth(self.access_token().await)
+            .query(query)
+            .send()
+            .await?
+            .json::<T>()
+            .await
+    }
+    pub async fn get_product_by_id(&self, product_id: i64) -> Result<ProductInfo, reqwest::Error> {
+        self.get_entity("product/item", &[("id", product_id.to_string())])
+            .await
+    }
+    pub async fn get_contract_maturity(&self, maturity_id: i64) -> Result<ContractMaturity, reqwest::Error> {
+        self.get_entity("contractMaturity/item", &[("id", maturity_id.to_string())])
+            .await
+    }
+    pub async fn get_contract_maturities(&self, product_id: i64) -> Result<Vec<ContractMaturity>, reqwest::Error> {
+        self.get_entity("contractMaturity/deps", &[("masterid", product_id.to_string())])
+            .await
+    }
+    pub async fn get_maturity_contracts(&self, maturity_id: i64) -> Result<Vec<ContractID>, reqwest::Error> {
+        self.get_entity("contract/deps", &[("masterid", maturity_id.to_string())])
+            .await
+    }
+    pub async fn get_product_margin(&self, product_id: i64) -> Result<ProductMargin, reqwest::Error> {
+        self.get_entity("productMargin/item", &[("id", product_id.to_string())])
+            .await
+    }
+    pub async fn get_exchanges(&self) -> Result<Vec<Exchange>, reqwest::Error> {
+        self.get_entity("exchange/list", &[]).await
+    }
+    pub async fn get_contract_groups(&self) -> Result<Vec<ContractGroup>, reqwest::Error> {
+        self.get_entity("contractGroup/list", &[]).await
+    }
+    pub async fn get_currencies(&self) -> Result<Vec<Currency>, reqwest::Error> {
+        self.get_entity("currency/list", &[]).await
+    }
+    // The product is looked up through the contract's maturity, so symbols of any length work
     pub async fn get_contract_info(&self, symbol: &str) -> Result<ContractID, reqwest::Error> {
         let mut contract_id = self.get_contract_id(symbol).await?;
-        let product_info = self.get_product_info(&symbol[0..symbol.len() - 2]).await?;
+        let maturity = self
+            .get_contract_maturity(contract_id.contract_maturity_id)
+            .await?;
+        let product_info = self.get_product_by_id(maturity.product_id).await?;
         contract_id.big_point_value = product_info.value_per_point;
         Ok(contract_id)
     }
-    pub async fn renew_access_token(&self) -> Result<Value, reqwest::Error> {
+    pub async fn renew_access_token(&self) -> Result<Token, TokenError> {
         let url = &format!(
             "{}/v1/auth/renewaccesstoken",
-            self.url(ResourceType::Trading, Protocol::Https)
+            self.url(Endpoint::Trading, Protocol::Https)
         );
         let request = reqwest::Client::new()
             .get(url)
             .header("Accept", "application/json")
-            .bearer_auth(self.access_token.clone());
+            .bearer_auth(self.access_token().await);
         let response = request.send().await?;
         let body = response.json::<Value>().await?;
-        Ok(body)
-    }
-    pub fn token_is_valid(&self) -> bool {
-        chrono::Utc::now().timestamp_millis() - self.token_expiration_time > 120000
+        Token::from_response(&body)
     }
 }
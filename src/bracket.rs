@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    api_client::ResourceType,
+    environment::environment_label,
+    models::{AcctCommChannelRWL, OrderIDMessage, SimpleQuote},
+    price::{Price, Rounding},
+    reporting::{program_log, ReportRWL},
+    settings::{
+        BREAKEVEN_OFFSET_TICKS, BREAKEVEN_TRIGGER_TICKS, STOP_LOSS_TICKS, TAKE_PROFIT_TICKS,
+        TRAILING_STOP_TICKS, USE_BRACKETS,
+    },
+    socket_processing::MarketData,
+    strategy::{StrategyRWL, StrategyStatus},
+    time_and_sales::OrderAction,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BracketSettings {
+    pub stop_loss_ticks: i64,
+    pub take_profit_ticks: i64,
+    pub trailing_stop_ticks: Option<i64>,
+    pub breakeven_trigger_ticks: Option<i64>,
+    pub breakeven_offset_ticks: i64,
+}
+impl BracketSettings {
+    // None when USE_BRACKETS is off
+    pub fn from_settings() -> Option<Self> {
+        USE_BRACKETS.then_some(Self {
+            stop_loss_ticks: STOP_LOSS_TICKS,
+            take_profit_ticks: TAKE_PROFIT_TICKS,
+            trailing_stop_ticks: TRAILING_STOP_TICKS,
+            breakeven_trigger_ticks: BREAKEVEN_TRIGGER_TICKS,
+            breakeven_offset_ticks: BREAKEVEN_OFFSET_TICKS,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BracketLeg {
+    StopLoss,
+    TakeProfit,
+}
+impl BracketLeg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BracketLeg::StopLoss => "Stop loss",
+            BracketLeg::TakeProfit => "Take profit",
+        }
+    }
+}
+
+// The stop loss and take profit protecting one position. Prices start from the quote at entry
+// and are moved to the actual fill once it arrives.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bracket {
+    pub settings: BracketSettings,
+    // side of the entry, Buy for a long
+    pub direction: OrderAction,
+    pub qty: i64,
+    pub entry: Price,
+    pub stop: Price,
+    pub target: Price,
+    // the most favourable price since entry
+    pub best: Price,
+    pub request_id: i64,
+    pub entry_order_id: Option<i64>,
+    pub stop_order_id: Option<i64>,
+    pub target_order_id: Option<i64>,
+    pub anchored_to_fill: bool,
+    pub at_breakeven: bool,
+}
+impl Bracket {
+    pub fn new(settings: BracketSettings, direction: OrderAction, qty: i64, reference: Price) -> Self {
+        let mut bracket = Self {
+            settings,
+            direction,
+            qty,
+            entry: reference,
+            stop: reference,
+            target: reference,
+            best: reference,
+            request_id: 0,
+            entry_order_id: None,
+            stop_order_id: None,
+            target_order_id: None,
+            anchored_to_fill: false,
+            at_breakeven: false,
+        };
+        bracket.set_levels();
+        bracket
+    }
+    // 1 for a long, -1 for a short, so favourable moves are positive tick counts
+    fn sign(&self) -> i64 {
+        if self.direction == OrderAction::Buy {
+            1
+        } else {
+            -1
+        }
+    }
+    fn set_levels(&mut self) {
+        self.stop = self.entry - self.sign() * self.settings.stop_loss_ticks;
+        self.target = self.entry + self.sign() * self.settings.take_profit_ticks;
+    }
+    fn exit_action(&self) -> OrderAction {
+        if self.direction == OrderAction::Buy {
+            OrderAction::Sell
+        } else {
+            OrderAction::Buy
+        }
+    }
+    pub fn oso_request(
+        &self,
+        account_spec: &str,
+        account_id: i64,
+        symbol: &str,
+        cl_ord_id: &str,
+    ) -> String {
+        let body = json!({
+            "accountSpec": account_spec,
+            "accountId": account_id,
+            "action": self.direction,
+            "symbol": symbol,
+            "orderQty": self.qty,
+            "orderType": "Market",
+            "clOrdId": cl_ord_id,
+            "text": environment_label(),
+            "isAutomated": true,
+            "bracket1": {
+                "action": self.exit_action(),
+                "orderType": "Stop",
+                "stopPrice": self.stop.to_f64(),
+            },
+            "bracket2": {
+                "action": self.exit_action(),
+                "orderType": "Limit",
+                "price": self.target.to_f64(),
+            },
+        });
+        format!("order/placeOSO\n{}\n\n{}", self.request_id, body)
+    }
+    pub fn record_order_ids(&mut self, message: &OrderIDMessage) -> bool {
+        if message.request_id != self.request_id || self.entry_order_id.is_some() {
+            return false;
+        }
+        self.entry_order_id = Some(message.data.order_id);
+        self.stop_order_id = message.data.oso1_id;
+        self.target_order_id = message.data.oso2_id;
+        true
+    }
+    pub fn leg(&self, order_id: i64) -> Option<BracketLeg> {
+        if self.stop_order_id == Some(order_id) {
+            Some(BracketLeg::StopLoss)
+        } else if self.target_order_id == Some(order_id) {
+            Some(BracketLeg::TakeProfit)
+        } else {
+            None
+        }
+    }
+    // Moves both legs to the same distance from the actual fill. True when they changed.
+    pub fn anchor_to_fill(&mut self, avg_px: f64) -> bool {
+        if self.anchored_to_fill {
+            return false;
+        }
+        self.anchored_to_fill = true;
+        let fill = self.entry.spec.price(avg_px, Rounding::Nearest);
+        if fill == self.entry {
+            return false;
+        }
+        self.entry = fill;
+        self.best = fill;
+        self.set_levels();
+        true
+    }
+    // Applies the breakeven and trailing rules to a new quote. The stop only ever tightens.
+    // True when it moved.
+    pub fn update(&mut self, quote: &SimpleQuote) -> bool {
+        let mark = if self.direction == OrderAction::Buy {
+            quote.bid
+        } else {
+            quote.ask
+        };
+        let mark = self.entry.spec.price(mark, Rounding::Nearest);
+        if (mark - self.best) * self.sign() > 0 {
+            self.best = mark;
+        }
+        let mut candidates = Vec::new();
+        if let Some(trigger) = self.settings.breakeven_trigger_ticks {
+            if !self.at_breakeven && (self.best - self.entry) * self.sign() >= trigger {
+                self.at_breakeven = true;
+                candidates.push(self.entry + self.sign() * self.settings.breakeven_offset_ticks);
+            }
+        }
+        if let Some(trail) = self.settings.trailing_stop_ticks {
+            candidates.push(self.best - self.sign() * trail);
+        }
+        let tighter = candidates
+            .into_iter()
+            .filter(|candidate| (*candidate - self.stop) * self.sign() > 0)
+            .max_by_key(|candidate| (*candidate - self.stop) * self.sign());
+        match tighter {
+            Some(stop) => {
+                self.stop = stop;
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn modify_stop_request(&self, request_id: i64) -> Option<String> {
+        let body = json!({
+            "orderId": self.stop_order_id?,
+            "orderQty": self.qty,
+            "orderType": "Stop",
+            "stopPrice": self.stop.to_f64(),
+            "isAutomated": true,
+        });
+        Some(format!("order/modifyorder\n{}\n\n{}", request_id, body))
+    }
+    pub fn modify_target_request(&self, request_id: i64) -> Option<String> {
+        let body = json!({
+            "orderId": self.target_order_id?,
+            "orderQty": self.qty,
+            "orderType": "Limit",
+            "price": self.target.to_f64(),
+            "isAutomated": true,
+        });
+        Some(format!("order/modifyorder\n{}\n\n{}", request_id, body))
+    }
+    pub fn cancel_requests(&self, first_request_id: i64) -> Vec<String> {
+        [self.stop_order_id, self.target_order_id]
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(n, order_id)| {
+                format!(
+                    "order/cancelorder\n{}\n\n{}",
+                    first_request_id + n as i64,
+                    json!({ "orderId": order_id })
+                )
+            })
+            .collect()
+    }
+}
+
+// Keeps the bracket of the open position in step with it: learns the child order ids, moves the
+// legs to the fill, trails the stop and closes the position when a leg fills. Returns true once a
+// leg has closed the position.
+pub async fn manage_bracket(
+    market_data: &MarketData,
+    strategy_rwl: StrategyRWL,
+    account_data_rwl: AcctCommChannelRWL,
+    report_rwl: ReportRWL,
+) -> bool {
+    let mut open = match account_data_rwl.try_write() {
+        Ok(open) => open,
+        Err(_) => return false,
+    };
+    let mut strategy = match strategy_rwl.try_write() {
+        Ok(strategy) => strategy,
+        Err(_) => return false,
+    };
+    let mut bracket = match strategy.bracket.clone() {
+        Some(bracket) => bracket,
+        None => return false,
+    };
+    let position = match strategy.positions.last() {
+        Some(position) => position.clone(),
+        None => return false,
+    };
+    if bracket.entry_order_id.is_none() {
+        if let Some(message) = open
+            .order_id_messages
+            .iter()
+            .find(|message| message.request_id == bracket.request_id)
+        {
+            bracket.record_order_ids(message);
+        }
+    }
+    // the child ids arrive with the placeOSO response, until then nothing can be tracked
+    if bracket.stop_order_id.is_none() {
+        strategy.bracket = Some(bracket);
+        return false;
+    }
+    let contract = match market_data
+        .contract_ids
+        .iter()
+        .find(|contract| contract.id == position.contract_id)
+    {
+        Some(contract) => contract,
+        None => return false,
+    };
+    let mut log = Vec::new();
+    if let Some(order_update) = open.order_update.clone() {
+        if order_update.is_filled() {
+            if let Some(leg) = bracket.leg(order_update.id()) {
+                let closed_position = position.close(&order_update);
+                let index = strategy.positions.len() - 1;
+                strategy.positions[index] = crate::strategy::MarketPosition {
+                    exit_trigger: leg.name().to_string(),
+                    ..closed_position
+                };
+                // the legs are one cancels other, canceling the filled one again is harmless
+                let request_id = open.reserve_request_ids(2);
+                let cancels = bracket.cancel_requests(request_id);
+                open.unsent_requests.extend(cancels);
+                open.order_update = None;
+                strategy.bracket = None;
+                strategy.sent_entry_orders = false;
+                strategy.sent_exit_orders = false;
+                strategy.status = StrategyStatus::AwaitingTrades;
+                drop(strategy);
+                drop(open);
+                program_log(
+                    &format!("{} filled", leg.name()),
+                    ResourceType::Trading,
+                    report_rwl.clone(),
+                )
+                .await;
+                program_log(
+                    &format!("Strategy Status -> {:#?}", StrategyStatus::AwaitingTrades),
+                    ResourceType::Trading,
+                    report_rwl,
+                )
+                .await;
+                return true;
+            }
+        }
+    }
+    let mut modify_stop = false;
+    if let Some(entry_fill) = &position.entry_fill {
+        if bracket.anchor_to_fill(entry_fill.avg_px) {
+            modify_stop = true;
+            let request_id = open.next_request_id();
+            if let Some(request) = bracket.modify_target_request(request_id) {
+                open.unsent_requests.push(request);
+            }
+            log.push(format!("Bracket moved to fill {}", bracket.entry));
+        }
+    }
+    if let Some(quote) = market_data.time_and_sales.get_quote(contract.id) {
+        if bracket.update(&quote) {
+            modify_stop = true;
+            log.push(format!("Stop moved to {}", bracket.stop));
+        }
+    }
+    if modify_stop {
+        let request_id = open.next_request_id();
+        if let Some(request) = bracket.modify_stop_request(request_id) {
+            open.unsent_requests.push(request);
+        }
+    }
+    strategy.bracket = Some(bracket);
+    drop(strategy);
+    drop(open);
+    for line in log {
+        program_log(&line, ResourceType::Trading, report_rwl.clone()).await;
+    }
+    false
+}
@@ -0,0 +1,368 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeZone, Utc, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::{others::open_json, settings::TRADING_CALENDAR_FILENAME};
+
+pub type TradingCalendarRef = Arc<TradingCalendar>;
+
+// How far ahead next_open and next_close look before giving up
+const SEARCH_DAYS: i64 = 14;
+
+// A session is named by its trade date, the day it closes. When open is later than close the
+// session opens on the previous calendar day, like the CME Globex evening open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTemplate {
+    pub timezone: chrono_tz::Tz,
+    pub open: NaiveTime,
+    pub close: NaiveTime,
+    pub trading_days: Vec<Weekday>,
+}
+impl SessionTemplate {
+    pub fn cme_globex() -> Self {
+        Self {
+            timezone: chrono_tz::America::Chicago,
+            open: NaiveTime::from_hms(17, 0, 0),
+            close: NaiveTime::from_hms(16, 0, 0),
+            trading_days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
+        }
+    }
+    pub fn opens_previous_day(&self) -> bool {
+        self.open >= self.close
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Session {
+    pub trade_date: NaiveDate,
+    pub open: DateTime<Utc>,
+    pub close: DateTime<Utc>,
+}
+impl Session {
+    pub fn contains(&self, t: DateTime<Utc>) -> bool {
+        t >= self.open && t < self.close
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExceptionKind {
+    Closed,
+    EarlyClose(NaiveTime),
+}
+
+// One row of the calendar file. No products means every product.
+// {"date": "2022-04-15", "early_close": null, "products": []}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CalendarFileEntry {
+    pub date: String,
+    #[serde(default)]
+    pub early_close: Option<String>,
+    #[serde(default)]
+    pub products: Vec<String>,
+    #[serde(default)]
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalendarException {
+    pub trade_date: NaiveDate,
+    pub kind: ExceptionKind,
+    pub products: Vec<String>,
+}
+impl CalendarException {
+    pub fn from_file_entry(entry: &CalendarFileEntry) -> Result<Self, String> {
+        let trade_date = NaiveDate::parse_from_str(&entry.date, "%Y-%m-%d")
+            .map_err(|e| format!("invalid calendar date {}: {}", entry.date, e))?;
+        let kind = match &entry.early_close {
+            Some(close) => ExceptionKind::EarlyClose(
+                NaiveTime::parse_from_str(close, "%H:%M")
+                    .map_err(|e| format!("invalid early close {}: {}", close, e))?,
+            ),
+            None => ExceptionKind::Closed,
+        };
+        Ok(Self {
+            trade_date,
+            kind,
+            products: entry.products.clone(),
+        })
+    }
+    pub fn applies_to(&self, product: &str) -> bool {
+        self.products.is_empty() || self.products.iter().any(|listed| listed == product)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TradingCalendar {
+    pub templates: HashMap<String, SessionTemplate>,
+    pub default_template: SessionTemplate,
+    pub exceptions: Vec<CalendarException>,
+}
+impl TradingCalendar {
+    pub fn new() -> Self {
+        let templates = ["ES", "NQ", "YM", "RTY", "MES", "MNQ", "MYM", "M2K"]
+            .iter()
+            .map(|product| (product.to_string(), SessionTemplate::cme_globex()))
+            .collect();
+        Self {
+            templates,
+            default_template: SessionTemplate::cme_globex(),
+            exceptions: Vec::new(),
+        }
+    }
+    pub fn from_entries(entries: &[CalendarFileEntry]) -> Result<Self, String> {
+        let mut calendar = Self::new();
+        for entry in entries {
+            calendar
+                .exceptions
+                .push(CalendarException::from_file_entry(entry)?);
+        }
+        Ok(calendar)
+    }
+    // Holidays and early closes are read from TRADING_CALENDAR_FILENAME. A year without a single
+    // exception has not been filled in yet, so the current year must be listed.
+    pub fn load(now: DateTime<Utc>) -> Result<Self, String> {
+        let file = open_json(TRADING_CALENDAR_FILENAME)
+            .map_err(|e| format!("could not read {}: {}", TRADING_CALENDAR_FILENAME, e))?;
+        let entries: Vec<CalendarFileEntry> = serde_json::from_value(file)
+            .map_err(|e| format!("invalid {}: {}", TRADING_CALENDAR_FILENAME, e))?;
+        let calendar = Self::from_entries(&entries)?;
+        let year = now
+            .with_timezone(&calendar.default_template.timezone)
+            .year();
+        if !calendar.covers(year) {
+            return Err(format!(
+                "{} has no holidays for {}, add them before trading",
+                TRADING_CALENDAR_FILENAME, year
+            ));
+        }
+        Ok(calendar)
+    }
+    pub fn covers(&self, year: i32) -> bool {
+        self.exceptions
+            .iter()
+            .any(|exception| exception.trade_date.year() == year)
+    }
+    pub fn template(&self, product: &str) -> &SessionTemplate {
+        self.templates
+            .get(product)
+            .unwrap_or(&self.default_template)
+    }
+    pub fn session(&self, product: &str, trade_date: NaiveDate) -> Option<Session> {
+        let template = self.template(product);
+        if !template.trading_days.contains(&trade_date.weekday()) {
+            return None;
+        }
+        let mut close = template.close;
+        for exception in self
+            .exceptions
+            .iter()
+            .filter(|exception| exception.trade_date == trade_date && exception.applies_to(product))
+        {
+            match exception.kind {
+                ExceptionKind::Closed => return None,
+                ExceptionKind::EarlyClose(early_close) => close = close.min(early_close),
+            }
+        }
+        let open_date = if template.opens_previous_day() {
+            trade_date.pred()
+        } else {
+            trade_date
+        };
+        let open = template
+            .timezone
+            .from_local_datetime(&open_date.and_time(template.open))
+            .earliest()?
+            .with_timezone(&Utc);
+        let close = template
+            .timezone
+            .from_local_datetime(&trade_date.and_time(close))
+            .earliest()?
+            .with_timezone(&Utc);
+        Some(Session {
+            trade_date,
+            open,
+            close,
+        })
+    }
+    // Sessions whose close is at or after t, in order
+    fn sessions_from(&self, product: &str, t: DateTime<Utc>) -> impl Iterator<Item = Session> + '_ {
+        let product = product.to_string();
+        let first_date = t.with_timezone(&self.template(&product).timezone).date().naive_local();
+        (0..=SEARCH_DAYS)
+            .map(move |n| first_date + chrono::Duration::days(n))
+            .filter_map(move |trade_date| self.session(&product, trade_date))
+            .filter(move |session| session.close > t)
+    }
+    pub fn current_session(&self, product: &str, t: DateTime<Utc>) -> Option<Session> {
+        self.sessions_from(product, t)
+            .next()
+            .filter(|session| session.contains(t))
+    }
+    pub fn is_open(&self, product: &str, t: DateTime<Utc>) -> bool {
+        self.current_session(product, t).is_some()
+    }
+    // t itself when the market is already open
+    pub fn next_open(&self, product: &str, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.sessions_from(product, t)
+            .next()
+            .map(|session| session.open.max(t))
+    }
+    pub fn next_close(&self, product: &str, t: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.sessions_from(product, t)
+            .next()
+            .map(|session| session.close)
+    }
+    // None when the market is closed
+    pub fn time_to_close(&self, product: &str, t: DateTime<Utc>) -> Option<std::time::Duration> {
+        self.current_session(product, t)
+            .and_then(|session| (session.close - t).to_std().ok())
+    }
+    // Zero when the market is open
+    pub fn time_to_open(&self, product: &str, t: DateTime<Utc>) -> Option<std::time::Duration> {
+        self.next_open(product, t)
+            .and_then(|open| (open - t).to_std().ok())
+    }
+}
+impl Default for TradingCalendar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap()
+    }
+
+    fn entry(date: &str, early_close: Option<&str>, products: &[&str]) -> CalendarFileEntry {
+        CalendarFileEntry {
+            date: date.to_string(),
+            early_close: early_close.map(|close| close.to_string()),
+            products: products.iter().map(|product| product.to_string()).collect(),
+            description: String::new(),
+        }
+    }
+
+    #[test]
+    fn sessions_open_at_17_and_close_at_16_central_across_dst() {
+        let calendar = TradingCalendar::new();
+        // Friday before the spring change, CST
+        let session = calendar.session("ES", date("2022-03-11")).unwrap();
+        assert_eq!(session.open, utc("2022-03-10T23:00:00Z"));
+        assert_eq!(session.close, utc("2022-03-11T22:00:00Z"));
+        // Monday after it, opening the Sunday the clocks changed, CDT
+        let session = calendar.session("ES", date("2022-03-14")).unwrap();
+        assert_eq!(session.open, utc("2022-03-13T22:00:00Z"));
+        assert_eq!(session.close, utc("2022-03-14T21:00:00Z"));
+        // Friday before the fall change, CDT
+        let session = calendar.session("ES", date("2022-11-04")).unwrap();
+        assert_eq!(session.open, utc("2022-11-03T22:00:00Z"));
+        assert_eq!(session.close, utc("2022-11-04T21:00:00Z"));
+        // Monday after it, opening the Sunday the clocks changed, CST
+        let session = calendar.session("ES", date("2022-11-07")).unwrap();
+        assert_eq!(session.open, utc("2022-11-06T23:00:00Z"));
+        assert_eq!(session.close, utc("2022-11-07T22:00:00Z"));
+    }
+
+    #[test]
+    fn weekends_and_the_daily_break_are_closed() {
+        let calendar = TradingCalendar::new();
+        assert_eq!(calendar.session("ES", date("2022-10-15")), None);
+        assert_eq!(calendar.session("ES", date("2022-10-16")), None);
+        // the daily break between 16:00 and 17:00 CDT
+        assert!(calendar.is_open("ES", utc("2022-10-18T20:59:59Z")));
+        assert!(!calendar.is_open("ES", utc("2022-10-18T21:30:00Z")));
+        assert!(calendar.is_open("ES", utc("2022-10-18T22:00:00Z")));
+        // Saturday waits for the Sunday evening open of Monday's session
+        let saturday = utc("2022-10-15T12:00:00Z");
+        assert!(!calendar.is_open("ES", saturday));
+        assert_eq!(
+            calendar.next_open("ES", saturday),
+            Some(utc("2022-10-16T22:00:00Z"))
+        );
+        assert_eq!(
+            calendar
+                .current_session("ES", utc("2022-10-16T22:00:00Z"))
+                .map(|session| session.trade_date),
+            Some(date("2022-10-17"))
+        );
+    }
+
+    #[test]
+    fn holidays_skip_the_whole_session() {
+        let calendar = TradingCalendar::from_entries(&[entry("2022-04-15", None, &[])]).unwrap();
+        assert_eq!(calendar.session("ES", date("2022-04-15")), None);
+        // Thursday evening would have opened Good Friday's session
+        let thursday_evening = utc("2022-04-14T23:00:00Z");
+        assert!(!calendar.is_open("ES", thursday_evening));
+        assert_eq!(calendar.time_to_close("ES", thursday_evening), None);
+        assert_eq!(
+            calendar.next_open("ES", thursday_evening),
+            Some(utc("2022-04-17T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn early_closes_shorten_the_session() {
+        let calendar =
+            TradingCalendar::from_entries(&[entry("2022-07-04", Some("12:00"), &[])]).unwrap();
+        let session = calendar.session("ES", date("2022-07-04")).unwrap();
+        assert_eq!(session.open, utc("2022-07-03T22:00:00Z"));
+        assert_eq!(session.close, utc("2022-07-04T17:00:00Z"));
+        assert_eq!(
+            calendar.time_to_close("ES", utc("2022-07-04T16:00:00Z")),
+            Some(std::time::Duration::from_secs(3600))
+        );
+        assert!(!calendar.is_open("ES", utc("2022-07-04T17:30:00Z")));
+        assert_eq!(
+            calendar.next_open("ES", utc("2022-07-04T17:30:00Z")),
+            Some(utc("2022-07-04T22:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn exceptions_only_apply_to_their_products() {
+        let calendar =
+            TradingCalendar::from_entries(&[entry("2022-04-15", None, &["ES"])]).unwrap();
+        assert_eq!(calendar.session("ES", date("2022-04-15")), None);
+        assert!(calendar.session("NQ", date("2022-04-15")).is_some());
+    }
+
+    #[test]
+    fn coverage_is_the_years_with_exceptions() {
+        let calendar = TradingCalendar::from_entries(&[entry("2022-04-15", None, &[])]).unwrap();
+        assert!(calendar.covers(2022));
+        assert!(!calendar.covers(2023));
+        assert!(!TradingCalendar::new().covers(2022));
+    }
+
+    #[test]
+    fn the_calendar_file_lists_trading_days_only() {
+        let entries: Vec<CalendarFileEntry> =
+            serde_json::from_value(open_json(TRADING_CALENDAR_FILENAME).unwrap()).unwrap();
+        let calendar = TradingCalendar::from_entries(&entries).unwrap();
+        for exception in &calendar.exceptions {
+            assert!(
+                SessionTemplate::cme_globex()
+                    .trading_days
+                    .contains(&exception.trade_date.weekday()),
+                "{} is not a trading day",
+                exception.trade_date
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc,
+};
+
+use chrono::TimeZone;
+
+use crate::replay::ReplayClock;
+
+pub type ClockRef = Arc<dyn Clock>;
+
+// Everything time dependent asks a clock instead of the system time, so the same code runs live,
+// on a replay server and under a manual clock.
+pub trait Clock: std::fmt::Debug + Send + Sync {
+    fn now_millis(&self) -> i64;
+    fn now(&self) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc.timestamp_millis(self.now_millis())
+    }
+    // Called with every server clock event. Only clocks that follow the server use it.
+    fn sync(&self, _server_time: i64, _speed: i64) {}
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        chrono::Utc::now().timestamp_millis()
+    }
+}
+
+// Only moves when told to
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    millis: AtomicI64,
+}
+impl ManualClock {
+    pub fn new(millis: i64) -> Self {
+        Self {
+            millis: AtomicI64::new(millis),
+        }
+    }
+    pub fn set(&self, millis: i64) {
+        self.millis.store(millis, Ordering::SeqCst);
+    }
+    pub fn advance(&self, millis: i64) {
+        self.millis.fetch_add(millis, Ordering::SeqCst);
+    }
+}
+impl Clock for ManualClock {
+    fn now_millis(&self) -> i64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+    fn sync(&self, server_time: i64, _speed: i64) {
+        self.set(server_time);
+    }
+}
+
+// Follows the clock events of a replay session, starting from the replay start time
+#[derive(Debug)]
+pub struct ReplayServerClock {
+    latest: std::sync::RwLock<ReplayClock>,
+}
+impl ReplayServerClock {
+    pub fn new(start_timestamp: i64, speed: i64) -> Self {
+        Self {
+            latest: std::sync::RwLock::new(ReplayClock {
+                server_time: start_timestamp,
+                speed,
+                received_at: tokio::time::Instant::now(),
+            }),
+        }
+    }
+}
+impl Clock for ReplayServerClock {
+    fn now_millis(&self) -> i64 {
+        match self.latest.read() {
+            Ok(latest) => latest.now_millis(),
+            Err(poisoned) => poisoned.into_inner().now_millis(),
+        }
+    }
+    fn sync(&self, server_time: i64, speed: i64) {
+        let clock = ReplayClock {
+            server_time,
+            speed,
+            received_at: tokio::time::Instant::now(),
+        };
+        match self.latest.write() {
+            Ok(mut latest) => *latest = clock,
+            Err(poisoned) => *poisoned.into_inner() = clock,
+        }
+    }
+}
+
+pub fn system_clock() -> ClockRef {
+    Arc::new(SystemClock)
+}
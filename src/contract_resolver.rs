@@ -0,0 +1,383 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+
+use crate::{
+    api_client::ResourceType,
+    instrument_catalog::{refresh_if_expired, InstrumentCatalog, InstrumentCatalogRWL},
+    md_socket::{
+        APIClientRWL, ChartTimeRange, MarketData, MarketDataOutGoingRWL, MarketDataRWL,
+        RequestStatus,
+    },
+    models::{AcctCommChannelRWL, ContractID, ContractMaturity},
+    order_journal::OrderIntent,
+    reporting::{program_log, send_email, send_txt_message, ReportRWL},
+    session_boundary::create_liquidate_position_request,
+    settings::{
+        ALERT_PHONE_NUMBER, BACKFILL_MINUTES, REPORT_TO_EMAIL, ROLL_CHECK_SECONDS,
+        ROLL_DAYS_BEFORE_EXPIRATION, ROLL_POSITIONS, ROLL_VOLUME_WATCH_DAYS,
+        ROLL_VOLUME_WINDOW_MILLIS, TRADING_PRODUCT,
+    },
+    strategy::{StrategyRWL, StrategyStatus},
+    time_and_sales::OrderAction,
+    watchdog::FeedWatchdogRWL,
+};
+
+pub type ContractResolverRWL = Arc<tokio::sync::RwLock<ContractResolver>>;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    UnknownProduct(String),
+    // the product has no maturity left before its roll date
+    NoActiveContract(String),
+}
+impl std::fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResolveError::UnknownProduct(product) => {
+                write!(f, "{} is not in the instrument catalog", product)
+            }
+            ResolveError::NoActiveContract(product) => {
+                write!(f, "no active contract found for {}", product)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollReason {
+    Scheduled,
+    // the next contract outtraded the front month inside the watch window
+    Volume,
+}
+
+pub fn roll_date(maturity: &ContractMaturity) -> Option<DateTime<Utc>> {
+    maturity
+        .expiration()
+        .map(|expiration| expiration - chrono::Duration::days(ROLL_DAYS_BEFORE_EXPIRATION))
+}
+
+// The contract traded for a product and the one it rolls to
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedContract {
+    pub product: String,
+    pub front: ContractID,
+    pub front_maturity: ContractMaturity,
+    pub next: Option<(ContractID, ContractMaturity)>,
+}
+impl ResolvedContract {
+    pub fn roll_date(&self) -> Option<DateTime<Utc>> {
+        roll_date(&self.front_maturity)
+    }
+    // The next contract's volume is only compared once it is being watched
+    pub fn watches_next(&self, now: DateTime<Utc>) -> bool {
+        self.next.is_some()
+            && self.roll_date().is_some_and(|roll_date| {
+                now >= roll_date - chrono::Duration::days(ROLL_VOLUME_WATCH_DAYS)
+            })
+    }
+    pub fn roll_reason(&self, now: DateTime<Utc>, front_volume: i64, next_volume: i64) -> Option<RollReason> {
+        self.next.as_ref()?;
+        if self.roll_date().is_none_or(|roll_date| now >= roll_date) {
+            Some(RollReason::Scheduled)
+        } else if self.watches_next(now) && next_volume > front_volume {
+            Some(RollReason::Volume)
+        } else {
+            None
+        }
+    }
+}
+
+// Turns product roots like ES or MES into the contracts to trade
+#[derive(Debug, Clone, Default)]
+pub struct ContractResolver {
+    pub contracts: HashMap<String, ResolvedContract>,
+}
+impl ContractResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn resolve_all(
+        catalog: &InstrumentCatalog,
+        products: &[&str],
+        now: DateTime<Utc>,
+    ) -> Result<Self, ResolveError> {
+        let mut resolver = Self::new();
+        for product in products {
+            let resolved = resolve(catalog, product, now, 0)?;
+            resolver.contracts.insert(product.to_string(), resolved);
+        }
+        Ok(resolver)
+    }
+    pub fn front(&self, product: &str) -> Option<&ContractID> {
+        self.contracts.get(product).map(|resolved| &resolved.front)
+    }
+    pub fn front_symbol(&self, product: &str) -> Option<String> {
+        self.front(product).map(|contract| contract.symbol.clone())
+    }
+    pub fn front_contracts(&self) -> Vec<ContractID> {
+        self.contracts
+            .values()
+            .map(|resolved| resolved.front.clone())
+            .collect()
+    }
+}
+
+// The front month is the earliest maturity whose roll date has not passed. Maturities up to
+// rolled_through (an expiration month like 202206) are skipped, for rolls that happen early on volume.
+pub fn resolve(
+    catalog: &InstrumentCatalog,
+    product: &str,
+    now: DateTime<Utc>,
+    rolled_through: i64,
+) -> Result<ResolvedContract, ResolveError> {
+    let product_info = catalog
+        .product(product)
+        .ok_or_else(|| ResolveError::UnknownProduct(product.to_string()))?;
+    let maturities: Vec<&ContractMaturity> = catalog
+        .product_maturities(product_info.id)
+        .into_iter()
+        .filter(|maturity| {
+            !maturity.archived
+                && maturity.expiration_month > rolled_through
+                && roll_date(maturity).is_some_and(|roll_date| roll_date > now)
+        })
+        .collect();
+    let contract_of = |maturity: &ContractMaturity| {
+        catalog
+            .maturity_contracts(maturity.id)
+            .first()
+            .and_then(|contract| catalog.instrument(contract.id))
+            .map(|instrument| instrument.contract)
+    };
+    let front_maturity = maturities
+        .first()
+        .ok_or_else(|| ResolveError::NoActiveContract(product.to_string()))?;
+    let front = contract_of(front_maturity)
+        .ok_or_else(|| ResolveError::NoActiveContract(product.to_string()))?;
+    let next = maturities.get(1).and_then(|next_maturity| {
+        contract_of(next_maturity).map(|next| (next, (*next_maturity).clone()))
+    });
+    Ok(ResolvedContract {
+        product: product.to_string(),
+        front,
+        front_maturity: (*front_maturity).clone(),
+        next,
+    })
+}
+
+// Checks every product for a due roll. The next contract's chart is subscribed inside the watch
+// window so its volume can be compared. On roll every subscription moves to the next contract.
+// The traded product waits until the strategy is flat, unless ROLL_POSITIONS liquidates the old
+// position and reopens it on the next contract.
+#[allow(clippy::too_many_arguments)]
+pub async fn watch_rollovers(
+    resolver_rwl: ContractResolverRWL,
+    catalog_rwl: InstrumentCatalogRWL,
+    api_client_rwl: APIClientRWL,
+    market_data_rwl: MarketDataRWL,
+    market_data_outgoing_rwl: MarketDataOutGoingRWL,
+    strategy_rwl: StrategyRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+    watchdog_rwl: FeedWatchdogRWL,
+    report_rwl: ReportRWL,
+) {
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(ROLL_CHECK_SECONDS));
+    loop {
+        interval.tick().await;
+        let resolved: Vec<ResolvedContract> =
+            resolver_rwl.read().await.contracts.values().cloned().collect();
+        for contract in resolved {
+            let now = market_data_rwl.read().await.clock.now();
+            let (next, _) = match &contract.next {
+                Some(next) => next,
+                None => continue,
+            };
+            if contract.watches_next(now) {
+                watch_contract(next, &market_data_rwl, &market_data_outgoing_rwl, &watchdog_rwl).await;
+            }
+            let market_data = market_data_rwl.read().await;
+            let now_millis = market_data.now_millis();
+            let volume = |contract_id: i64| -> i64 {
+                market_data
+                    .time_and_sales
+                    .range(contract_id, now_millis - ROLL_VOLUME_WINDOW_MILLIS, now_millis)
+                    .iter()
+                    .map(|item| item.qty)
+                    .sum()
+            };
+            let (front_volume, next_volume) = (volume(contract.front.id), volume(next.id));
+            drop(market_data);
+            let reason = match contract.roll_reason(now, front_volume, next_volume) {
+                Some(reason) => reason,
+                None => continue,
+            };
+            let position_report = if contract.product == TRADING_PRODUCT {
+                match roll_strategy(&contract, strategy_rwl.clone(), acct_comms_channel.clone()).await {
+                    Some(position_report) => position_report,
+                    // the strategy is busy with the old contract, try again on the next check
+                    None => continue,
+                }
+            } else {
+                String::new()
+            };
+            move_subscriptions(
+                &contract.front,
+                next,
+                &market_data_rwl,
+                &market_data_outgoing_rwl,
+                &watchdog_rwl,
+            )
+            .await;
+            // a stale catalog may not list the maturity after the next one yet
+            if let Err(e) = refresh_if_expired(&catalog_rwl, &api_client_rwl).await {
+                program_log(
+                    &format!("Could not refresh the instrument catalog: {}", e),
+                    ResourceType::Trading,
+                    report_rwl.clone(),
+                )
+                .await;
+            }
+            let resolved = resolve(
+                &*catalog_rwl.read().await,
+                &contract.product,
+                now,
+                contract.front_maturity.expiration_month,
+            );
+            let rolled = match resolved {
+                Ok(rolled) => rolled,
+                Err(e) => {
+                    program_log(
+                        &format!("Could not resolve the contract after {}: {}", next.symbol, e),
+                        ResourceType::Trading,
+                        report_rwl.clone(),
+                    )
+                    .await;
+                    ResolvedContract {
+                        product: contract.product.clone(),
+                        front: next.clone(),
+                        front_maturity: contract.next.clone().unwrap().1,
+                        next: None,
+                    }
+                }
+            };
+            resolver_rwl
+                .write()
+                .await
+                .contracts
+                .insert(contract.product.clone(), rolled);
+            let report = format!(
+                "{} rolled from {} to {} ({:?}, front volume {}, next volume {})\n{}",
+                contract.product,
+                contract.front.symbol,
+                next.symbol,
+                reason,
+                front_volume,
+                next_volume,
+                position_report
+            );
+            program_log(&report, ResourceType::Trading, report_rwl.clone()).await;
+            let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+            send_email(REPORT_TO_EMAIL, &report, "Contract Roll").await;
+        }
+    }
+}
+
+// Subscribes the next contract's chart without letting its silence reconnect the socket
+async fn watch_contract(
+    next: &ContractID,
+    market_data_rwl: &MarketDataRWL,
+    market_data_outgoing_rwl: &MarketDataOutGoingRWL,
+    watchdog_rwl: &FeedWatchdogRWL,
+) {
+    let mut market_data = market_data_rwl.write().await;
+    if market_data.contract(&next.symbol).is_some() {
+        return;
+    }
+    market_data.contract_ids.push(next.clone());
+    let mut outgoing = market_data_outgoing_rwl.write().await;
+    outgoing.add_subscription(MarketData::Chart, &next.symbol);
+    drop(outgoing);
+    drop(market_data);
+    watchdog_rwl.write().await.set_quiet(next.id, true);
+}
+
+// The old contract stays in contract_ids so positions still open on it can be valued
+async fn move_subscriptions(
+    front: &ContractID,
+    next: &ContractID,
+    market_data_rwl: &MarketDataRWL,
+    market_data_outgoing_rwl: &MarketDataOutGoingRWL,
+    watchdog_rwl: &FeedWatchdogRWL,
+) {
+    let mut market_data = market_data_rwl.write().await;
+    if market_data.contract(&next.symbol).is_none() {
+        market_data.contract_ids.push(next.clone());
+    }
+    let mut outgoing = market_data_outgoing_rwl.write().await;
+    let data_types: Vec<MarketData> = outgoing
+        .data_requests
+        .iter()
+        .filter(|request| request.symbol == front.symbol && request.status != RequestStatus::Canceled)
+        .map(|request| request.data_type)
+        .collect();
+    for data_type in data_types {
+        if data_type == MarketData::Chart && outgoing.replay.is_none() {
+            let time_range = ChartTimeRange::last_minutes(BACKFILL_MINUTES, outgoing.clock.now());
+            outgoing.request_backfill(&next.symbol, time_range);
+        }
+        outgoing.add_subscription(data_type, &next.symbol);
+        outgoing.remove_subscription(data_type, &front.symbol);
+    }
+    drop(outgoing);
+    drop(market_data);
+    let mut watchdog = watchdog_rwl.write().await;
+    watchdog.forget_contract(front.id);
+    watchdog.set_quiet(next.id, false);
+}
+
+// Returns None while the strategy has orders working on the old contract or, without
+// ROLL_POSITIONS, while it holds a position there.
+async fn roll_strategy(
+    contract: &ResolvedContract,
+    strategy_rwl: StrategyRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+) -> Option<String> {
+    let (next, _) = contract.next.as_ref()?;
+    let mut open = acct_comms_channel.write().await;
+    let mut strategy = strategy_rwl.write().await;
+    let report = match strategy.status {
+        StrategyStatus::Unaware | StrategyStatus::AwaitingTrades => "Strategy was flat".to_string(),
+        StrategyStatus::InATrade if ROLL_POSITIONS && !strategy.sent_exit_orders => {
+            let position = strategy.positions.last()?.clone();
+            let action = if position.net_pos > 0 {
+                OrderAction::Buy
+            } else {
+                OrderAction::Sell
+            };
+            let intent = OrderIntent::liquidate(position.account_id, position.contract_id);
+            let cl_ord_id = open
+                .submit_order(intent, |request_id, _| {
+                    create_liquidate_position_request(
+                        request_id,
+                        position.account_id,
+                        position.contract_id,
+                    )
+                })
+                .ok()?;
+            strategy.pending_roll_entry = Some((action, position.net_pos.abs()));
+            strategy.order_cl_ord_id = Some(cl_ord_id);
+            strategy.sent_exit_orders = true;
+            strategy.status = StrategyStatus::SentExitOrders;
+            let index = strategy.positions.len() - 1;
+            strategy.positions[index].exit_trigger = "Contract roll".to_string();
+            format!(
+                "Liquidating {} {} and reopening it on {}",
+                position.net_pos, contract.front.symbol, next.symbol
+            )
+        }
+        _ => return None,
+    };
+    strategy.symbol = next.symbol.clone();
+    Some(report)
+}
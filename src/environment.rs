@@ -0,0 +1,49 @@
+use std::{io::Write, sync::OnceLock};
+
+use crate::{
+    api_client::Server,
+    settings::{DEFAULT_SERVER, LIVE_CONFIRMATION_PHRASE},
+};
+
+static ACTIVE_SERVER: OnceLock<Server> = OnceLock::new();
+
+// Picks the server from --server, falling back to DEFAULT_SERVER. The live server also needs
+// --confirm-live or the confirmation phrase typed in before anything connects.
+pub fn select_server() -> Result<Server, String> {
+    let args: Vec<String> = std::env::args().collect();
+    let server = match args.iter().position(|arg| arg == "--server") {
+        Some(n) => {
+            let name = args
+                .get(n + 1)
+                .ok_or_else(|| "--server needs one of live, demo, replay or custom".to_string())?;
+            Server::from_name(name).ok_or_else(|| format!("unknown server {}", name))?
+        }
+        None => DEFAULT_SERVER,
+    };
+    if server.is_live() && !args.iter().any(|arg| arg == "--confirm-live") && !confirm_live() {
+        return Err("Live trading was not confirmed".to_string());
+    }
+    ACTIVE_SERVER
+        .set(server)
+        .map_err(|_| "The server was already selected".to_string())?;
+    Ok(server)
+}
+
+fn confirm_live() -> bool {
+    print!(
+        "You are about to trade with real money. Type {} to continue: ",
+        LIVE_CONFIRMATION_PHRASE
+    );
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && answer.trim() == LIVE_CONFIRMATION_PHRASE
+}
+
+pub fn active_server() -> Server {
+    *ACTIVE_SERVER.get().unwrap_or(&DEFAULT_SERVER)
+}
+
+// Stamped on log lines, notifications and orders
+pub fn environment_label() -> &'static str {
+    active_server().name()
+}
@@ -0,0 +1,927 @@
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+
+use crate::{
+    models::Dom,
+    settings::{
+        ABSORPTION_MAX_TICKS, ABSORPTION_VOLUME, ABSORPTION_WINDOW, DOM_IMBALANCE_LEVELS,
+        IMBALANCE_RATIO, INDICATOR_BAR_MILLIS, INDICATOR_LOOKBACK, STACKED_IMBALANCE_LEVELS,
+        VWAP_BAND_DEVIATIONS,
+    },
+    time_and_sales::{OrderAction, TimeAndSalesItem},
+};
+
+// Every indicator here is updated one value at a time in O(1) (amortized for the rolling
+// extremes) so they can be fed straight from the socket without rescanning history.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Bar {
+    pub timestamp: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: i64,
+    pub delta: i64,
+}
+impl Bar {
+    pub fn from_item(item: &TimeAndSalesItem, timestamp: i64) -> Self {
+        Self {
+            timestamp,
+            open: item.price,
+            high: item.price,
+            low: item.price,
+            close: item.price,
+            volume: item.qty,
+            delta: signed_qty(item),
+        }
+    }
+    pub fn add(&mut self, item: &TimeAndSalesItem) {
+        self.high = self.high.max(item.price);
+        self.low = self.low.min(item.price);
+        self.close = item.price;
+        self.volume += item.qty;
+        self.delta += signed_qty(item);
+    }
+}
+
+pub fn signed_qty(item: &TimeAndSalesItem) -> i64 {
+    match item.action {
+        OrderAction::Buy => item.qty,
+        OrderAction::Sell => -item.qty,
+        OrderAction::Unknown => 0,
+    }
+}
+
+// Builds time based bars. A bar is returned once the first item of the next bar arrives.
+#[derive(Debug, Clone)]
+pub struct BarAggregator {
+    pub period_millis: i64,
+    pub current: Option<Bar>,
+}
+impl BarAggregator {
+    pub fn new(period_millis: i64) -> Self {
+        Self {
+            period_millis,
+            current: None,
+        }
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) -> Option<Bar> {
+        let bar_start = item.timestamp - item.timestamp.rem_euclid(self.period_millis);
+        match &mut self.current {
+            Some(bar) if bar.timestamp == bar_start => {
+                bar.add(item);
+                None
+            }
+            _ => self.current.replace(Bar::from_item(item, bar_start)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sma {
+    pub period: usize,
+    window: VecDeque<f64>,
+    sum: f64,
+}
+impl Sma {
+    pub fn new(period: usize) -> Self {
+        Self {
+            period,
+            window: VecDeque::with_capacity(period),
+            sum: 0.0,
+        }
+    }
+    pub fn update(&mut self, value: f64) -> Option<f64> {
+        self.window.push_back(value);
+        self.sum += value;
+        if self.window.len() > self.period {
+            self.sum -= self.window.pop_front().unwrap_or(0.0);
+        }
+        self.value()
+    }
+    pub fn value(&self) -> Option<f64> {
+        if self.window.len() == self.period {
+            Some(self.sum / self.period as f64)
+        } else {
+            None
+        }
+    }
+}
+
+// Seeded with the simple average of the first `period` values
+#[derive(Debug, Clone)]
+pub struct Ema {
+    pub period: usize,
+    alpha: f64,
+    seed: Sma,
+    value: Option<f64>,
+}
+impl Ema {
+    pub fn new(period: usize) -> Self {
+        Self {
+            period,
+            alpha: 2.0 / (period as f64 + 1.0),
+            seed: Sma::new(period),
+            value: None,
+        }
+    }
+    pub fn update(&mut self, value: f64) -> Option<f64> {
+        self.value = match self.value {
+            Some(previous) => Some(previous + self.alpha * (value - previous)),
+            None => self.seed.update(value),
+        };
+        self.value
+    }
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+}
+
+// Wilder smoothing, shared by ATR and RSI
+#[derive(Debug, Clone)]
+struct WilderAverage {
+    period: usize,
+    count: usize,
+    value: f64,
+}
+impl WilderAverage {
+    fn new(period: usize) -> Self {
+        Self {
+            period,
+            count: 0,
+            value: 0.0,
+        }
+    }
+    fn update(&mut self, value: f64) -> Option<f64> {
+        if self.count < self.period {
+            self.count += 1;
+            self.value += (value - self.value) / self.count as f64;
+        } else {
+            self.value += (value - self.value) / self.period as f64;
+        }
+        self.value()
+    }
+    fn value(&self) -> Option<f64> {
+        if self.count >= self.period {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Atr {
+    previous_close: Option<f64>,
+    average: WilderAverage,
+}
+impl Atr {
+    pub fn new(period: usize) -> Self {
+        Self {
+            previous_close: None,
+            average: WilderAverage::new(period),
+        }
+    }
+    pub fn update(&mut self, bar: &Bar) -> Option<f64> {
+        let true_range = match self.previous_close {
+            Some(close) => (bar.high - bar.low)
+                .max((bar.high - close).abs())
+                .max((bar.low - close).abs()),
+            None => bar.high - bar.low,
+        };
+        self.previous_close = Some(bar.close);
+        self.average.update(true_range)
+    }
+    pub fn value(&self) -> Option<f64> {
+        self.average.value()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rsi {
+    previous_close: Option<f64>,
+    gains: WilderAverage,
+    losses: WilderAverage,
+}
+impl Rsi {
+    pub fn new(period: usize) -> Self {
+        Self {
+            previous_close: None,
+            gains: WilderAverage::new(period),
+            losses: WilderAverage::new(period),
+        }
+    }
+    pub fn update(&mut self, bar: &Bar) -> Option<f64> {
+        if let Some(previous_close) = self.previous_close.replace(bar.close) {
+            let change = bar.close - previous_close;
+            self.gains.update(change.max(0.0));
+            self.losses.update((-change).max(0.0));
+        }
+        self.value()
+    }
+    pub fn value(&self) -> Option<f64> {
+        match (self.gains.value(), self.losses.value()) {
+            (Some(_), Some(0.0)) => Some(100.0),
+            (Some(gains), Some(losses)) => Some(100.0 - 100.0 / (1.0 + gains / losses)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CumulativeDelta {
+    pub value: i64,
+}
+impl CumulativeDelta {
+    pub fn new() -> Self {
+        Self { value: 0 }
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) -> i64 {
+        self.value += signed_qty(item);
+        self.value
+    }
+    pub fn reset(&mut self) {
+        self.value = 0;
+    }
+}
+
+// Rolling max or min over the last `period` values using a monotonic deque
+#[derive(Debug, Clone)]
+pub struct RollingExtreme {
+    pub period: usize,
+    is_max: bool,
+    count: usize,
+    values: VecDeque<(usize, f64)>,
+}
+impl RollingExtreme {
+    pub fn max(period: usize) -> Self {
+        Self::new(period, true)
+    }
+    pub fn min(period: usize) -> Self {
+        Self::new(period, false)
+    }
+    fn new(period: usize, is_max: bool) -> Self {
+        Self {
+            period,
+            is_max,
+            count: 0,
+            values: VecDeque::new(),
+        }
+    }
+    pub fn update(&mut self, value: f64) -> Option<f64> {
+        let is_max = self.is_max;
+        while self.values.back().is_some_and(|(_, last)| {
+            if is_max {
+                *last <= value
+            } else {
+                *last >= value
+            }
+        }) {
+            self.values.pop_back();
+        }
+        self.values.push_back((self.count, value));
+        self.count += 1;
+        while self
+            .values
+            .front()
+            .is_some_and(|(index, _)| index + self.period < self.count)
+        {
+            self.values.pop_front();
+        }
+        self.value()
+    }
+    pub fn value(&self) -> Option<f64> {
+        self.values.front().map(|(_, value)| *value)
+    }
+    pub fn is_full(&self) -> bool {
+        self.count >= self.period
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Divergence {
+    Bullish,
+    Bearish,
+}
+
+// Price making a new high over the lookback while cumulative delta does not is bearish,
+// and the mirror image is bullish.
+#[derive(Debug, Clone)]
+pub struct DeltaDivergence {
+    price_highs: RollingExtreme,
+    price_lows: RollingExtreme,
+    delta_highs: RollingExtreme,
+    delta_lows: RollingExtreme,
+}
+impl DeltaDivergence {
+    pub fn new(lookback: usize) -> Self {
+        Self {
+            price_highs: RollingExtreme::max(lookback),
+            price_lows: RollingExtreme::min(lookback),
+            delta_highs: RollingExtreme::max(lookback),
+            delta_lows: RollingExtreme::min(lookback),
+        }
+    }
+    pub fn update(&mut self, bar: &Bar, cumulative_delta: i64) -> Option<Divergence> {
+        let delta = cumulative_delta as f64;
+        let ready = self.price_highs.is_full();
+        let new_price_high = self.price_highs.value().is_some_and(|high| bar.high > high);
+        let new_price_low = self.price_lows.value().is_some_and(|low| bar.low < low);
+        let new_delta_high = self.delta_highs.value().is_some_and(|high| delta > high);
+        let new_delta_low = self.delta_lows.value().is_some_and(|low| delta < low);
+        self.price_highs.update(bar.high);
+        self.price_lows.update(bar.low);
+        self.delta_highs.update(delta);
+        self.delta_lows.update(delta);
+        if !ready {
+            None
+        } else if new_price_high && !new_delta_high {
+            Some(Divergence::Bearish)
+        } else if new_price_low && !new_delta_low {
+            Some(Divergence::Bullish)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct VwapBands {
+    pub vwap: f64,
+    pub standard_deviation: f64,
+    pub upper: f64,
+    pub lower: f64,
+}
+
+// Anchored at the session open. The first tick of a new trade date starts it over and ticks of an
+// earlier session, like a late backfill page, are left out.
+#[derive(Debug, Clone, Default)]
+pub struct Vwap {
+    volume: f64,
+    price_volume: f64,
+    price_squared_volume: f64,
+    trade_date: i64,
+}
+impl Vwap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) -> Option<f64> {
+        if item.trade_date < self.trade_date {
+            return self.value();
+        }
+        if item.trade_date > self.trade_date {
+            self.reset();
+            self.trade_date = item.trade_date;
+        }
+        let qty = item.qty as f64;
+        self.volume += qty;
+        self.price_volume += item.price * qty;
+        self.price_squared_volume += item.price * item.price * qty;
+        self.value()
+    }
+    pub fn value(&self) -> Option<f64> {
+        if self.volume > 0.0 {
+            Some(self.price_volume / self.volume)
+        } else {
+            None
+        }
+    }
+    pub fn standard_deviation(&self) -> Option<f64> {
+        self.value().map(|vwap| {
+            (self.price_squared_volume / self.volume - vwap * vwap)
+                .max(0.0)
+                .sqrt()
+        })
+    }
+    pub fn bands(&self, deviations: f64) -> Option<VwapBands> {
+        let vwap = self.value()?;
+        let standard_deviation = self.standard_deviation()?;
+        Some(VwapBands {
+            vwap,
+            standard_deviation,
+            upper: vwap + deviations * standard_deviation,
+            lower: vwap - deviations * standard_deviation,
+        })
+    }
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+// (bids - asks) / (bids + asks) over the top `levels` of the book. Positive means more resting bids.
+pub fn dom_imbalance_ratio(dom: &Dom, levels: usize) -> f64 {
+    let bids: i64 = dom.bids.iter().take(levels).map(|bid| bid.size).sum();
+    let asks: i64 = dom.offers.iter().take(levels).map(|offer| offer.size).sum();
+    if bids + asks == 0 {
+        0.0
+    } else {
+        (bids - asks) as f64 / (bids + asks) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Absorption {
+    pub price: f64,
+    pub volume: i64,
+    pub delta: i64,
+    pub timestamp: i64,
+}
+
+// Flags a window where a lot of volume traded without the price moving more than `max_ticks`
+#[derive(Debug, Clone)]
+pub struct AbsorptionDetector {
+    pub window_millis: i64,
+    pub min_volume: i64,
+    pub max_ticks: i64,
+    pub tick_size: f64,
+    items: VecDeque<(i64, f64, i64, i64)>,
+    volume: i64,
+    delta: i64,
+    highs: VecDeque<(i64, f64)>,
+    lows: VecDeque<(i64, f64)>,
+}
+impl AbsorptionDetector {
+    pub fn new(window_millis: i64, min_volume: i64, max_ticks: i64, tick_size: f64) -> Self {
+        Self {
+            window_millis,
+            min_volume,
+            max_ticks,
+            tick_size,
+            items: VecDeque::new(),
+            volume: 0,
+            delta: 0,
+            highs: VecDeque::new(),
+            lows: VecDeque::new(),
+        }
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) -> Option<Absorption> {
+        let delta = signed_qty(item);
+        self.items
+            .push_back((item.timestamp, item.price, item.qty, delta));
+        self.volume += item.qty;
+        self.delta += delta;
+        while self.highs.back().is_some_and(|(_, price)| *price <= item.price) {
+            self.highs.pop_back();
+        }
+        self.highs.push_back((item.timestamp, item.price));
+        while self.lows.back().is_some_and(|(_, price)| *price >= item.price) {
+            self.lows.pop_back();
+        }
+        self.lows.push_back((item.timestamp, item.price));
+        let cutoff = item.timestamp - self.window_millis;
+        while let Some((timestamp, _, qty, delta)) = self.items.front().copied() {
+            if timestamp >= cutoff {
+                break;
+            }
+            self.items.pop_front();
+            self.volume -= qty;
+            self.delta -= delta;
+        }
+        while self.highs.front().is_some_and(|(timestamp, _)| *timestamp < cutoff) {
+            self.highs.pop_front();
+        }
+        while self.lows.front().is_some_and(|(timestamp, _)| *timestamp < cutoff) {
+            self.lows.pop_front();
+        }
+        let high = self.highs.front()?.1;
+        let low = self.lows.front()?.1;
+        let range_ticks = ((high - low) / self.tick_size).round() as i64;
+        if self.volume >= self.min_volume && range_ticks <= self.max_ticks {
+            Some(Absorption {
+                price: item.price,
+                volume: self.volume,
+                delta: self.delta,
+                timestamp: item.timestamp,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackedImbalance {
+    pub action: OrderAction,
+    pub low_price: f64,
+    pub high_price: f64,
+    pub levels: usize,
+}
+
+// Footprint of bid/ask volume per price level. Imbalances are diagonal: ask volume at a price
+// against bid volume one tick below (and the reverse for sell imbalances).
+#[derive(Debug, Clone)]
+pub struct StackedImbalanceDetector {
+    pub tick_size: f64,
+    pub ratio: f64,
+    pub min_levels: usize,
+    footprint: HashMap<i64, (i64, i64)>,
+}
+impl StackedImbalanceDetector {
+    pub fn new(tick_size: f64, ratio: f64, min_levels: usize) -> Self {
+        Self {
+            tick_size,
+            ratio,
+            min_levels,
+            footprint: HashMap::new(),
+        }
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) {
+        let level = self.footprint.entry(self.to_ticks(item.price)).or_default();
+        match item.action {
+            OrderAction::Buy => level.1 += item.qty,
+            OrderAction::Sell => level.0 += item.qty,
+            OrderAction::Unknown => {}
+        }
+    }
+    pub fn reset(&mut self) {
+        self.footprint.clear();
+    }
+    pub fn detect(&self) -> Vec<StackedImbalance> {
+        let (low, high) = match (self.footprint.keys().min(), self.footprint.keys().max()) {
+            (Some(low), Some(high)) => (*low, *high),
+            _ => return Vec::new(),
+        };
+        let volume_at = |ticks: i64| self.footprint.get(&ticks).copied().unwrap_or((0, 0));
+        let mut found = Vec::new();
+        for action in [OrderAction::Buy, OrderAction::Sell] {
+            let mut run_start: Option<i64> = None;
+            for ticks in low..=high + 1 {
+                let is_imbalanced = ticks <= high
+                    && match action {
+                        OrderAction::Buy => {
+                            is_imbalance(volume_at(ticks).1, volume_at(ticks - 1).0, self.ratio)
+                        }
+                        _ => is_imbalance(volume_at(ticks).0, volume_at(ticks + 1).1, self.ratio),
+                    };
+                match (is_imbalanced, run_start) {
+                    (true, None) => run_start = Some(ticks),
+                    (false, Some(start)) => {
+                        let levels = (ticks - start) as usize;
+                        if levels >= self.min_levels {
+                            found.push(StackedImbalance {
+                                action,
+                                low_price: start as f64 * self.tick_size,
+                                high_price: (ticks - 1) as f64 * self.tick_size,
+                                levels,
+                            });
+                        }
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        found
+    }
+    fn to_ticks(&self, price: f64) -> i64 {
+        (price / self.tick_size).round() as i64
+    }
+}
+fn is_imbalance(aggressive: i64, opposite: i64, ratio: f64) -> bool {
+    aggressive > 0 && aggressive as f64 >= ratio * opposite.max(1) as f64
+}
+
+// The set of indicators kept per contract and fed from the live time and sales stream and the DOM
+#[derive(Debug, Clone)]
+pub struct OrderFlowIndicators {
+    pub bars: BarAggregator,
+    pub last_bar: Option<Bar>,
+    pub cumulative_delta: CumulativeDelta,
+    pub delta_divergence: DeltaDivergence,
+    pub last_divergence: Option<Divergence>,
+    pub vwap: Vwap,
+    pub vwap_bands: Option<VwapBands>,
+    pub ema: Ema,
+    pub sma: Sma,
+    pub atr: Atr,
+    pub rsi: Rsi,
+    pub absorption: AbsorptionDetector,
+    pub last_absorption: Option<Absorption>,
+    pub stacked_imbalances: StackedImbalanceDetector,
+    // found in the last closed bar's footprint
+    pub last_stacked_imbalances: Vec<StackedImbalance>,
+    pub dom_imbalance: Option<f64>,
+}
+impl OrderFlowIndicators {
+    pub fn new(tick_size: f64) -> Self {
+        Self {
+            bars: BarAggregator::new(INDICATOR_BAR_MILLIS),
+            last_bar: None,
+            cumulative_delta: CumulativeDelta::new(),
+            delta_divergence: DeltaDivergence::new(INDICATOR_LOOKBACK),
+            last_divergence: None,
+            vwap: Vwap::new(),
+            vwap_bands: None,
+            ema: Ema::new(INDICATOR_LOOKBACK),
+            sma: Sma::new(INDICATOR_LOOKBACK),
+            atr: Atr::new(INDICATOR_LOOKBACK),
+            rsi: Rsi::new(INDICATOR_LOOKBACK),
+            absorption: AbsorptionDetector::new(
+                ABSORPTION_WINDOW,
+                ABSORPTION_VOLUME,
+                ABSORPTION_MAX_TICKS,
+                tick_size,
+            ),
+            last_absorption: None,
+            stacked_imbalances: StackedImbalanceDetector::new(
+                tick_size,
+                IMBALANCE_RATIO,
+                STACKED_IMBALANCE_LEVELS,
+            ),
+            last_stacked_imbalances: Vec::new(),
+            dom_imbalance: None,
+        }
+    }
+    pub fn update(&mut self, item: &TimeAndSalesItem) {
+        // the bar is closed before the item that opens the next one touches the running totals
+        if let Some(bar) = self.bars.update(item) {
+            self.ema.update(bar.close);
+            self.sma.update(bar.close);
+            self.atr.update(&bar);
+            self.rsi.update(&bar);
+            self.last_divergence = self
+                .delta_divergence
+                .update(&bar, self.cumulative_delta.value);
+            self.last_stacked_imbalances = self.stacked_imbalances.detect();
+            self.stacked_imbalances.reset();
+            self.last_bar = Some(bar);
+        }
+        self.cumulative_delta.update(item);
+        self.vwap.update(item);
+        self.vwap_bands = self.vwap.bands(VWAP_BAND_DEVIATIONS);
+        if let Some(absorption) = self.absorption.update(item) {
+            self.last_absorption = Some(absorption);
+        }
+        self.stacked_imbalances.update(item);
+    }
+    pub fn update_dom(&mut self, dom: &Dom) {
+        self.dom_imbalance = Some(dom_imbalance_ratio(dom, DOM_IMBALANCE_LEVELS));
+    }
+    pub fn snapshot(&self) -> IndicatorSnapshot {
+        IndicatorSnapshot {
+            last_bar: self.last_bar,
+            cumulative_delta: self.cumulative_delta.value,
+            divergence: self.last_divergence,
+            vwap_bands: self.vwap_bands,
+            ema: self.ema.value(),
+            sma: self.sma.value(),
+            atr: self.atr.value(),
+            rsi: self.rsi.value(),
+            absorption: self.last_absorption,
+            stacked_imbalances: self.last_stacked_imbalances.clone(),
+            dom_imbalance: self.dom_imbalance,
+        }
+    }
+}
+
+// What the indicators read right now, logged along with the signals
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndicatorSnapshot {
+    pub last_bar: Option<Bar>,
+    pub cumulative_delta: i64,
+    pub divergence: Option<Divergence>,
+    pub vwap_bands: Option<VwapBands>,
+    pub ema: Option<f64>,
+    pub sma: Option<f64>,
+    pub atr: Option<f64>,
+    pub rsi: Option<f64>,
+    pub absorption: Option<Absorption>,
+    pub stacked_imbalances: Vec<StackedImbalance>,
+    pub dom_imbalance: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{Bid, Offer},
+        trade_classifier::ClassificationRule,
+    };
+
+    fn item(timestamp: i64, action: OrderAction, qty: i64, price: f64) -> TimeAndSalesItem {
+        TimeAndSalesItem {
+            historical_id: 1,
+            contract_id: 1,
+            tick_id: timestamp,
+            action,
+            qty,
+            price,
+            bid: price,
+            ask: price + 0.25,
+            timestamp,
+            receipt_delay: 0,
+            trade_date: 20221017,
+            classified_by: ClassificationRule::Unclassified,
+            confidence: 0.0,
+        }
+    }
+
+    fn bar(high: f64, low: f64, close: f64) -> Bar {
+        Bar {
+            timestamp: 0,
+            open: low,
+            high,
+            low,
+            close,
+            volume: 0,
+            delta: 0,
+        }
+    }
+
+    fn assert_close(value: Option<f64>, expected: f64) {
+        let value = value.expect("indicator has no value");
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn sma_known_values() {
+        let mut sma = Sma::new(3);
+        assert_eq!(sma.update(1.0), None);
+        assert_eq!(sma.update(2.0), None);
+        assert_close(sma.update(3.0), 2.0);
+        assert_close(sma.update(4.0), 3.0);
+    }
+
+    #[test]
+    fn ema_is_seeded_with_the_sma() {
+        let mut ema = Ema::new(3);
+        ema.update(1.0);
+        ema.update(2.0);
+        assert_close(ema.update(3.0), 2.0);
+        assert_close(ema.update(4.0), 3.0);
+        assert_close(ema.update(5.0), 4.0);
+    }
+
+    #[test]
+    fn atr_known_values() {
+        let mut atr = Atr::new(3);
+        assert_eq!(atr.update(&bar(10.0, 8.0, 9.0)), None);
+        assert_eq!(atr.update(&bar(11.0, 9.0, 10.0)), None);
+        // the gap up from 10 makes the true range 4
+        assert_close(atr.update(&bar(14.0, 12.0, 13.0)), 8.0 / 3.0);
+        assert_close(atr.update(&bar(13.0, 12.0, 12.0)), 19.0 / 9.0);
+    }
+
+    #[test]
+    fn rsi_known_values() {
+        let mut rsi = Rsi::new(2);
+        rsi.update(&bar(10.0, 10.0, 10.0));
+        rsi.update(&bar(11.0, 11.0, 11.0));
+        assert_close(rsi.update(&bar(10.0, 10.0, 10.0)), 50.0);
+        assert_close(rsi.update(&bar(12.0, 12.0, 12.0)), 100.0 - 100.0 / 6.0);
+        let mut rising = Rsi::new(2);
+        for close in [1.0, 2.0, 3.0] {
+            rising.update(&bar(close, close, close));
+        }
+        assert_close(rising.value(), 100.0);
+    }
+
+    #[test]
+    fn rolling_extremes_drop_values_leaving_the_window() {
+        let mut max = RollingExtreme::max(3);
+        let mut min = RollingExtreme::min(3);
+        let values = [1.0, 3.0, 2.0, 1.0, 0.0];
+        let maxes: Vec<_> = values.iter().map(|value| max.update(*value)).collect();
+        let mins: Vec<_> = values.iter().map(|value| min.update(*value)).collect();
+        assert_eq!(maxes, vec![Some(1.0), Some(3.0), Some(3.0), Some(3.0), Some(2.0)]);
+        assert_eq!(mins, vec![Some(1.0), Some(1.0), Some(1.0), Some(1.0), Some(0.0)]);
+    }
+
+    #[test]
+    fn bars_close_on_the_first_item_of_the_next_bar() {
+        let mut bars = BarAggregator::new(1000);
+        assert_eq!(bars.update(&item(100, OrderAction::Buy, 2, 100.0)), None);
+        assert_eq!(bars.update(&item(500, OrderAction::Sell, 5, 99.5)), None);
+        let closed = bars.update(&item(1200, OrderAction::Buy, 1, 101.0));
+        assert_eq!(
+            closed,
+            Some(Bar {
+                timestamp: 0,
+                open: 100.0,
+                high: 100.0,
+                low: 99.5,
+                close: 99.5,
+                volume: 7,
+                delta: -3,
+            })
+        );
+        assert_eq!(bars.current.map(|bar| bar.timestamp), Some(1000));
+    }
+
+    #[test]
+    fn vwap_bands_known_values() {
+        let mut vwap = Vwap::new();
+        assert_eq!(vwap.bands(2.0), None);
+        vwap.update(&item(0, OrderAction::Buy, 1, 100.0));
+        vwap.update(&item(1, OrderAction::Sell, 3, 102.0));
+        let bands = vwap.bands(2.0).unwrap();
+        let standard_deviation = 0.75f64.sqrt();
+        assert_close(Some(bands.vwap), 101.5);
+        assert_close(Some(bands.standard_deviation), standard_deviation);
+        assert_close(Some(bands.upper), 101.5 + 2.0 * standard_deviation);
+        assert_close(Some(bands.lower), 101.5 - 2.0 * standard_deviation);
+    }
+
+    #[test]
+    fn vwap_starts_over_at_the_session_open() {
+        let mut vwap = Vwap::new();
+        vwap.update(&item(0, OrderAction::Buy, 1, 100.0));
+        vwap.update(&item(1, OrderAction::Buy, 1, 102.0));
+        assert_close(vwap.value(), 101.0);
+        let next_session = |item: TimeAndSalesItem| TimeAndSalesItem {
+            trade_date: 20221018,
+            ..item
+        };
+        assert_close(vwap.update(&next_session(item(2, OrderAction::Sell, 2, 110.0))), 110.0);
+        assert_eq!(vwap.bands(2.0).map(|bands| bands.standard_deviation), Some(0.0));
+        // a late tick of the previous session
+        assert_close(vwap.update(&item(3, OrderAction::Buy, 5, 90.0)), 110.0);
+    }
+
+    #[test]
+    fn dom_imbalance_uses_the_top_levels() {
+        let dom = Dom {
+            bids: [10, 20, 30]
+                .iter()
+                .map(|size| Bid { price: 0.0, size: *size })
+                .collect(),
+            offers: [5, 5, 100]
+                .iter()
+                .map(|size| Offer { price: 0.0, size: *size })
+                .collect(),
+            contract_id: 1,
+            timestamp: String::new(),
+        };
+        assert_close(Some(dom_imbalance_ratio(&dom, 2)), 0.5);
+        assert_close(Some(dom_imbalance_ratio(&dom, 3)), -50.0 / 170.0);
+        assert_close(Some(dom_imbalance_ratio(&Dom::default(), 5)), 0.0);
+    }
+
+    #[test]
+    fn absorption_needs_volume_inside_a_tight_range() {
+        let mut absorption = AbsorptionDetector::new(10_000, 10, 1, 0.25);
+        assert_eq!(absorption.update(&item(0, OrderAction::Sell, 6, 100.0)), None);
+        let found = absorption.update(&item(1000, OrderAction::Buy, 6, 100.25));
+        assert_eq!(
+            found,
+            Some(Absorption {
+                price: 100.25,
+                volume: 12,
+                delta: 0,
+                timestamp: 1000,
+            })
+        );
+        assert_eq!(absorption.update(&item(2000, OrderAction::Buy, 6, 101.0)), None);
+        // the first two items have left the window
+        assert_eq!(absorption.update(&item(12_500, OrderAction::Buy, 6, 101.0)), None);
+        assert!(absorption.update(&item(13_000, OrderAction::Buy, 6, 101.0)).is_some());
+    }
+
+    #[test]
+    fn stacked_imbalances_are_diagonal() {
+        let mut detector = StackedImbalanceDetector::new(0.25, 3.0, 3);
+        for price in [100.25, 100.5, 100.75] {
+            detector.update(&item(0, OrderAction::Buy, 10, price));
+        }
+        for price in [100.0, 100.25, 100.5] {
+            detector.update(&item(0, OrderAction::Sell, 1, price));
+        }
+        assert_eq!(
+            detector.detect(),
+            vec![StackedImbalance {
+                action: OrderAction::Buy,
+                low_price: 100.25,
+                high_price: 100.75,
+                levels: 3,
+            }]
+        );
+        detector.reset();
+        assert!(detector.detect().is_empty());
+    }
+
+    #[test]
+    fn delta_divergence_known_values() {
+        let mut divergence = DeltaDivergence::new(2);
+        assert_eq!(divergence.update(&bar(10.0, 9.0, 10.0), 1), None);
+        assert_eq!(divergence.update(&bar(11.0, 10.0, 11.0), 2), None);
+        // a new high with cumulative delta below its high
+        assert_eq!(
+            divergence.update(&bar(12.0, 11.0, 12.0), 1),
+            Some(Divergence::Bearish)
+        );
+        assert_eq!(divergence.update(&bar(11.5, 8.0, 9.0), 3), Some(Divergence::Bullish));
+    }
+
+    #[test]
+    fn closed_bars_get_the_delta_at_their_close() {
+        let mut indicators = OrderFlowIndicators::new(0.25);
+        indicators.update(&item(0, OrderAction::Buy, 5, 100.0));
+        indicators.update(&item(INDICATOR_BAR_MILLIS, OrderAction::Sell, 100, 100.0));
+        assert_eq!(indicators.delta_divergence.delta_highs.value(), Some(5.0));
+        assert_eq!(indicators.cumulative_delta.value, -95);
+        assert_eq!(indicators.last_bar.map(|bar| bar.delta), Some(5));
+    }
+}
@@ -0,0 +1,320 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_client::TradovateAPIClient,
+    md_socket::APIClientRWL,
+    models::{
+        ContractGroup, ContractID, ContractMaturity, Currency, Exchange, ProductInfo, ProductMargin,
+    },
+    others::{create_json_file_atomically, open_json},
+    settings::{INSTRUMENT_CATALOG_FILENAME, INSTRUMENT_CATALOG_TTL_HOURS},
+};
+
+pub type InstrumentCatalogRWL = Arc<tokio::sync::RwLock<InstrumentCatalog>>;
+
+#[derive(Debug)]
+pub enum CatalogError {
+    Http(reqwest::Error),
+    Persist(std::io::Error),
+}
+impl std::fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CatalogError::Http(e) => write!(f, "catalog request failed: {}", e),
+            CatalogError::Persist(e) => write!(f, "could not save the catalog: {}", e),
+        }
+    }
+}
+impl From<reqwest::Error> for CatalogError {
+    fn from(e: reqwest::Error) -> Self {
+        CatalogError::Http(e)
+    }
+}
+
+// A contract with everything needed to price and describe it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub contract: ContractID,
+    pub product: ProductInfo,
+    pub maturity: Option<ContractMaturity>,
+    pub exchange: Option<Exchange>,
+    pub currency: Option<Currency>,
+    pub contract_group: Option<ContractGroup>,
+    pub margin: Option<ProductMargin>,
+}
+impl Instrument {
+    pub fn tick_size(&self) -> f64 {
+        if self.contract.provider_tick_size > 0.0 {
+            self.contract.provider_tick_size
+        } else {
+            self.product.tick_size
+        }
+    }
+    pub fn point_value(&self) -> f64 {
+        self.product.value_per_point
+    }
+    pub fn tick_value(&self) -> f64 {
+        self.tick_size() * self.point_value()
+    }
+    // Tradovate's price format, like ("Decimal", -2) for two decimals
+    pub fn price_format(&self) -> (&str, i64) {
+        (&self.product.price_format_type, self.product.price_format)
+    }
+    pub fn currency_name(&self) -> &str {
+        self.currency
+            .as_ref()
+            .map_or("", |currency| currency.name.as_str())
+    }
+    // per contract, None when the exchange margin could not be loaded
+    pub fn initial_margin(&self) -> Option<f64> {
+        self.margin
+            .as_ref()
+            .map(|margin| margin.initial_margin)
+            .filter(|margin| *margin > 0.0)
+    }
+    pub fn exchange_name(&self) -> &str {
+        self.exchange
+            .as_ref()
+            .map_or("", |exchange| exchange.name.as_str())
+    }
+}
+
+// Products, maturities and contracts of the product roots it was loaded for, plus the exchange,
+// currency and contract group lists. Cached in INSTRUMENT_CATALOG_FILENAME.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InstrumentCatalog {
+    pub fetched_at: i64,
+    pub product_names: Vec<String>,
+    pub products: HashMap<i64, ProductInfo>,
+    pub maturities: HashMap<i64, ContractMaturity>,
+    pub contracts: HashMap<i64, ContractID>,
+    pub exchanges: HashMap<i64, Exchange>,
+    pub currencies: HashMap<i64, Currency>,
+    pub contract_groups: HashMap<i64, ContractGroup>,
+    // by product id
+    #[serde(default)]
+    pub margins: HashMap<i64, ProductMargin>,
+}
+impl InstrumentCatalog {
+    // The cached catalog when it is fresh and has every product, otherwise a new one from the api
+    pub async fn load(
+        api_client: &TradovateAPIClient,
+        product_names: &[&str],
+    ) -> Result<Self, CatalogError> {
+        if let Some(cached) = Self::from_cache() {
+            if !cached.is_expired(chrono::Utc::now().timestamp_millis()) && cached.covers(product_names) {
+                return Ok(cached);
+            }
+        }
+        let catalog = Self::fetch(api_client, product_names).await?;
+        catalog.save().map_err(CatalogError::Persist)?;
+        Ok(catalog)
+    }
+    pub async fn fetch(
+        api_client: &TradovateAPIClient,
+        product_names: &[&str],
+    ) -> Result<Self, CatalogError> {
+        let mut catalog = Self {
+            fetched_at: chrono::Utc::now().timestamp_millis(),
+            product_names: product_names.iter().map(|name| name.to_string()).collect(),
+            ..Default::default()
+        };
+        for exchange in api_client.get_exchanges().await? {
+            catalog.exchanges.insert(exchange.id, exchange);
+        }
+        for currency in api_client.get_currencies().await? {
+            catalog.currencies.insert(currency.id, currency);
+        }
+        for contract_group in api_client.get_contract_groups().await? {
+            catalog.contract_groups.insert(contract_group.id, contract_group);
+        }
+        for name in product_names {
+            let product = api_client.get_product_info(name).await?;
+            for maturity in api_client.get_contract_maturities(product.id).await? {
+                if maturity.archived {
+                    continue;
+                }
+                for contract in api_client.get_maturity_contracts(maturity.id).await? {
+                    catalog.contracts.insert(contract.id, contract);
+                }
+                catalog.maturities.insert(maturity.id, maturity);
+            }
+            catalog
+                .margins
+                .insert(product.id, api_client.get_product_margin(product.id).await?);
+            catalog.products.insert(product.id, product);
+        }
+        Ok(catalog)
+    }
+    pub fn from_cache() -> Option<Self> {
+        open_json(INSTRUMENT_CATALOG_FILENAME)
+            .ok()
+            .and_then(|file| serde_json::from_value(file).ok())
+    }
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        create_json_file_atomically(INSTRUMENT_CATALOG_FILENAME, self)
+    }
+    pub fn is_expired(&self, now_millis: i64) -> bool {
+        now_millis - self.fetched_at > INSTRUMENT_CATALOG_TTL_HOURS * 60 * 60 * 1000
+    }
+    // Caches saved before margins were loaded are fetched again
+    pub fn covers(&self, product_names: &[&str]) -> bool {
+        product_names
+            .iter()
+            .all(|name| self.product_names.iter().any(|loaded| loaded == name))
+            && self
+                .products
+                .keys()
+                .all(|product_id| self.margins.contains_key(product_id))
+    }
+    pub fn product(&self, name: &str) -> Option<&ProductInfo> {
+        self.products.values().find(|product| product.symbol == name)
+    }
+    pub fn product_maturities(&self, product_id: i64) -> Vec<&ContractMaturity> {
+        let mut maturities: Vec<&ContractMaturity> = self
+            .maturities
+            .values()
+            .filter(|maturity| maturity.product_id == product_id)
+            .collect();
+        maturities.sort_by_key(|maturity| maturity.expiration_month);
+        maturities
+    }
+    pub fn maturity_contracts(&self, maturity_id: i64) -> Vec<&ContractID> {
+        let mut contracts: Vec<&ContractID> = self
+            .contracts
+            .values()
+            .filter(|contract| contract.contract_maturity_id == maturity_id)
+            .collect();
+        contracts.sort_by_key(|contract| contract.id);
+        contracts
+    }
+    pub fn contract(&self, contract_id: i64) -> Option<&ContractID> {
+        self.contracts.get(&contract_id)
+    }
+    pub fn contract_by_symbol(&self, symbol: &str) -> Option<&ContractID> {
+        self.contracts
+            .values()
+            .find(|contract| contract.symbol == symbol)
+    }
+    // contract -> maturity -> product
+    pub fn product_of(&self, contract_id: i64) -> Option<&ProductInfo> {
+        let maturity = self
+            .maturities
+            .get(&self.contract(contract_id)?.contract_maturity_id)?;
+        self.products.get(&maturity.product_id)
+    }
+    pub fn product_contract_ids(&self, name: &str) -> Vec<i64> {
+        let mut contract_ids: Vec<i64> = self
+            .contracts
+            .keys()
+            .filter(|contract_id| {
+                self.product_of(**contract_id)
+                    .is_some_and(|product| product.symbol == name)
+            })
+            .copied()
+            .collect();
+        contract_ids.sort();
+        contract_ids
+    }
+    pub fn instrument(&self, contract_id: i64) -> Option<Instrument> {
+        let contract = self.contract(contract_id)?;
+        let maturity = self.maturities.get(&contract.contract_maturity_id);
+        let product = self.products.get(&maturity?.product_id)?;
+        let mut contract = contract.clone();
+        contract.big_point_value = product.value_per_point;
+        Some(Instrument {
+            contract,
+            product: product.clone(),
+            maturity: maturity.cloned(),
+            exchange: self.exchanges.get(&product.exchange_id).cloned(),
+            currency: self.currencies.get(&product.currency_id).cloned(),
+            contract_group: self.contract_groups.get(&product.contract_group_id).cloned(),
+            margin: self.margins.get(&product.id).cloned(),
+        })
+    }
+    pub fn instrument_by_symbol(&self, symbol: &str) -> Option<Instrument> {
+        self.instrument(self.contract_by_symbol(symbol)?.id)
+    }
+}
+
+// Fetches the catalog again once it is older than INSTRUMENT_CATALOG_TTL_HOURS
+pub async fn refresh_if_expired(
+    catalog_rwl: &InstrumentCatalogRWL,
+    api_client_rwl: &APIClientRWL,
+) -> Result<(), CatalogError> {
+    let catalog = catalog_rwl.read().await;
+    if !catalog.is_expired(chrono::Utc::now().timestamp_millis()) {
+        return Ok(());
+    }
+    let product_names = catalog.product_names.clone();
+    drop(catalog);
+    let product_names: Vec<&str> = product_names.iter().map(|name| name.as_str()).collect();
+    let api_client = api_client_rwl.read().await.clone();
+    let fresh = InstrumentCatalog::fetch(&api_client, &product_names).await?;
+    fresh.save().map_err(CatalogError::Persist)?;
+    *catalog_rwl.write().await = fresh;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> InstrumentCatalog {
+        let mut catalog = InstrumentCatalog::default();
+        for (product_id, name) in [(1, "ES"), (2, "MES")] {
+            catalog.products.insert(
+                product_id,
+                ProductInfo {
+                    id: product_id,
+                    symbol: name.to_string(),
+                    ..ProductInfo::default()
+                },
+            );
+        }
+        for (maturity_id, product_id) in [(10, 1), (11, 1), (20, 2)] {
+            catalog.maturities.insert(
+                maturity_id,
+                ContractMaturity {
+                    id: maturity_id,
+                    product_id,
+                    ..ContractMaturity::default()
+                },
+            );
+        }
+        for (contract_id, maturity_id, symbol) in
+            [(100, 10, "ESZ2"), (101, 11, "ESH3"), (200, 20, "MESZ2"), (300, 30, "NQZ2")]
+        {
+            catalog.contracts.insert(
+                contract_id,
+                ContractID {
+                    id: contract_id,
+                    contract_maturity_id: maturity_id,
+                    symbol: symbol.to_string(),
+                    ..ContractID::default()
+                },
+            );
+        }
+        catalog
+    }
+
+    #[test]
+    fn contracts_belong_to_the_product_of_their_maturity() {
+        let catalog = catalog();
+        assert_eq!(catalog.product_of(100).map(|product| product.id), Some(1));
+        assert_eq!(catalog.product_of(200).map(|product| product.id), Some(2));
+        // its maturity is not in the catalog
+        assert_eq!(catalog.product_of(300), None);
+        assert_eq!(catalog.product_of(400), None);
+    }
+
+    #[test]
+    fn product_contracts_span_every_maturity() {
+        let catalog = catalog();
+        assert_eq!(catalog.product_contract_ids("ES"), vec![100, 101]);
+        assert_eq!(catalog.product_contract_ids("MES"), vec![200]);
+        assert!(catalog.product_contract_ids("NQ").is_empty());
+    }
+}
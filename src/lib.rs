@@ -0,0 +1,31 @@
+pub mod account_risk;
+pub mod accounting;
+pub mod acct_socket;
+pub mod api_client;
+pub mod bracket;
+pub mod calendar;
+pub mod clock;
+pub mod contract_resolver;
+pub mod credentials;
+pub mod environment;
+pub mod indicators;
+pub mod instrument_catalog;
+pub mod md_socket;
+pub mod models;
+pub mod order_journal;
+pub mod others;
+pub mod position_sizing;
+pub mod price;
+pub mod replay;
+pub mod reporting;
+pub mod session_boundary;
+pub mod settings;
+pub mod socket_processing;
+pub mod state_snapshot;
+pub mod strategy;
+pub mod supervisor;
+pub mod time_and_sales;
+pub mod token_manager;
+pub mod trade_classifier;
+pub mod trade_copier;
+pub mod watchdog;
@@ -1,74 +1,201 @@
-mod time_and_sales;
-use crate::{
-    acct_socket::{incoming_acct_socket, outgoing_acct_socket},
-    api_client::{Protocol, ResourceType, TradovateAPIClient, Server},
+use tradovate_api::{
+    account_risk::{watch_accounts, AccountRiskMonitor, AccountRiskMonitorRWL},
+    accounting::Ledger,
+    api_client::{Endpoint, Protocol, ResourceType, Server, TradovateAPIClient},
+    calendar::TradingCalendar,
+    clock::{system_clock, ClockRef, ReplayServerClock},
+    contract_resolver::{watch_rollovers, ContractResolver, ContractResolverRWL},
+    environment,
+    instrument_catalog::{InstrumentCatalog, InstrumentCatalogRWL},
     md_socket::{
-        process_market_data_messages, process_outgoing_md_messages, MarketDataIncoming,
-        MarketDataRequest,
+        self, APIClientRWL, ChartTimeRange, MarketData, MarketDataIncoming, MarketDataIncomingRWL,
+        MarketDataOutGoingRWL, MarketDataRWL, MarketDataRequest,
     },
-    models::AcctCommsChannel,
-    others::get_duration_until_open,
-    reporting::program_log,
-    settings::{REPORT_TO_EMAIL, SOCKET_INTERVAL_SPEED, TIMEZONE, TRADING_SYMBOL},
-    strategy::calculate,
+    models::{AcctCommChannelRWL, AcctCommsChannel},
+    order_journal::{keep_journal_saved, OrderJournal},
+    replay::{ReplaySession, ReplaySettings},
+    reporting::{self, program_log, ReportRWL},
+    session_boundary::watch_session_boundaries,
+    state_snapshot::{keep_state_saved, restore_state},
+    settings::{
+        BACKFILL_MINUTES, CONNECT_BEFORE_OPEN_SECONDS, MARKET_DATA_PRODUCTS, REPORT_TO_EMAIL,
+        SOCKET_INTERVAL_SPEED, TRADING_PRODUCT,
+    },
+    socket_processing,
+    strategy::{self, calculate, StrategyRWL},
+    supervisor::{supervise_account, supervise_market_data, Backoff, ConnectionMonitor, ConnectionMonitorRef},
+    token_manager::keep_token_fresh,
+    trade_copier::{watch_copier, TradeCopier, TradeCopierRWL},
+    watchdog::{FeedWatchdog, FeedWatchdogRWL},
 };
 use core::time;
 use futures_util::StreamExt;
-use md_socket::{APIClientRWL, MarketDataIncomingRWL, MarketDataOutGoingRWL, MarketDataRWL};
-use models::AcctCommChannelRWL;
-use reporting::ReportRWL;
 use std::sync::Arc;
-use strategy::StrategyRWL;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio_stream::wrappers::{IntervalStream, SignalStream};
-use tokio_tungstenite::connect_async;
-mod acct_socket;
-mod api_client;
-mod credentials;
-mod md_socket;
-mod models;
-mod others;
-mod reporting;
-mod settings;
-mod socket_processing;
-mod strategy;
-use crate::md_socket::MarketData;
 #[tokio::main]
 async fn main() {
     use MarketData::*;
     let strategy_rwl = Arc::new(tokio::sync::RwLock::new(strategy::Strategy::new()));
     let report_rwl = Arc::new(tokio::sync::RwLock::new(String::new()));
-    let mut fast_attempts = 0;
+    let md_monitor = Arc::new(ConnectionMonitor::new());
+    let account_monitor = Arc::new(ConnectionMonitor::new());
+    let watchdog_rwl = Arc::new(tokio::sync::RwLock::new(FeedWatchdog::new()));
+    let account_risk_rwl = Arc::new(tokio::sync::RwLock::new(AccountRiskMonitor::new()));
+    let copier_rwl = Arc::new(tokio::sync::RwLock::new(TradeCopier::from_settings()));
+    let mut auth_backoff = Backoff::default();
+    let server = match environment::select_server() {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("{}", e);
+            program_log(&e, ResourceType::Any, report_rwl.clone()).await;
+            reporting::print_contents(report_rwl.clone());
+            return;
+        }
+    };
+    program_log(
+        &format!("Using the {} server", server.name()),
+        ResourceType::Any,
+        report_rwl.clone(),
+    )
+    .await;
+    let replay_settings = if server == Server::Replay {
+        match ReplaySettings::from_args() {
+            Ok(replay_settings) => Some(replay_settings),
+            Err(e) => {
+                eprintln!("{}", e);
+                program_log(&e, ResourceType::Any, report_rwl.clone()).await;
+                reporting::print_contents(report_rwl.clone());
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let clock: ClockRef = match &replay_settings {
+        Some(replay_settings) => Arc::new(ReplayServerClock::new(
+            replay_settings.start_timestamp,
+            replay_settings.speed,
+        )),
+        None => system_clock(),
+    };
+    let calendar = match TradingCalendar::load(clock.now()) {
+        Ok(calendar) => Arc::new(calendar),
+        Err(e) => {
+            eprintln!("{}", e);
+            program_log(&e, ResourceType::Any, report_rwl.clone()).await;
+            reporting::print_contents(report_rwl.clone());
+            return;
+        }
+    };
+    let (mut saved_journal, restore_log) = restore_state(&strategy_rwl, &calendar, &clock).await;
+    program_log(&restore_log, ResourceType::Trading, report_rwl.clone()).await;
     loop {
-        if let Ok(client) = TradovateAPIClient::new(Server::Demo, "MyApp", "0.2").get_auth().await {
-            let md_url = client.url(ResourceType::MarketData, Protocol::Wss);
-            let account_url = client.url(ResourceType::Trading, Protocol::Wss);
+        if let Ok(client) = TradovateAPIClient::new(server, "MyApp", "0.2").get_auth().await {
+            auth_backoff.reset();
+            let md_url = client.url(Endpoint::MarketData, Protocol::Wss);
+            let account_url = client.url(Endpoint::Trading, Protocol::Wss);
             let api_client_rwl = Arc::new(tokio::sync::RwLock::new(client.clone()));
+            let catalog = match InstrumentCatalog::load(&client, &MARKET_DATA_PRODUCTS).await {
+                Ok(catalog) => catalog,
+                Err(e) => {
+                    program_log(
+                        &format!("Could not load the instrument catalog: {}", e),
+                        ResourceType::Any,
+                        report_rwl.clone(),
+                    )
+                    .await;
+                    tokio::time::sleep(auth_backoff.next_delay()).await;
+                    continue;
+                }
+            };
+            let resolver =
+                match ContractResolver::resolve_all(&catalog, &MARKET_DATA_PRODUCTS, clock.now()) {
+                    Ok(resolver) => resolver,
+                    Err(e) => {
+                        program_log(
+                            &format!("Could not resolve contracts: {}", e),
+                            ResourceType::Any,
+                            report_rwl.clone(),
+                        )
+                        .await;
+                        tokio::time::sleep(auth_backoff.next_delay()).await;
+                        continue;
+                    }
+                };
+            let catalog_rwl = Arc::new(tokio::sync::RwLock::new(catalog));
+            let symbols: Vec<String> = MARKET_DATA_PRODUCTS
+                .iter()
+                .filter_map(|product| resolver.front_symbol(product))
+                .collect();
+            program_log(
+                &format!("Front month contracts {:?}", symbols),
+                ResourceType::Any,
+                report_rwl.clone(),
+            )
+            .await;
+            strategy_rwl.write().await.symbol =
+                resolver.front_symbol(TRADING_PRODUCT).unwrap_or_default();
             let market_data = Arc::new(tokio::sync::RwLock::new(
-                socket_processing::MarketData::new(vec![
-                    client.get_contract_info("ESM2").await.unwrap(),
-                    client.get_contract_info("NQM2").await.unwrap(),
-                ]),
-            ));
-            let md_requests = vec![
-                MarketDataRequest::new(Chart, "ESM2"),
-                MarketDataRequest::new(Chart, "NQM2"),
-                MarketDataRequest::new(DepthOfMarket, "ESM2"),
-                MarketDataRequest::new(DepthOfMarket, "NQM2"),
-            ];
-            let acct_requests = vec![
-                client.ws_auth_msg(ResourceType::Trading).await,
-                client.get_user_sync_request(),
-            ];
-            let market_data_outgoing_rwl = Arc::new(tokio::sync::RwLock::new(
-                md_socket::MarketDataOutgoing::new(md_requests),
+                socket_processing::MarketData::new(
+                    resolver.front_contracts(),
+                    clock.clone(),
+                    calendar.clone(),
+                ),
             ));
+            let resolver_rwl = Arc::new(tokio::sync::RwLock::new(resolver));
+            let md_requests = [Chart, DepthOfMarket]
+                .iter()
+                .flat_map(|data_type| {
+                    symbols
+                        .iter()
+                        .map(|symbol| MarketDataRequest::new(*data_type, symbol))
+                })
+                .collect();
+            let mut market_data_outgoing = md_socket::MarketDataOutgoing {
+                clock: clock.clone(),
+                ..md_socket::MarketDataOutgoing::new(md_requests)
+            };
+            let chart_symbols: Vec<String> = market_data_outgoing
+                .data_requests
+                .iter()
+                .filter(|request| request.data_type == Chart)
+                .map(|request| request.symbol.clone())
+                .collect();
+            // a replay has no history before its start time to backfill from
+            if let Some(replay_settings) = &replay_settings {
+                market_data_outgoing.replay = Some(ReplaySession::new(replay_settings.clone()));
+            } else {
+                for symbol in chart_symbols {
+                    market_data_outgoing.request_backfill(
+                        &symbol,
+                        ChartTimeRange::last_minutes(BACKFILL_MINUTES, clock.now()),
+                    );
+                }
+            }
+            let market_data_outgoing_rwl =
+                Arc::new(tokio::sync::RwLock::new(market_data_outgoing));
             let market_data_incoming_rwl =
                 Arc::new(tokio::sync::RwLock::new(MarketDataIncoming::new()));
-            let acct_comms_channel = Arc::new(tokio::sync::RwLock::new(AcctCommsChannel::new(
-                acct_requests,
-            )));
-            let time_to_wait = get_duration_until_open();
+            // the supervisor queues auth and user/syncrequest at the start of every session
+            let acct_comms_channel = Arc::new(tokio::sync::RwLock::new(AcctCommsChannel {
+                ledger: Ledger::from_catalog(&*catalog_rwl.read().await),
+                journal: OrderJournal::load_or(
+                    std::mem::take(&mut saved_journal),
+                    clock.now_millis(),
+                ),
+                replay: replay_settings.clone().map(ReplaySession::new),
+                clock: clock.clone(),
+                ..AcctCommsChannel::new(Vec::new())
+            }));
+            let time_to_wait = if replay_settings.is_some() {
+                time::Duration::ZERO
+            } else {
+                calendar
+                    .time_to_open(TRADING_PRODUCT, clock.now())
+                    .unwrap_or_default()
+                    .saturating_sub(time::Duration::from_secs(CONNECT_BEFORE_OPEN_SECONDS))
+            };
             if time_to_wait != time::Duration::ZERO {
                 program_log(
                     &format!(
@@ -88,7 +215,6 @@ async fn main() {
                 .await
             };
             tokio::time::sleep(time_to_wait).await;
-            let start = tokio::time::Instant::now();
             if connect_and_trade(
                 &md_url,
                 &account_url,
@@ -98,40 +224,37 @@ async fn main() {
                 market_data.clone(),
                 acct_comms_channel.clone(),
                 strategy_rwl.clone(),
+                resolver_rwl.clone(),
+                catalog_rwl.clone(),
+                watchdog_rwl.clone(),
+                account_risk_rwl.clone(),
+                copier_rwl.clone(),
+                md_monitor.clone(),
+                account_monitor.clone(),
+                clock.clone(),
                 report_rwl.clone(),
             )
             .await
             {
                 break;
             }
-            if start.elapsed().as_secs() < 60 {
-                fast_attempts += 1
-            } else {
-                fast_attempts = 0
-            }
-            if fast_attempts >= 3 {
-                reporting::send_txt_message("phone-number", "Max connections have been attempted")
-                    .await
-                    .unwrap();
-                program_log(
-                    "Max connections have been attempted",
-                    ResourceType::Any,
-                    report_rwl.clone(),
-                )
-                .await;
-                break;
-            }
             program_log(
                 "Program finished and restarting",
                 ResourceType::MarketData,
                 report_rwl.clone(),
             )
             .await;
-        } else {program_log("Could not obtain auth", ResourceType::Any, report_rwl.clone()).await}
+        } else {
+            program_log("Could not obtain auth", ResourceType::Any, report_rwl.clone()).await;
+            tokio::time::sleep(auth_backoff.next_delay()).await;
+        }
     }
     program_log("User Aborted", ResourceType::MarketData, report_rwl.clone()).await;
 }
 
+// Each socket is kept alive by its own supervisor, so a drop on one side reconnects only that
+// socket. Returns true once both sockets have been terminated by the user.
+#[allow(clippy::too_many_arguments)]
 pub async fn connect_and_trade(
     md_url: &str,
     account_url: &str,
@@ -141,6 +264,14 @@ pub async fn connect_and_trade(
     market_data: MarketDataRWL,
     acct_comms_channel: AcctCommChannelRWL,
     strategy_rwl: StrategyRWL,
+    resolver_rwl: ContractResolverRWL,
+    catalog_rwl: InstrumentCatalogRWL,
+    watchdog_rwl: FeedWatchdogRWL,
+    account_risk_rwl: AccountRiskMonitorRWL,
+    copier_rwl: TradeCopierRWL,
+    md_monitor: ConnectionMonitorRef,
+    account_monitor: ConnectionMonitorRef,
+    clock: ClockRef,
     report_rwl: ReportRWL,
 ) -> bool {
     program_log(
@@ -149,203 +280,168 @@ pub async fn connect_and_trade(
         report_rwl.clone(),
     )
     .await;
-    if let Ok((stream, response)) = connect_async(md_url).await {
-        if let Ok((trading_stream, trading_response)) = connect_async(account_url).await {
-            program_log(
-                &format!("Websocket connection status: {:#?}", response.status()),
-                ResourceType::MarketData,
-                report_rwl.clone(),
-            )
-            .await;
+    let md_supervisor = supervise_market_data(
+        md_url,
+        market_data_incoming_rwl.clone(),
+        market_data_outgoing_rwl.clone(),
+        market_data.clone(),
+        api_client_rwl.clone(),
+        watchdog_rwl.clone(),
+        md_monitor.clone(),
+        report_rwl.clone(),
+    );
+    let account_supervisor = supervise_account(
+        account_url,
+        acct_comms_channel.clone(),
+        api_client_rwl.clone(),
+        watchdog_rwl.clone(),
+        account_monitor.clone(),
+        report_rwl.clone(),
+    );
+    let supervisors = futures_util::future::join(md_supervisor, account_supervisor);
+    let interval =
+        tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
+    let strategy_calculations = IntervalStream::new(interval).for_each(|_| async {
+        calculate(
+            market_data.clone(),
+            strategy_rwl.clone(),
+            acct_comms_channel.clone(),
+            catalog_rwl.clone(),
+            watchdog_rwl.clone(),
+            report_rwl.clone(),
+        )
+        .await
+    });
+    let start = tokio::time::Instant::now() + time::Duration::from_secs(60 * 30);
+    let interval_at = tokio::time::interval_at(start, time::Duration::from_secs(60 * 120));
+    let periodic_reporting = IntervalStream::new(interval_at).for_each(|_| async {
+        if let Ok(strategy) = strategy_rwl.try_read() {
+            if let Ok(market_data) = market_data.try_read() {
+                let accounts = account_risk_rwl.read().await.snapshots();
+                let body = &format!(
+                    "{:#?}\n{:#?}\n{}",
+                    strategy,
+                    accounts,
+                    market_data.summarize_ts()
+                );
+                reporting::send_email(REPORT_TO_EMAIL, body, "Periodic Report").await;
+            }
+        }
+    });
+    let start = tokio::time::Instant::now() + time::Duration::from_secs(60);
+    let interval_at = tokio::time::interval_at(start, time::Duration::from_secs(60));
+    let log_file_print = IntervalStream::new(interval_at).for_each(|_| async {
+        reporting::print_contents(report_rwl.clone())
+    });
+    let access_token_renewal_future = keep_token_fresh(api_client_rwl.clone(), report_rwl.clone());
+    let session_boundaries = watch_session_boundaries(
+        market_data.clone(),
+        strategy_rwl.clone(),
+        acct_comms_channel.clone(),
+        catalog_rwl.clone(),
+        report_rwl.clone(),
+    );
+    let rollovers = watch_rollovers(
+        resolver_rwl.clone(),
+        catalog_rwl.clone(),
+        api_client_rwl.clone(),
+        market_data.clone(),
+        market_data_outgoing_rwl.clone(),
+        strategy_rwl.clone(),
+        acct_comms_channel.clone(),
+        watchdog_rwl.clone(),
+        report_rwl.clone(),
+    );
+    let accounts = watch_accounts(
+        account_risk_rwl.clone(),
+        market_data.clone(),
+        acct_comms_channel.clone(),
+        report_rwl.clone(),
+    );
+    let copier = watch_copier(
+        copier_rwl.clone(),
+        acct_comms_channel.clone(),
+        report_rwl.clone(),
+    );
+    let journal_saving = keep_journal_saved(acct_comms_channel.clone(), report_rwl.clone());
+    let state_saving = keep_state_saved(
+        strategy_rwl.clone(),
+        acct_comms_channel.clone(),
+        clock.clone(),
+        report_rwl.clone(),
+    );
+    let program_close_future = SignalStream::new(signal(SignalKind::interrupt()).unwrap())
+        .for_each(|_| async {
+            program_log("Received Ctrl+C", ResourceType::Any, report_rwl.clone()).await;
+            let mut open = market_data_outgoing_rwl.write().await;
+            open.socket_close = true;
+            open.terminated = true;
+            let mut other_open = acct_comms_channel.write().await;
+            other_open.socket_close = true;
+            other_open.terminated = true;
+            let strat = strategy_rwl.read().await;
             program_log(
-                &format!(
-                    "Websocket connection status: {:#?}",
-                    trading_response.status()
-                ),
+                &format!("{:#?}", strat),
                 ResourceType::MarketData,
                 report_rwl.clone(),
             )
             .await;
-            let (account_write, account_read) = trading_stream.split();
-            let (md_write, md_read) = stream.split();
-            let md_read_future = md_read.for_each(|msg| async {
-                if process_market_data_messages(
-                    market_data_incoming_rwl.clone(),
-                    market_data_outgoing_rwl.clone(),
-                    market_data.clone(),
-                    msg,
-                    ResourceType::MarketData,
-                    report_rwl.clone(),
-                )
-                .await
+            drop(other_open);
+            drop(open);
+            if let Ok(market_data_open) = market_data.try_read() {
+                if let Some(contract) = market_data_open
+                    .contract_ids
+                    .iter()
+                    .find(|contract| contract.symbol == strat.symbol)
                 {
-                    return;
-                }
-            });
-            let account_read_future = account_read.for_each(|msg| async {
-                if incoming_acct_socket(
-                    acct_comms_channel.clone(),
-                    ResourceType::Trading,
-                    msg,    
-                    report_rwl.clone(),
-                )
-                .await
-                {
-                    return;
-                }
-            });
-            let interval =
-                tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
-            let account_write_future =
-                IntervalStream::new(interval).fold(account_write, |pmd_write, _| async {
-                    outgoing_acct_socket(
-                        acct_comms_channel.clone(),
-                        pmd_write,
-                        ResourceType::Trading,
-                        report_rwl.clone(),
-                    )
-                    .await
-                });
-            let interval =
-                tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
-            let md_write_future =
-                IntervalStream::new(interval).fold(md_write, |pmd_write, _| async {
-                    process_outgoing_md_messages(
-                        market_data_incoming_rwl.clone(),
-                        market_data_outgoing_rwl.clone(),
-                        api_client_rwl.clone(),
-                        pmd_write,
-                        ResourceType::MarketData,
-                        report_rwl.clone(),
-                    )
-                    .await
-                });
-            let interval =
-                tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
-            let strategy_calculations = IntervalStream::new(interval).for_each(|_| async {
-                calculate(
-                    market_data.clone(),
-                    strategy_rwl.clone(),
-                    acct_comms_channel.clone(),
-                    report_rwl.clone(),
-                )
-                .await
-            });
-            let start = tokio::time::Instant::now() + time::Duration::from_secs(60 * 30);
-            let interval_at = tokio::time::interval_at(start, time::Duration::from_secs(60 * 120));
-            let periodic_reporting = IntervalStream::new(interval_at).for_each(|_| async {
-                if let Ok(strategy) = strategy_rwl.try_read() {
-                    if let Ok(market_data) = market_data.try_read() {
-                        let body = &format!("{:#?}\n{}", strategy, market_data.summarize_ts());
-                        reporting::send_email(REPORT_TO_EMAIL, body, "Periodic Report").await;
-                    }
-                }
-            });
-            let start = tokio::time::Instant::now() + time::Duration::from_secs(60);
-            let interval_at = tokio::time::interval_at(start, time::Duration::from_secs(60));
-            let log_file_print = IntervalStream::new(interval_at).for_each(|_| async {
-                reporting::print_contents(report_rwl.clone())
-            });
-            let interval = tokio::time::interval(time::Duration::from_secs(60));
-            let access_token_renewal_future = IntervalStream::new(interval).for_each(|_| async {
-                let now = chrono::Utc::now().timestamp_millis();
-                if let Ok(api_client) = api_client_rwl.try_read() {
-                    if now > (api_client.token_expiration_time - 240000) {
-                        if let Ok(response) = api_client.renew_access_token().await {
-                            drop(api_client);
-                            let new_expiration_time = chrono::DateTime::parse_from_rfc3339(
-                                response["expirationTime"].as_str().unwrap(),
+                    if let Some(quote) =
+                        market_data_open.time_and_sales.get_quote(contract.id)
+                    {
+                        let account_data = acct_comms_channel.read().await;
+                        program_log(
+                            &strat.summary(&account_data.ledger, &quote),
+                            ResourceType::MarketData,
+                            report_rwl.clone(),
+                        )
+                        .await;
+                        for account_id in account_data.ledger.account_ids() {
+                            let pnl = account_data.ledger.account_pnl(account_id, |contract_id| {
+                                market_data_open.time_and_sales.get_quote(contract_id)
+                            });
+                            program_log(
+                                &format!("Account {} {:#?}", account_id, pnl),
+                                ResourceType::Trading,
+                                report_rwl.clone(),
                             )
-                            .unwrap();
-                            let pst_expiry = new_expiration_time
-                                .with_timezone(&TIMEZONE)
-                                .format("%v %T")
-                                .to_string();
-                            api_client_rwl.write().await.token_expiration_time =
-                                new_expiration_time.timestamp_millis();
-                            others::delete_file(settings::AUTH_FILENAME);
-                            let report =
-                                format!("Renewed Access token. Now expiriring at {}", pst_expiry);
-                            program_log(&report, ResourceType::Any, report_rwl.clone()).await;
-                            reporting::send_txt_message("yourphonenumber", &report)
-                                .await
-                                .unwrap();
+                            .await;
                         }
+                        drop(account_data);
+                        program_log(
+                            &market_data_open.summarize_ts(),
+                            ResourceType::MarketData,
+                            report_rwl.clone(),
+                        )
+                        .await;
                     }
                 }
-            });
-            let program_close_future = SignalStream::new(signal(SignalKind::interrupt()).unwrap())
-                .for_each(|_| async {
-                    program_log("Received Ctrl+C", ResourceType::Any, report_rwl.clone()).await;
-                    let mut open = market_data_outgoing_rwl.write().await;
-                    open.socket_close = true;
-                    open.terminated = true;
-                    let mut other_open = acct_comms_channel.write().await;
-                    other_open.socket_close = true;
-                    other_open.terminated = true;
-                    let strat = strategy_rwl.read().await;
-                    program_log(
-                        &format!("{:#?}", strat),
-                        ResourceType::MarketData,
-                        report_rwl.clone(),
-                    )
-                    .await;
-                    drop(other_open);
-                    drop(open);
-                    if let Ok(market_data_open) = market_data.try_read() {
-                        if let Some(contract) = market_data_open
-                            .contract_ids
-                            .iter()
-                            .find(|contract| contract.symbol == TRADING_SYMBOL)
-                        {
-                            if let Some(quote) =
-                                market_data_open.time_and_sales.get_quote(contract.id)
-                            {
-                                program_log(
-                                    &strat.summary(quote, contract.big_point_value),
-                                    ResourceType::MarketData,
-                                    report_rwl.clone(),
-                                )
-                                .await;
-                                program_log(
-                                    &market_data_open.summarize_ts(),
-                                    ResourceType::MarketData,
-                                    report_rwl.clone(),
-                                )
-                                .await;
-                            }
-                        }
-                    }
-                    drop(strat);
-                    reporting::print_contents(report_rwl.clone());
-                    tokio::time::sleep(time::Duration::from_millis(2000)).await;
-                });
-            tokio::select! {
-                () = md_read_future => false,
-                () = account_read_future => false,
-                _ = md_write_future => true,
-                _ = account_write_future => true,
-                _ = program_close_future => true,
-                _ = periodic_reporting => false,
-                _ = access_token_renewal_future => false,
-                _ = log_file_print => false,
-                () = strategy_calculations => false,
-            };
-        } else {
-            program_log(
-                "Could not connect to account socket",
-                ResourceType::Trading,
-                report_rwl,
-            )
-            .await
-        };
-    } else {
-        program_log(
-            "Could not connect to market data socket",
-            ResourceType::MarketData,
-            report_rwl,
-        )
-        .await
+            }
+            drop(strat);
+            reporting::print_contents(report_rwl.clone());
+            tokio::time::sleep(time::Duration::from_millis(2000)).await;
+        });
+    tokio::select! {
+        _ = supervisors => {},
+        _ = program_close_future => {},
+        _ = periodic_reporting => {},
+        () = access_token_renewal_future => {},
+        _ = log_file_print => {},
+        () = strategy_calculations => {},
+        () = session_boundaries => {},
+        () = rollovers => {},
+        () = accounts => {},
+        () = copier => {},
+        () = state_saving => {},
+        () = journal_saving => {},
     };
     market_data_outgoing_rwl.read().await.terminated
 }
@@ -2,14 +2,21 @@
 use std::sync::Arc;
 
 use crate::{
-    api_client::{ResourceType, TradovateAPIClient},
+    api_client::{Endpoint, ResourceType, TradovateAPIClient},
+    clock::{system_clock, ClockRef},
     models::WebsocketResponse,
+    replay::ReplaySession,
+    time_and_sales::TimeAndSalesItem,
     reporting::{send_email, program_log, ReportRWL},
-    settings::REPORT_TO_EMAIL,
+    settings::{
+        BACKFILL_MAX_ATTEMPTS, BACKFILL_MINUTES, BACKFILL_PAGE_SIZE, REPORT_TO_EMAIL,
+        SUBSCRIPTION_ACK_TIMEOUT_MILLIS,
+    },
     socket_processing::{self, process_message},
+    watchdog::FeedWatchdogRWL,
 };
 
-use chrono::Duration;
+use chrono::{Duration, TimeZone};
 use futures_util::{stream::SplitSink, SinkExt};
 use serde_json::{json, Value};
 use tokio::net::TcpStream;
@@ -22,6 +29,8 @@ pub type MarketDataIncomingRWL = Arc<tokio::sync::RwLock<MarketDataIncoming>>;
 pub type MarketDataOutGoingRWL = Arc<tokio::sync::RwLock<MarketDataOutgoing>>;
 pub type MarketDataRWL = Arc<tokio::sync::RwLock<socket_processing::MarketData>>;
 pub type APIClientRWL = Arc<tokio::sync::RwLock<TradovateAPIClient>>;
+// Backfill pages are sent with their own request ids so responses never index into data_requests
+pub const BACKFILL_REQUEST_ID_OFFSET: i32 = 1000;
 #[derive(Debug, Clone)]
 pub struct MarketDataOutgoing {
     pub outgoing_messages: Vec<String>,
@@ -31,6 +40,9 @@ pub struct MarketDataOutgoing {
     pub data_requests: Vec<MarketDataRequest>,
     pub sent_requests: Vec<String>,
     pub received_closing_frame:bool,
+    pub backfill_requests: Vec<BackfillRequest>,
+    pub replay: Option<ReplaySession>,
+    pub clock: ClockRef,
 }
 impl MarketDataOutgoing {
     pub fn new(data_requests: Vec<MarketDataRequest>) -> Self {
@@ -42,6 +54,189 @@ impl MarketDataOutgoing {
             data_requests,
             sent_requests: Vec::new(),
             received_closing_frame: false,
+            backfill_requests: Vec::new(),
+            replay: None,
+            clock: system_clock(),
+        }
+    }
+    // Forget everything sent on the previous connection so that auth, subscriptions and any
+    // unfinished backfill page are sent again on the new one
+    pub fn reset_session(&mut self) {
+        self.outgoing_messages.clear();
+        self.sent_requests.clear();
+        self.received_closing_frame = false;
+        self.last_heart_beat_instant = tokio::time::Instant::now();
+        for request in self.data_requests.iter_mut() {
+            request.reset_session();
+        }
+        for backfill in self.backfill_requests.iter_mut().filter(|backfill| backfill.is_sent()) {
+            backfill.status = RequestStatus::Unsent;
+        }
+        if let Some(replay) = &mut self.replay {
+            replay.reset_session();
+        }
+    }
+    // Subscriptions on a replay socket are only valid once the clock has been initialized
+    pub fn ready_for_requests(&self) -> bool {
+        self.replay.as_ref().is_none_or(|replay| replay.is_ready())
+    }
+    // Applies --replay-speed once the clock is running
+    pub fn replay_clock_initialized(&mut self, initialized: bool) {
+        let requested_speed = match &mut self.replay {
+            Some(replay) => {
+                replay.clock_initialized = initialized;
+                if initialized {
+                    replay.take_requested_speed()
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        if let Some(speed) = requested_speed {
+            self.change_replay_speed(speed);
+        }
+    }
+    pub fn change_replay_speed(&mut self, speed: i64) {
+        if let Some(replay) = &mut self.replay {
+            let request = replay.change_speed(speed);
+            if replay.is_ready() {
+                self.outgoing_messages.push(request);
+            }
+        }
+    }
+    // data_requests is the registry of subscriptions we want. Entries are never removed since
+    // their position is their request id, a removed subscription is just marked Canceled.
+    pub fn add_subscription(&mut self, data_type: MarketData, symbol: &str) {
+        match self
+            .data_requests
+            .iter_mut()
+            .find(|request| request.data_type == data_type && request.symbol == symbol)
+        {
+            Some(request) => {
+                if request.status == RequestStatus::Canceled {
+                    request.status = RequestStatus::Unsent;
+                }
+            }
+            None => self
+                .data_requests
+                .push(MarketDataRequest::new(data_type, symbol)),
+        }
+    }
+    pub fn remove_subscription(&mut self, data_type: MarketData, symbol: &str) {
+        if let Some(n) = self
+            .data_requests
+            .iter()
+            .position(|request| request.data_type == data_type && request.symbol == symbol)
+        {
+            if self.data_requests[n].is_sent() {
+                let unsubscribe = self.data_requests[n].unsubscribe((n + 2) as i32);
+                self.outgoing_messages.push(unsubscribe);
+            }
+            self.data_requests[n].status = RequestStatus::Canceled;
+            self.data_requests[n].acknowledged = false;
+        }
+    }
+    pub fn subscription_index(&self, request_id: i64) -> Option<usize> {
+        let index = request_id - 2;
+        if index >= 0 && (index as usize) < self.data_requests.len() {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+    // Records the server's answer to a subscription. Rejected subscriptions are canceled
+    // rather than retried.
+    pub fn acknowledge(&mut self, request_id: i64, status: i64, historical_id: i64) -> Option<&MarketDataRequest> {
+        let index = self.subscription_index(request_id)?;
+        let request = &mut self.data_requests[index];
+        if !request.is_sent() {
+            return None;
+        }
+        if status == 200 {
+            request.acknowledged = true;
+            if historical_id > 0 {
+                request.historical_id = historical_id;
+            }
+        } else {
+            request.status = RequestStatus::Canceled;
+        }
+        Some(request)
+    }
+    // Anything sent on this session that the server has not answered yet is queued again
+    pub fn expire_unacknowledged(&mut self) {
+        let timeout = tokio::time::Duration::from_millis(SUBSCRIPTION_ACK_TIMEOUT_MILLIS);
+        for request in self.data_requests.iter_mut() {
+            if request.is_sent()
+                && !request.acknowledged
+                && request.sent_at.is_some_and(|sent_at| sent_at.elapsed() > timeout)
+            {
+                request.status = RequestStatus::Unsent;
+            }
+        }
+    }
+    pub fn pending_subscriptions(&self) -> Vec<&MarketDataRequest> {
+        self.data_requests
+            .iter()
+            .filter(|request| request.is_unsent() || (request.is_sent() && !request.acknowledged))
+            .collect()
+    }
+    pub fn request_backfill(&mut self, symbol: &str, time_range: ChartTimeRange) {
+        self.backfill_requests
+            .push(BackfillRequest::new(symbol, time_range, BACKFILL_PAGE_SIZE));
+    }
+    // The chart subscription only covers the last minute, so after a reconnect every chart is
+    // backfilled from its last stored tick, at most BACKFILL_MINUTES back
+    pub fn backfill_gaps(&mut self, last_tick: impl Fn(&str) -> Option<i64>) {
+        if self.replay.is_some() {
+            return;
+        }
+        let now = self.clock.now();
+        let symbols: Vec<String> = self
+            .data_requests
+            .iter()
+            .filter(|request| request.data_type == MarketData::Chart && request.status != RequestStatus::Canceled)
+            .map(|request| request.symbol.clone())
+            .collect();
+        for symbol in symbols {
+            let mut time_range = ChartTimeRange::last_minutes(BACKFILL_MINUTES, now);
+            if let Some(last_tick) = last_tick(&symbol) {
+                time_range.as_far_as_timestamp = time_range
+                    .as_far_as_timestamp
+                    .map(|as_far_as| as_far_as.max(last_tick));
+            }
+            self.request_backfill(&symbol, time_range);
+        }
+    }
+    pub fn backfill_index(&self, request_id: i64) -> Option<usize> {
+        let index = request_id - BACKFILL_REQUEST_ID_OFFSET as i64;
+        if index >= 0 && (index as usize) < self.backfill_requests.len() {
+            Some(index as usize)
+        } else {
+            None
+        }
+    }
+    pub fn backfill_in_progress(&mut self, historical_id: i64) -> Option<&mut BackfillRequest> {
+        self.backfill_requests
+            .iter_mut()
+            .find(|backfill| backfill.is_sent() && backfill.historical_id == historical_id)
+    }
+    // Called after every chart packet. Finished pages get their subscription cancelled and either
+    // queue the next page or mark the backfill as completed.
+    pub fn advance_backfills(&mut self) {
+        for n in 0..self.backfill_requests.len() {
+            let backfill = &mut self.backfill_requests[n];
+            if !backfill.is_sent() || !backfill.end_of_history {
+                continue;
+            }
+            let cancel = backfill.cancel((n as i32) + BACKFILL_REQUEST_ID_OFFSET);
+            if backfill.needs_next_page() {
+                backfill.time_range.closest_timestamp = backfill.earliest_timestamp;
+                backfill.status = RequestStatus::Unsent;
+            } else {
+                backfill.status = RequestStatus::Completed;
+            }
+            self.outgoing_messages.push(cancel);
         }
     }
 }
@@ -60,6 +255,13 @@ impl MarketDataIncoming {
         }
     }
 }
+impl MarketDataIncoming {
+    pub fn reset_session(&mut self) {
+        self.connection_established = false;
+        self.is_authorized = false;
+        self.request_responses.clear();
+    }
+}
 impl Default for MarketDataIncoming {
     fn default() -> Self {
         Self::new()
@@ -69,11 +271,13 @@ pub async fn process_market_data_messages(
     market_data_incoming_rwl: MarketDataIncomingRWL,
     market_data_outgoing_rwl: MarketDataOutGoingRWL,
     market_data: MarketDataRWL,
+    watchdog_rwl: FeedWatchdogRWL,
     message: Result<Message, Error>,
     resource_type: ResourceType,
     report_rwl:ReportRWL
 ) -> bool {
     if let Ok(msg) = message {
+        watchdog_rwl.write().await.record_frame(resource_type);
         match msg {
             Message::Text(mut textmsg) => {
                 if let Some(first_char) = textmsg.chars().next() {
@@ -92,6 +296,7 @@ pub async fn process_market_data_messages(
                                 market_data_incoming_rwl.clone(),
                                 market_data_outgoing_rwl.clone(),
                                 market_data.clone(),
+                                watchdog_rwl.clone(),
                                 resource_type,
                                 report_rwl.clone()
                             )
@@ -130,7 +335,7 @@ pub async fn process_market_data_messages(
             }
         }
     } 
-    return false
+    false
 }
 
 pub async fn process_outgoing_md_messages(
@@ -169,7 +374,7 @@ pub async fn process_outgoing_md_messages(
     if let Ok(read) = market_data_incoming_rwl.try_read() {
         if read.connection_established && write.sent_requests.is_empty() {
             if let Ok(api_client) = api_client_rwl.try_read() {
-                let auth = api_client.ws_auth_msg(resource_type).await;
+                let auth = api_client.ws_auth_msg(Endpoint::MarketData).await;
                 match pwrite.send(Message::Text(auth.clone())).await {
                     Ok(_) => write.sent_requests.push(auth),
                     Err(e) => program_log(
@@ -178,10 +383,30 @@ pub async fn process_outgoing_md_messages(
                 }
             }
         }
-        if read.is_authorized && write.sent_requests.len() < (write.data_requests.len() + 2) {
+        if read.is_authorized {
+            if let Some(replay) = &write.replay {
+                if replay.status == RequestStatus::Unsent {
+                    let request_string = replay.initialize_clock();
+                    match pwrite.send(Message::Text(request_string)).await {
+                        Ok(_) => {
+                            program_log("succesfully sent replay clock initialization", resource_type,report_rwl.clone()).await;
+                            if let Some(replay) = &mut write.replay {
+                                replay.status = RequestStatus::Sent;
+                            }
+                        }
+                        Err(e) => program_log(
+                            &format!("error sending replay clock initialization Error: {}", e),
+                            resource_type,report_rwl.clone()).await,
+                    }
+                }
+            }
+        }
+        if read.is_authorized && write.ready_for_requests() {
+            write.expire_unacknowledged();
             for n in 0..write.data_requests.len() {
                 if write.data_requests[n].is_unsent() {
-                    let request_string = write.data_requests[n].subscribe((n + 2) as i32);
+                    let request_string =
+                        write.data_requests[n].subscribe((n + 2) as i32, write.clock.now());
                     match pwrite.send(Message::Text(request_string.clone())).await {
                         Ok(_) => {
                             program_log(
@@ -191,6 +416,7 @@ pub async fn process_outgoing_md_messages(
                                 ),
                                 resource_type,report_rwl.clone()).await;
                             write.data_requests[n].status = RequestStatus::Sent;
+                            write.data_requests[n].sent_at = Some(tokio::time::Instant::now());
                             write.sent_requests.push(request_string)
                         }
                         Err(e) => program_log(
@@ -205,9 +431,45 @@ pub async fn process_outgoing_md_messages(
             }
         }
     }
+    if let Ok(read) = market_data_incoming_rwl.try_read() {
+        if read.is_authorized && write.ready_for_requests() {
+            for n in 0..write.backfill_requests.len() {
+                if write.backfill_requests[n].is_unsent() {
+                    let request_string = write.backfill_requests[n]
+                        .subscribe((n as i32) + BACKFILL_REQUEST_ID_OFFSET);
+                    match pwrite.send(Message::Text(request_string)).await {
+                        Ok(_) => {
+                            program_log(
+                                &format!(
+                                    "{} succesfully sent backfill request message",
+                                    write.backfill_requests[n].summarize()
+                                ),
+                                resource_type,report_rwl.clone()).await;
+                            write.backfill_requests[n].start_page();
+                        }
+                        Err(e) => program_log(
+                            &format!(
+                                "{} error sending backfill request message Error: {}",
+                                write.backfill_requests[n].summarize(),
+                                e
+                            ),
+                            resource_type,report_rwl.clone()).await,
+                    }
+                }
+            }
+        }
+    }
     // AFTER USER HITS CTRL C
     if write.socket_close {
         write.socket_close = false;
+        for n in 0..write.backfill_requests.len() {
+            if write.backfill_requests[n].is_sent() {
+                let cancel = write.backfill_requests[n].cancel((n as i32) + BACKFILL_REQUEST_ID_OFFSET);
+                if pwrite.send(Message::Text(cancel)).await.is_ok() {
+                    write.backfill_requests[n].status = RequestStatus::Canceled;
+                }
+            }
+        }
         for n in 0..write.data_requests.len() {
             if write.data_requests[n].is_sent() {
                 match pwrite
@@ -259,6 +521,8 @@ pub enum RequestStatus {
     Sent,
     Unsent,
     Canceled,
+    Completed,
+    Failed,
 }
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct MarketDataRequest {
@@ -267,6 +531,8 @@ pub struct MarketDataRequest {
     pub status: RequestStatus,
     pub contract_id: i64,
     pub historical_id: i64,
+    pub acknowledged: bool,
+    pub sent_at: Option<tokio::time::Instant>,
 }
 impl MarketDataRequest {
     pub fn new(data_type: MarketData, symbol: &str) -> Self {
@@ -276,9 +542,20 @@ impl MarketDataRequest {
             status: RequestStatus::Unsent,
             contract_id: 0,
             historical_id: 0,
+            acknowledged: false,
+            sent_at: None,
         }
     }
-    pub fn subscribe(&self, request_id: i32) -> String {
+    // Chart ids are only valid for the session that created them
+    pub fn reset_session(&mut self) {
+        if self.is_sent() {
+            self.status = RequestStatus::Unsent;
+        }
+        self.acknowledged = false;
+        self.sent_at = None;
+        self.historical_id = 0;
+    }
+    pub fn subscribe(&self, request_id: i32, now: chrono::DateTime<chrono::Utc>) -> String {
         use MarketData::*;
         let endpoint = match self.data_type {
             DepthOfMarket => "md/subscribeDOM",
@@ -291,7 +568,7 @@ impl MarketDataRequest {
                 "symbol": self.symbol
             })
         } else {
-            get_tick_chart_request_body(&self.symbol)
+            get_tick_chart_request_body(&self.symbol, now)
         };
         format!("{}\n{}\n\n{}", endpoint, request_id, request_body)
     }
@@ -324,9 +601,11 @@ impl MarketDataRequest {
     }
 }
 
-pub fn get_tick_chart_request_body(symbol: &str) -> Value {
-    let time_stamp = chrono::Utc::now() - Duration::minutes(1);
-    let formatted = time_stamp.to_rfc3339();
+pub fn get_tick_chart_request_body(symbol: &str, now: chrono::DateTime<chrono::Utc>) -> Value {
+    get_tick_chart_range_request_body(symbol, &ChartTimeRange::last_minutes(1, now))
+}
+
+pub fn get_tick_chart_range_request_body(symbol: &str, time_range: &ChartTimeRange) -> Value {
     json!({
       "symbol": symbol,
       "chartDescription": {
@@ -334,8 +613,216 @@ pub fn get_tick_chart_request_body(symbol: &str) -> Value {
         "elementSize": 1,
         "elementSizeUnit": "UnderlyingUnits"
       },
-      "timeRange": {
-          "asFarAsTimestamp": formatted,
-      }
+      "timeRange": time_range.to_json()
     })
 }
+
+// Timestamps are unix millis. History is returned from closest_timestamp going back
+// until as_far_as_timestamp or as_much_as_elements is reached.
+#[derive(Debug, Clone, Default, PartialEq, PartialOrd)]
+pub struct ChartTimeRange {
+    pub closest_timestamp: Option<i64>,
+    pub as_far_as_timestamp: Option<i64>,
+    pub as_much_as_elements: Option<i64>,
+}
+impl ChartTimeRange {
+    pub fn last_minutes(minutes: i64, now: chrono::DateTime<chrono::Utc>) -> Self {
+        let time_stamp = now - Duration::minutes(minutes);
+        Self {
+            closest_timestamp: None,
+            as_far_as_timestamp: Some(time_stamp.timestamp_millis()),
+            as_much_as_elements: None,
+        }
+    }
+    pub fn between(as_far_as_timestamp: i64, closest_timestamp: i64) -> Self {
+        Self {
+            closest_timestamp: Some(closest_timestamp),
+            as_far_as_timestamp: Some(as_far_as_timestamp),
+            as_much_as_elements: None,
+        }
+    }
+    pub fn with_elements(self, as_much_as_elements: i64) -> Self {
+        Self {
+            as_much_as_elements: Some(as_much_as_elements),
+            ..self
+        }
+    }
+    pub fn to_json(&self) -> Value {
+        let mut time_range = serde_json::Map::new();
+        if let Some(closest) = self.closest_timestamp {
+            time_range.insert(
+                "closestTimestamp".to_string(),
+                json!(chrono::Utc.timestamp_millis(closest).to_rfc3339()),
+            );
+        }
+        if let Some(as_far_as) = self.as_far_as_timestamp {
+            time_range.insert(
+                "asFarAsTimestamp".to_string(),
+                json!(chrono::Utc.timestamp_millis(as_far_as).to_rfc3339()),
+            );
+        }
+        if let Some(elements) = self.as_much_as_elements {
+            time_range.insert("asMuchAsElements".to_string(), json!(elements));
+        }
+        Value::Object(time_range)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct BackfillRequest {
+    pub symbol: String,
+    pub time_range: ChartTimeRange,
+    pub page_size: i64,
+    pub status: RequestStatus,
+    pub historical_id: i64,
+    pub pages_received: i64,
+    pub page_items: i64,
+    pub total_items: i64,
+    pub earliest_timestamp: Option<i64>,
+    pub end_of_history: bool,
+    // rejections of the current page
+    pub attempts: i64,
+}
+impl BackfillRequest {
+    pub fn new(symbol: &str, time_range: ChartTimeRange, page_size: i64) -> Self {
+        Self {
+            symbol: symbol.to_string(),
+            time_range,
+            page_size,
+            status: RequestStatus::Unsent,
+            historical_id: 0,
+            pages_received: 0,
+            page_items: 0,
+            total_items: 0,
+            earliest_timestamp: None,
+            end_of_history: false,
+            attempts: 0,
+        }
+    }
+    pub fn subscribe(&self, request_id: i32) -> String {
+        let page_range = self.time_range.clone().with_elements(self.page_size);
+        format!(
+            "md/getChart\n{}\n\n{}",
+            request_id,
+            get_tick_chart_range_request_body(&self.symbol, &page_range)
+        )
+    }
+    pub fn cancel(&self, request_id: i32) -> String {
+        format!(
+            "md/cancelChart\n{}\n\n{}",
+            request_id,
+            json!({ "subscriptionId": self.historical_id })
+        )
+    }
+    pub fn start_page(&mut self) {
+        self.status = RequestStatus::Sent;
+        self.historical_id = 0;
+        self.page_items = 0;
+        self.end_of_history = false;
+    }
+    pub fn record_page(&mut self, items: &[TimeAndSalesItem], end_of_history: bool) {
+        self.page_items += items.len() as i64;
+        self.total_items += items.len() as i64;
+        if let Some(earliest) = items.iter().map(|item| item.timestamp).min() {
+            self.earliest_timestamp = Some(match self.earliest_timestamp {
+                Some(current) => std::cmp::min(current, earliest),
+                None => earliest,
+            });
+        }
+        if end_of_history {
+            self.pages_received += 1;
+            self.attempts = 0;
+            self.end_of_history = true;
+        }
+    }
+    // A full page that has not reached as_far_as_timestamp means there is more history to fetch
+    pub fn needs_next_page(&self) -> bool {
+        match self.earliest_timestamp {
+            Some(earliest) => {
+                self.page_items >= self.page_size
+                    && self
+                        .time_range
+                        .as_far_as_timestamp
+                        .is_none_or(|as_far_as| earliest > as_far_as)
+                    && self
+                        .time_range
+                        .closest_timestamp
+                        .is_none_or(|closest| earliest < closest)
+            }
+            None => false,
+        }
+    }
+    pub fn is_unsent(&self) -> bool {
+        self.status == RequestStatus::Unsent
+    }
+    pub fn is_sent(&self) -> bool {
+        self.status == RequestStatus::Sent
+    }
+    pub fn is_completed(&self) -> bool {
+        self.status == RequestStatus::Completed
+    }
+    // The page is sent again until it has been rejected BACKFILL_MAX_ATTEMPTS times
+    pub fn reject(&mut self) {
+        self.attempts += 1;
+        self.status = if self.attempts < BACKFILL_MAX_ATTEMPTS {
+            RequestStatus::Unsent
+        } else {
+            RequestStatus::Failed
+        };
+    }
+    pub fn summarize(&self) -> String {
+        format!(
+            "{} Backfill page {} ({} ticks)",
+            self.symbol,
+            self.pages_received + 1,
+            self.total_items
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn reconnects_backfill_charts_from_their_last_tick() {
+        let now = 10 * 60 * 60 * 1000;
+        let mut outgoing = MarketDataOutgoing {
+            clock: Arc::new(ManualClock::new(now)),
+            ..MarketDataOutgoing::new(vec![
+                MarketDataRequest::new(MarketData::Chart, "ESZ2"),
+                MarketDataRequest::new(MarketData::Chart, "NQZ2"),
+                MarketDataRequest::new(MarketData::DepthOfMarket, "ESZ2"),
+            ])
+        };
+        let last_tick = now - 5 * 60 * 1000;
+        outgoing.backfill_gaps(|symbol| (symbol == "ESZ2").then_some(last_tick));
+        let ranges: Vec<(&str, Option<i64>)> = outgoing
+            .backfill_requests
+            .iter()
+            .map(|backfill| (backfill.symbol.as_str(), backfill.time_range.as_far_as_timestamp))
+            .collect();
+        assert_eq!(
+            ranges,
+            vec![
+                ("ESZ2", Some(last_tick)),
+                ("NQZ2", Some(now - BACKFILL_MINUTES * 60 * 1000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn backfills_never_reach_further_back_than_backfill_minutes() {
+        let now = 10 * 60 * 60 * 1000;
+        let mut outgoing = MarketDataOutgoing {
+            clock: Arc::new(ManualClock::new(now)),
+            ..MarketDataOutgoing::new(vec![MarketDataRequest::new(MarketData::Chart, "ESZ2")])
+        };
+        outgoing.backfill_gaps(|_| Some(0));
+        assert_eq!(
+            outgoing.backfill_requests[0].time_range.as_far_as_timestamp,
+            Some(now - BACKFILL_MINUTES * 60 * 1000)
+        );
+    }
+}
@@ -1,6 +1,12 @@
+use crate::accounting::Ledger;
+use crate::clock::{system_clock, Clock, ClockRef};
+use crate::order_journal::{DuplicateOrder, OrderIntent, OrderJournal, Submission, SubmissionState};
+use crate::price::{PriceSpec, Rounding};
+use crate::replay::ReplaySession;
 use crate::settings::TIMEZONE;
 use crate::strategy::MarketPosition;
 use crate::time_and_sales::{self, OrderAction, TimeAndSalesItem};
+use crate::trade_classifier::quote_rule;
 use std::cmp::Ordering;
 use std::sync::Arc;
 
@@ -14,7 +20,7 @@ use serde::Serialize;
 use serde_json::Value;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
 pub struct ContractID {
     pub contract_maturity_id: i64,
     pub id: i64,
@@ -28,7 +34,7 @@ pub struct ContractID {
     pub big_point_value: f64,
 }
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
 pub struct ProductInfo {
     pub id: i64,
     #[serde(rename = "name")]
@@ -51,6 +57,26 @@ pub struct ProductInfo {
 pub fn to_be_calculated_bool() -> bool {
     false
 }
+// One delivery month of a product, ESM2 belongs to the 202206 maturity of ES
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContractMaturity {
+    pub id: i64,
+    pub product_id: i64,
+    pub expiration_month: i64,
+    pub expiration_date: String,
+    #[serde(default = "to_be_calculated_bool")]
+    pub is_front: bool,
+    #[serde(default = "to_be_calculated_bool")]
+    pub archived: bool,
+}
+impl ContractMaturity {
+    pub fn expiration(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::parse_from_rfc3339(&self.expiration_date)
+            .ok()
+            .map(|expiration| expiration.with_timezone(&chrono::Utc))
+    }
+}
 
 #[derive(Debug)]
 pub struct AcctCommsChannel {
@@ -65,6 +91,12 @@ pub struct AcctCommsChannel {
     pub order_update: Option<OrderUpdateMessage>,
     pub order_id_messages: Vec<OrderIDMessage>,
     pub received_closing_frame:bool,
+    pub ledger: Ledger,
+    pub journal: OrderJournal,
+    pub replay: Option<ReplaySession>,
+    pub clock: ClockRef,
+    // request id of the command/list lookup in flight
+    pub order_lookup: Option<i64>,
 }
 impl AcctCommsChannel {
     pub fn new(requests: Vec<String>) -> Self {
@@ -80,6 +112,133 @@ impl AcctCommsChannel {
             order_update: None,
             order_id_messages: Vec::new(),
             received_closing_frame: false,
+            ledger: Ledger::default(),
+            journal: OrderJournal::default(),
+            replay: None,
+            clock: system_clock(),
+            order_lookup: None,
+        }
+    }
+    pub fn next_request_id(&mut self) -> i64 {
+        self.reserve_request_ids(1)
+    }
+    // Consecutive request ids for a batch of requests, returns the first
+    pub fn reserve_request_ids(&mut self, count: i64) -> i64 {
+        self.journal.reserve_request_ids(count)
+    }
+    // Journals the order and queues the request build makes from its request id and client order
+    // id. The same intent within DUPLICATE_ORDER_WINDOW_MILLIS is refused.
+    pub fn submit_order(
+        &mut self,
+        intent: OrderIntent,
+        build: impl FnOnce(i64, &str) -> String,
+    ) -> Result<String, DuplicateOrder> {
+        let now = self.clock.now_millis();
+        if let Some(original) = self.journal.duplicate_of(&intent, now) {
+            return Err(DuplicateOrder {
+                cl_ord_id: original.cl_ord_id.clone(),
+            });
+        }
+        let request_id = self.next_request_id();
+        let cl_ord_id = self.journal.next_cl_ord_id();
+        let request = build(request_id, &cl_ord_id);
+        self.journal.record(Submission {
+            cl_ord_id: cl_ord_id.clone(),
+            intent,
+            request_id,
+            request: request.clone(),
+            state: SubmissionState::Queued,
+            order_id: None,
+            submitted_at: now,
+        });
+        self.unsent_requests.push(request);
+        Ok(cl_ord_id)
+    }
+    // Looks up the journaled orders in the synced data. Orders not found there are asked for with
+    // a command/list lookup before anything is sent again. Returns lines to log.
+    pub fn reconcile_orders(&mut self) -> Vec<String> {
+        let user_data = match &self.user_data {
+            Some(user_data) => &user_data.data,
+            None => return Vec::new(),
+        };
+        let (mut log, lookup) = self.journal.reconcile(user_data);
+        if lookup && self.order_lookup.is_none() {
+            let request_id = self.next_request_id();
+            self.order_lookup = Some(request_id);
+            self.unsent_requests
+                .push(format!("command/list\n{}\n\n", request_id));
+            log.push("Looking up orders missing from the sync".to_string());
+        }
+        log
+    }
+    pub fn is_order_lookup(&self, request_id: Option<i64>) -> bool {
+        self.order_lookup.is_some() && self.order_lookup == request_id
+    }
+    // Sends again the orders the command/list response shows never arrived. Returns lines to log.
+    pub fn on_order_lookup(&mut self, response: &Value) -> Vec<String> {
+        self.order_lookup = None;
+        let commands = match response["d"].as_array() {
+            Some(commands) if response["s"].as_i64() == Some(200) => commands,
+            _ => {
+                self.journal.lookup_failed();
+                return vec![format!("Order lookup failed {}", response)];
+            }
+        };
+        let (log, resubmit) = self
+            .journal
+            .resolve_lookup(commands, self.clock.now_millis());
+        if let Some(user_data) = &self.user_data {
+            self.journal.update_states(&user_data.data.orders);
+        }
+        for cl_ord_id in resubmit {
+            let request_id = self.next_request_id();
+            if let Some(request) = self.journal.renumber(&cl_ord_id, request_id) {
+                self.unsent_requests.push(request);
+            }
+        }
+        log
+    }
+    // Requests left over from the previous connection are kept, but any old auth, sync or lookup
+    // request is replaced by the ones for the new session which are sent first
+    pub fn reset_session(&mut self, session_requests: Vec<String>) {
+        self.connection_established = false;
+        self.is_authorized = false;
+        self.received_closing_frame = false;
+        self.last_heart_beat_instant = tokio::time::Instant::now();
+        self.journal.on_disconnect(&self.sent_requests);
+        self.sent_requests.clear();
+        // a lookup lost with the connection is asked again after the next sync
+        if self.order_lookup.take().is_some() {
+            self.journal.lookup_failed();
+        }
+        let pending = std::mem::take(&mut self.unsent_requests)
+            .into_iter()
+            .filter(|request| {
+                !request.starts_with("authorize")
+                    && !request.starts_with("user/syncrequest")
+                    && !request.starts_with("command/list")
+            });
+        self.unsent_requests = session_requests;
+        self.unsent_requests.extend(pending);
+        if let Some(replay) = &mut self.replay {
+            replay.reset_session();
+        }
+    }
+    // On a replay socket nothing but the auth request goes out before the clock is initialized
+    pub fn ready_for_requests(&self) -> bool {
+        self.replay.as_ref().is_none_or(|replay| replay.is_ready())
+    }
+    // Applies --replay-speed once the clock is running. The speed change goes ahead of queued requests.
+    pub fn replay_clock_initialized(&mut self, initialized: bool) {
+        if let Some(replay) = &mut self.replay {
+            replay.clock_initialized = initialized;
+            if !initialized {
+                return;
+            }
+            if let Some(speed) = replay.take_requested_speed() {
+                let request = replay.change_speed(speed);
+                self.unsent_requests.insert(0, request);
+            }
         }
     }
 }
@@ -197,12 +356,12 @@ pub struct DOMMessage {
     pub event: String,
 }
 impl DOMMessage {
-    pub fn combine(&self) -> DOMSummary {
+    pub fn combine(&self, clock: &dyn Clock) -> DOMSummary {
         let mut target = Vec::new();
         self.data
             .doms
             .par_iter()
-            .map(|dom| dom.get_summary())
+            .map(|dom| dom.get_summary(clock))
             .collect_into_vec(&mut target);
         let mut total = DOMSummary::new();
         for summary in target {
@@ -226,7 +385,7 @@ pub struct Dom {
     pub timestamp: String,
 }
 impl Dom {
-    pub fn get_summary(&self) -> DOMSummary {
+    pub fn get_summary(&self, clock: &dyn Clock) -> DOMSummary {
         let asks: i64 = self.offers.iter().map(|offer| offer.size).sum();
         let bids: i64 = self.bids.iter().map(|bid| bid.size).sum();
         let total = asks + bids;
@@ -234,7 +393,7 @@ impl Dom {
         let percent_sells = 100.0 * (asks as f64 / total as f64);
         let timestamp = match chrono::DateTime::parse_from_rfc3339(&self.timestamp) {
             Ok(ts) => ts.timestamp_millis(),
-            Err(_) => chrono::Utc::now().timestamp_millis()  
+            Err(_) => clock.now_millis(),
         };
         DOMSummary {
             percent_sells,
@@ -322,6 +481,7 @@ pub struct WebsocketResponse {
 }
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RequestData {
+    #[serde(default)]
     pub mode: String,
     #[serde(rename = "subscriptionId")]
     #[serde(default = "to_be_calculated_int")]
@@ -342,21 +502,6 @@ pub struct TickChart {
     #[serde(rename = "e")]
     pub event: String,
 }
-impl TickChart {
-    pub fn get_all_ts_items(&self) -> Vec<TimeAndSalesItem> {
-        let mut vec_of_vec = Vec::new();
-        self.data
-            .charts
-            .par_iter()
-            .map(|chart| chart.get_ts_items())
-            .collect_into_vec(&mut vec_of_vec);
-        let mut output_vec: Vec<TimeAndSalesItem> = Vec::new();
-        for vec in vec_of_vec {
-            output_vec.extend(vec);
-        }
-        output_vec
-    }
-}
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct ChartData {
@@ -388,17 +533,20 @@ pub struct Chart {
     pub eoh: bool,
 }
 impl Chart {
-    pub fn get_ts_items(&self) -> Vec<TimeAndSalesItem> {
+    pub fn get_ts_items(&self, now: i64) -> Vec<TimeAndSalesItem> {
         let mut output_vec = Vec::new();
         let base_price = self.base_price as f64 * self.tick_size;
         self.ticks
             .par_iter()
-            .map(|tick| {
-                tick.to_ts_item(
+            .map(|tick| TimeAndSalesItem {
+                trade_date: self.trade_date,
+                ..tick.to_ts_item(
                     base_price,
                     self.tick_size,
                     self.base_timestamp,
                     self.historical_id,
+                    self.contract_id,
+                    now,
                 )
             })
             .collect_into_vec(&mut output_vec);
@@ -439,27 +587,29 @@ impl Tick {
         tick_size: f64,
         base_timestamp: i64,
         historical_id: i64,
+        contract_id: i64,
+        now: i64,
     ) -> TimeAndSalesItem {
-        let price = base_price + (tick_size * self.relative_price as f64);
-        let bid = base_price + (tick_size * self.bid_relative_price as f64);
-        let ask = base_price + (tick_size * self.ask_relative_price as f64);
-        let mid_price = 0.5 * (ask + bid);
+        // prices are offsets in ticks from the packet's base price
+        let base = PriceSpec::new(tick_size, 0.0).price(base_price, Rounding::Nearest);
+        let price = (base + self.relative_price).to_f64();
+        let bid = (base + self.bid_relative_price).to_f64();
+        let ask = (base + self.ask_relative_price).to_f64();
         let timestamp = base_timestamp + self.relative_timestamp;
-        let delay = chrono::Utc::now().timestamp_millis() - timestamp;
-        let action = if price > mid_price {
-            OrderAction::Buy
-        } else if price < mid_price {
-            OrderAction::Sell
-        } else {
-            OrderAction::Unknown
-        };
+        let delay = now - timestamp;
+        let classification = quote_rule(price, bid, ask);
         TimeAndSalesItem {
             historical_id,
-            action,
+            contract_id,
+            tick_id: self.tick_id,
+            action: classification.action,
+            classified_by: classification.rule,
+            confidence: classification.confidence,
             qty: self.tick_volume,
             price,
             timestamp,
             receipt_delay: delay,
+            trade_date: 0,
             bid,
             ask,
         }
@@ -510,8 +660,8 @@ pub struct UserData {
     pub currencies: Vec<Currency>,
     pub exchanges: Vec<Exchange>,
     pub execution_reports: Vec<Value>,
-    pub fill_pairs: Vec<Value>,
-    pub fills: Vec<Value>,
+    pub fill_pairs: Vec<FillPair>,
+    pub fills: Vec<Fill>,
     pub margin_snapshots: Vec<MarginSnapshot>,
     pub order_strategies: Vec<Value>,
     pub order_strategy_links: Vec<Value>,
@@ -529,10 +679,66 @@ pub struct UserData {
     pub users: Vec<User>,
 }
 
+impl UserData {
+    pub fn account(&self, name: &str) -> Option<&Account> {
+        self.accounts.iter().find(|account| account.name == name)
+    }
+    pub fn open_positions(&self, account_id: i64) -> impl Iterator<Item = &Position> {
+        self.positions
+            .iter()
+            .filter(move |position| position.account_id == account_id && position.net_pos != 0)
+    }
+    // Keeps the synced lists current with props events. False for entity types not tracked here.
+    pub fn apply_props(&mut self, event: &PropsEvent) -> bool {
+        match event.entity_type.as_str() {
+            "cashBalance" => upsert(&mut self.cash_balances, event, |item| item.id),
+            "marginSnapshot" => upsert(&mut self.margin_snapshots, event, |item| item.id),
+            "position" => upsert(&mut self.positions, event, |item| item.id),
+            "fill" => upsert(&mut self.fills, event, |item| item.id),
+            "fillPair" => upsert(&mut self.fill_pairs, event, |item| item.id),
+            "accountRiskStatus" => upsert(&mut self.account_risk_statuses, event, |item| item.id),
+            "userAccountAutoLiq" => upsert(&mut self.user_account_auto_liqs, event, |item| item.id),
+            "order" => upsert(&mut self.orders, event, |item| item["id"].as_i64().unwrap_or(0)),
+            _ => false,
+        }
+    }
+}
+fn upsert<T: serde::de::DeserializeOwned>(
+    items: &mut Vec<T>,
+    event: &PropsEvent,
+    id_of: fn(&T) -> i64,
+) -> bool {
+    let id = match event.entity_id() {
+        Some(id) => id,
+        None => return false,
+    };
+    if event.is_deleted() {
+        items.retain(|item| id_of(item) != id);
+        return true;
+    }
+    let item: T = match serde_json::from_value(event.entity.clone()) {
+        Ok(item) => item,
+        Err(_) => return false,
+    };
+    match items.iter_mut().find(|existing| id_of(existing) == id) {
+        Some(existing) => *existing = item,
+        None => items.push(item),
+    }
+    true
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct AccountRiskStatus {
+    // the account id
     pub id: i64,
+    #[serde(default)]
+    pub admin_action: Option<String>,
+    // set while the account may only reduce positions
+    #[serde(default)]
+    pub liquidate_only: Option<String>,
+    #[serde(default)]
+    pub user_triggered_liq_only: bool,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -567,6 +773,46 @@ pub struct CashBalance {
     pub week_realized_pn_l: f64,
 }
 
+// One execution of an order. Fills carry no account, it comes from the order.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct Fill {
+    pub id: i64,
+    pub order_id: i64,
+    pub contract_id: i64,
+    pub timestamp: String,
+    pub action: OrderAction,
+    pub qty: i64,
+    pub price: f64,
+    #[serde(default)]
+    pub active: bool,
+}
+impl Fill {
+    // positive for a buy
+    pub fn signed_qty(&self) -> i64 {
+        if self.action == OrderAction::Sell {
+            -self.qty
+        } else {
+            self.qty
+        }
+    }
+}
+
+// A buy fill matched against a sell fill by Tradovate
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct FillPair {
+    pub id: i64,
+    pub position_id: i64,
+    pub buy_fill_id: i64,
+    pub sell_fill_id: i64,
+    pub qty: i64,
+    pub buy_price: f64,
+    pub sell_price: f64,
+    #[serde(default)]
+    pub active: bool,
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct TradeDate {
@@ -576,14 +822,25 @@ pub struct TradeDate {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
 pub struct ContractGroup {
     pub id: i64,
     pub name: String,
 }
 
+// Exchange margins of a product per contract, its id is the product's id
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
+pub struct ProductMargin {
+    pub id: i64,
+    pub initial_margin: f64,
+    pub maintenance_margin: f64,
+    #[serde(default)]
+    pub timestamp: String,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct Currency {
     pub id: i64,
     pub name: String,
@@ -591,7 +848,7 @@ pub struct Currency {
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
 pub struct Exchange {
     pub cftc_reporting: bool,
     pub complex: String,
@@ -622,7 +879,26 @@ pub struct MarginSnapshot {
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct UserAccountAutoLiq {
+    // the account id
     pub id: i64,
+    #[serde(default)]
+    pub margin_percentage_alert: Option<f64>,
+    #[serde(default)]
+    pub daily_loss_alert: Option<f64>,
+    #[serde(default)]
+    pub daily_loss_percentage_alert: Option<f64>,
+    #[serde(default)]
+    pub margin_percentage_liq_only: Option<f64>,
+    #[serde(default)]
+    pub daily_loss_liq_only: Option<f64>,
+    #[serde(default)]
+    pub margin_percentage_auto_liq: Option<f64>,
+    #[serde(default)]
+    pub daily_loss_auto_liq: Option<f64>,
+    #[serde(default)]
+    pub weekly_loss_auto_liq: Option<f64>,
+    #[serde(default)]
+    pub trailing_max_drawdown: Option<f64>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -705,10 +981,36 @@ impl OrderUpdateMessage {
     pub fn is_filled(&self) -> bool {
         self.data.entity.ord_status == "Filled"
     }
+    #[allow(clippy::misnamed_getters)]
     pub fn id(&self) -> i64 {
         self.data.entity.order_id
     }
 }
+// Any other entity pushed by user/syncrequest, kept as json until its type is known
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct PropsMessage {
+    #[serde(rename = "d")]
+    pub data: PropsEvent,
+    #[serde(rename = "e")]
+    pub event: String,
+}
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all(deserialize = "camelCase"))]
+pub struct PropsEvent {
+    pub entity: Value,
+    pub entity_type: String,
+    pub event_type: String,
+}
+impl PropsEvent {
+    pub fn is_deleted(&self) -> bool {
+        self.event_type == "Deleted"
+    }
+    pub fn entity_id(&self) -> Option<i64> {
+        self.entity["id"].as_i64()
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct OrderUpdate {
@@ -718,7 +1020,7 @@ pub struct OrderUpdate {
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all(deserialize = "camelCase"))]
+#[serde(rename_all = "camelCase")]
 pub struct OrderStatus {
     pub account_id: i64,
     pub action: time_and_sales::OrderAction,
@@ -735,11 +1037,12 @@ pub struct OrderStatus {
     pub ord_status: String,
     pub order_id: i64,
     pub timestamp: String,
-    #[serde(default = "to_be_calculated_ts")]
+    // stamped with the clock once parsed
+    #[serde(default)]
     pub parsing_ts: String,
 }
 impl OrderStatus {
-    pub fn new(avg_px: f64, action: OrderAction) -> Self {
+    pub fn new(avg_px: f64, action: OrderAction, clock: &dyn Clock) -> Self {
         Self {
             account_id: 0,
             action,
@@ -756,12 +1059,13 @@ impl OrderStatus {
             ord_status: "Filled".to_string(),
             order_id: 0,
             timestamp: String::new(),
-            parsing_ts: to_be_calculated_ts(),
+            parsing_ts: to_be_calculated_ts(clock),
         }
     }
 }
-fn to_be_calculated_ts() -> String {
-    chrono::Utc::now()
+pub fn to_be_calculated_ts(clock: &dyn Clock) -> String {
+    clock
+        .now()
         .with_timezone(&TIMEZONE)
         .format("%F %T")
         .to_string()
@@ -785,7 +1089,8 @@ pub struct Position {
     pub trade_date: TradeDate,
 }
 impl Position {
-    pub fn to_market_position(&self) -> MarketPosition {
+    // Counts as opened now, anything the ledger realized in the contract before is not its own
+    pub fn to_market_position(&self, ledger: &Ledger, clock: &dyn Clock) -> MarketPosition {
         let action = match self.net_pos.cmp(&0) {
             Ordering::Greater => OrderAction::Buy,
             Ordering::Less => OrderAction::Buy,
@@ -795,9 +1100,13 @@ impl Position {
             account_id: self.account_id,
             contract_id: self.contract_id,
             net_pos: self.net_pos,
-            entry_fill: Some(OrderStatus::new(self.net_price, action)),
+            entry_fill: Some(OrderStatus::new(self.net_price, action, clock)),
             exit_fill: None,
             realized_pnl: 0.0,
+            gross_baseline: ledger
+                .gross_realized(self.account_id, self.contract_id)
+                .unwrap_or_default(),
+            settled: false,
             entry_trigger: String::new(),
             exit_trigger: String::new(),
         }
@@ -822,6 +1131,11 @@ pub struct OrderIDMessage {
 #[serde(rename_all(deserialize = "camelCase"))]
 pub struct OrderID {
     pub order_id: i64,
+    // the bracket orders of an order/placeOSO
+    #[serde(default)]
+    pub oso1_id: Option<i64>,
+    #[serde(default)]
+    pub oso2_id: Option<i64>,
 }
 
 #[derive(Debug)]
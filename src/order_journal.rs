@@ -0,0 +1,529 @@
+use serde::{Deserialize, Serialize};
+
+use serde_json::Value;
+
+use crate::{
+    api_client::ResourceType,
+    environment::environment_label,
+    models::{AcctCommChannelRWL, UserData},
+    others::{create_json_file_atomically, open_json},
+    reporting::{program_log, ReportRWL},
+    settings::{
+        DUPLICATE_ORDER_WINDOW_MILLIS, FIRST_ORDER_REQUEST_ID, ORDER_JOURNAL_FILENAME,
+        ORDER_JOURNAL_RETENTION_HOURS, ORDER_JOURNAL_SAVE_MILLIS, ORDER_RESUBMIT_MAX_AGE_MILLIS,
+    },
+    time_and_sales::OrderAction,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderKind {
+    Market,
+    // an entry with its stop loss and take profit
+    Oso,
+    Liquidate,
+}
+
+// What an order is meant to do. Two equal intents close together are one order sent twice.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OrderIntent {
+    pub account_id: i64,
+    // the symbol, or the contract id for a liquidation
+    pub instrument: String,
+    pub action: OrderAction,
+    pub qty: i64,
+    pub kind: OrderKind,
+}
+impl OrderIntent {
+    pub fn market(account_id: i64, symbol: &str, action: OrderAction, qty: i64) -> Self {
+        Self {
+            account_id,
+            instrument: symbol.to_string(),
+            action,
+            qty,
+            kind: OrderKind::Market,
+        }
+    }
+    pub fn oso(account_id: i64, symbol: &str, action: OrderAction, qty: i64) -> Self {
+        Self {
+            kind: OrderKind::Oso,
+            ..Self::market(account_id, symbol, action, qty)
+        }
+    }
+    pub fn liquidate(account_id: i64, contract_id: i64) -> Self {
+        Self {
+            account_id,
+            instrument: contract_id.to_string(),
+            action: OrderAction::Unknown,
+            qty: 0,
+            kind: OrderKind::Liquidate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubmissionState {
+    // waiting in unsent_requests
+    Queued,
+    // sent when the connection dropped, its fate is looked up on the next sync
+    AwaitingLookup,
+    // not among the synced commands, confirmed with a command/list lookup before sending again
+    LookingUp,
+    Acknowledged,
+    Working,
+    Filled,
+    Canceled,
+    Rejected,
+    // never reached the broker and too old to send again
+    Lost,
+}
+impl SubmissionState {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            SubmissionState::Filled
+                | SubmissionState::Canceled
+                | SubmissionState::Rejected
+                | SubmissionState::Lost
+        )
+    }
+    fn from_ord_status(ord_status: &str) -> Option<Self> {
+        match ord_status {
+            "Filled" => Some(SubmissionState::Filled),
+            "Canceled" | "Expired" => Some(SubmissionState::Canceled),
+            "Rejected" => Some(SubmissionState::Rejected),
+            "Working" | "PendingNew" | "PendingReplace" | "PendingCancel" | "Suspended" => {
+                Some(SubmissionState::Working)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Submission {
+    pub cl_ord_id: String,
+    pub intent: OrderIntent,
+    pub request_id: i64,
+    pub request: String,
+    pub state: SubmissionState,
+    pub order_id: Option<i64>,
+    pub submitted_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateOrder {
+    pub cl_ord_id: String,
+}
+impl std::fmt::Display for DuplicateOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "duplicate of order {}", self.cl_ord_id)
+    }
+}
+
+// Every order submitted, by client order id. Saved in ORDER_JOURNAL_FILENAME by
+// keep_journal_saved after each change so orders that were in flight when the process or the
+// connection died can be looked up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OrderJournal {
+    // the server the orders went to, the file is shared by every server
+    #[serde(default)]
+    pub environment: String,
+    pub submissions: Vec<Submission>,
+    // request ids on the trading socket, saved so they never repeat across sessions and restarts
+    #[serde(default)]
+    next_request_id: i64,
+    // keeps client order ids unique across restarts
+    #[serde(skip)]
+    prefix: String,
+    #[serde(skip)]
+    next_seq: u64,
+    // bumped on every change, the saver writes the file when it moved
+    #[serde(skip)]
+    revision: u64,
+}
+impl OrderJournal {
+    // The saved journal without entries older than ORDER_JOURNAL_RETENTION_HOURS. Orders the last
+    // run queued or sent without hearing back are looked up on the first sync.
+    pub fn load(now_millis: i64) -> Self {
+        Self::load_or(Self::default(), now_millis)
+    }
+    // fallback stands in for a missing or unreadable file
+    pub fn load_or(fallback: Self, now_millis: i64) -> Self {
+        let saved = open_json(ORDER_JOURNAL_FILENAME)
+            .ok()
+            .and_then(|file| serde_json::from_value(file).ok());
+        Self::restore(saved, fallback, environment_label(), now_millis)
+    }
+    // A journal saved against another server is ignored, its orders and request ids mean nothing
+    // here. Client order ids are prefixed with the wall time so a replay restarted from the same
+    // start time never repeats them.
+    pub fn restore(saved: Option<Self>, fallback: Self, environment: &str, now_millis: i64) -> Self {
+        let mut journal = saved
+            .filter(|saved| saved.environment == environment)
+            .unwrap_or(fallback);
+        journal.environment = environment.to_string();
+        journal.prefix = chrono::Utc::now().timestamp_millis().to_string();
+        let last_request_id = journal
+            .submissions
+            .iter()
+            .map(|submission| submission.request_id)
+            .max()
+            .unwrap_or_default();
+        journal.next_request_id = journal.next_request_id.max(last_request_id + 1);
+        journal.submissions.retain(|submission| {
+            now_millis - submission.submitted_at < ORDER_JOURNAL_RETENTION_HOURS * 60 * 60 * 1000
+        });
+        for submission in &mut journal.submissions {
+            if submission.state == SubmissionState::Queued {
+                submission.state = SubmissionState::AwaitingLookup;
+            }
+        }
+        journal
+    }
+    fn changed(&mut self) {
+        self.revision += 1;
+    }
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+    // Hands out count consecutive request ids and returns the first
+    pub fn reserve_request_ids(&mut self, count: i64) -> i64 {
+        let first = self.next_request_id.max(FIRST_ORDER_REQUEST_ID);
+        self.next_request_id = first + count;
+        self.changed();
+        first
+    }
+    pub fn next_cl_ord_id(&mut self) -> String {
+        self.next_seq += 1;
+        format!("{}-{}", self.prefix, self.next_seq)
+    }
+    pub fn duplicate_of(&self, intent: &OrderIntent, now_millis: i64) -> Option<&Submission> {
+        self.submissions.iter().rev().find(|submission| {
+            submission.intent == *intent
+                && now_millis - submission.submitted_at < DUPLICATE_ORDER_WINDOW_MILLIS
+                && !matches!(
+                    submission.state,
+                    SubmissionState::Rejected | SubmissionState::Lost
+                )
+        })
+    }
+    pub fn last_submission(&self, intent: &OrderIntent) -> Option<&Submission> {
+        self.submissions
+            .iter()
+            .rev()
+            .find(|submission| submission.intent == *intent)
+    }
+    pub fn record(&mut self, submission: Submission) {
+        self.submissions.push(submission);
+        self.changed();
+    }
+    pub fn get(&self, cl_ord_id: &str) -> Option<&Submission> {
+        self.submissions
+            .iter()
+            .find(|submission| submission.cl_ord_id == cl_ord_id)
+    }
+    pub fn order_id(&self, cl_ord_id: &str) -> Option<i64> {
+        self.get(cl_ord_id)?.order_id
+    }
+    pub fn acknowledge(&mut self, request_id: i64, order_id: i64) {
+        if let Some(submission) = self.submissions.iter_mut().rev().find(|submission| {
+            submission.request_id == request_id && submission.state == SubmissionState::Queued
+        }) {
+            submission.order_id = Some(order_id);
+            submission.state = SubmissionState::Acknowledged;
+            self.changed();
+        }
+    }
+    pub fn on_execution(&mut self, order_id: i64, ord_status: &str) {
+        let state = match SubmissionState::from_ord_status(ord_status) {
+            Some(state) => state,
+            None => return,
+        };
+        if let Some(submission) = self
+            .submissions
+            .iter_mut()
+            .find(|submission| submission.order_id == Some(order_id))
+        {
+            if submission.state != state {
+                submission.state = state;
+                self.changed();
+            }
+        }
+    }
+    // Requests that had gone out when the connection dropped may or may not have reached the broker
+    pub fn on_disconnect(&mut self, sent_requests: &[String]) {
+        let mut changed = false;
+        for submission in &mut self.submissions {
+            if submission.state == SubmissionState::Queued
+                && sent_requests.contains(&submission.request)
+            {
+                submission.state = SubmissionState::AwaitingLookup;
+                changed = true;
+            }
+        }
+        if changed {
+            self.changed();
+        }
+    }
+    // Finds the orders awaiting lookup among the synced commands by client order id and brings
+    // every known order up to date. The synced commands may lag behind, so orders not found there
+    // are left to a command/list lookup. Returns lines to log and whether a lookup is needed.
+    pub fn reconcile(&mut self, user_data: &UserData) -> (Vec<String>, bool) {
+        let mut log = Vec::new();
+        let mut lookup = false;
+        for submission in &mut self.submissions {
+            if submission.state == SubmissionState::AwaitingLookup {
+                match order_id_of(&user_data.commands, &submission.cl_ord_id) {
+                    Some(order_id) => {
+                        submission.order_id = Some(order_id);
+                        submission.state = SubmissionState::Acknowledged;
+                        log.push(format!(
+                            "Order {} found as {}",
+                            submission.cl_ord_id, order_id
+                        ));
+                    }
+                    None => {
+                        submission.state = SubmissionState::LookingUp;
+                        lookup = true;
+                    }
+                }
+            }
+        }
+        self.update_states(&user_data.orders);
+        (log, lookup)
+    }
+    // Settles the orders being looked up against the command/list response. Orders not found are
+    // returned to be sent again when they are recent enough, otherwise they are marked lost.
+    // Returns lines to log and the client order ids to send again.
+    pub fn resolve_lookup(
+        &mut self,
+        commands: &[Value],
+        now_millis: i64,
+    ) -> (Vec<String>, Vec<String>) {
+        let mut log = Vec::new();
+        let mut resubmit = Vec::new();
+        for submission in &mut self.submissions {
+            if submission.state != SubmissionState::LookingUp {
+                continue;
+            }
+            match order_id_of(commands, &submission.cl_ord_id) {
+                Some(order_id) => {
+                    submission.order_id = Some(order_id);
+                    submission.state = SubmissionState::Acknowledged;
+                    log.push(format!(
+                        "Order {} found as {}",
+                        submission.cl_ord_id, order_id
+                    ));
+                }
+                None if now_millis - submission.submitted_at < ORDER_RESUBMIT_MAX_AGE_MILLIS => {
+                    submission.state = SubmissionState::Queued;
+                    resubmit.push(submission.cl_ord_id.clone());
+                    log.push(format!(
+                        "Order {} never arrived, sending it again",
+                        submission.cl_ord_id
+                    ));
+                }
+                None => {
+                    submission.state = SubmissionState::Lost;
+                    log.push(format!(
+                        "Order {} never arrived and is too old to send again",
+                        submission.cl_ord_id
+                    ));
+                }
+            }
+        }
+        self.changed();
+        (log, resubmit)
+    }
+    // A failed lookup leaves the orders to the lookup of the next sync
+    pub fn lookup_failed(&mut self) {
+        for submission in &mut self.submissions {
+            if submission.state == SubmissionState::LookingUp {
+                submission.state = SubmissionState::AwaitingLookup;
+            }
+        }
+        self.changed();
+    }
+    pub fn update_states(&mut self, orders: &[Value]) {
+        let mut changed = false;
+        for submission in &mut self.submissions {
+            if let Some(order_id) = submission.order_id {
+                if let Some(state) = orders
+                    .iter()
+                    .find(|order| order["id"].as_i64() == Some(order_id))
+                    .and_then(|order| order["ordStatus"].as_str())
+                    .and_then(SubmissionState::from_ord_status)
+                {
+                    if submission.state != state {
+                        submission.state = state;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if changed {
+            self.changed();
+        }
+    }
+    // Gives a requeued submission the request id it is sent with this time
+    pub fn renumber(&mut self, cl_ord_id: &str, request_id: i64) -> Option<String> {
+        let submission = self
+            .submissions
+            .iter_mut()
+            .find(|submission| submission.cl_ord_id == cl_ord_id)?;
+        submission.request = with_request_id(&submission.request, request_id);
+        submission.request_id = request_id;
+        let request = submission.request.clone();
+        self.changed();
+        Some(request)
+    }
+}
+
+fn order_id_of(commands: &[Value], cl_ord_id: &str) -> Option<i64> {
+    commands
+        .iter()
+        .find(|command| command["clOrdId"].as_str() == Some(cl_ord_id))
+        .and_then(|command| command["orderId"].as_i64())
+}
+
+// Writes the journal every ORDER_JOURNAL_SAVE_MILLIS when it changed. The file is written from
+// a copy, after the channel lock is released. A failure is logged once until saving works again.
+pub async fn keep_journal_saved(acct_comms_channel: AcctCommChannelRWL, report_rwl: ReportRWL) {
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_millis(ORDER_JOURNAL_SAVE_MILLIS));
+    let mut saved_revision = 0;
+    let mut last_error: Option<String> = None;
+    loop {
+        interval.tick().await;
+        let open = acct_comms_channel.read().await;
+        if open.journal.revision() == saved_revision {
+            continue;
+        }
+        let journal = open.journal.clone();
+        drop(open);
+        let error = create_json_file_atomically(ORDER_JOURNAL_FILENAME, &journal)
+            .err()
+            .map(|e| format!("Could not save the order journal: {}", e));
+        if error.is_none() {
+            saved_revision = journal.revision();
+        }
+        if error != last_error {
+            if let Some(error) = &error {
+                program_log(error, ResourceType::Trading, report_rwl.clone()).await;
+            }
+            last_error = error;
+        }
+    }
+}
+
+// "endpoint\nid\n\nbody" with another id
+fn with_request_id(request: &str, request_id: i64) -> String {
+    let mut parts = request.splitn(3, '\n');
+    let endpoint = parts.next().unwrap_or_default();
+    let _ = parts.next();
+    let rest = parts.next().unwrap_or_default();
+    format!("{}\n{}\n{}", endpoint, request_id, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn submission(cl_ord_id: &str, request_id: i64, submitted_at: i64) -> Submission {
+        Submission {
+            cl_ord_id: cl_ord_id.to_string(),
+            intent: OrderIntent::market(1, "ESZ6", OrderAction::Buy, 1),
+            request_id,
+            request: format!("order/placeorder\n{}\n\n{{}}", request_id),
+            state: SubmissionState::AwaitingLookup,
+            order_id: None,
+            submitted_at,
+        }
+    }
+
+    #[test]
+    fn request_ids_keep_counting_after_the_journal_is_saved_and_read_back() {
+        let mut journal = OrderJournal::default();
+        assert_eq!(journal.reserve_request_ids(1), FIRST_ORDER_REQUEST_ID);
+        assert_eq!(journal.reserve_request_ids(2), FIRST_ORDER_REQUEST_ID + 1);
+        let saved = serde_json::to_value(&journal).unwrap();
+        let mut restored: OrderJournal = serde_json::from_value(saved).unwrap();
+        assert_eq!(restored.reserve_request_ids(1), FIRST_ORDER_REQUEST_ID + 3);
+    }
+
+    #[test]
+    fn changes_move_the_revision() {
+        let mut journal = OrderJournal::default();
+        let revision = journal.revision();
+        journal.record(submission("a", 1000, 0));
+        assert!(journal.revision() > revision);
+        let revision = journal.revision();
+        journal.update_states(&[]);
+        assert_eq!(journal.revision(), revision);
+    }
+
+    #[test]
+    fn orders_missing_from_the_sync_are_looked_up_before_being_sent_again() {
+        let now = 100_000;
+        let mut journal = OrderJournal::default();
+        journal.record(submission("synced", 1000, now));
+        journal.record(submission("listed", 1001, now));
+        journal.record(submission("missing", 1002, now));
+        journal.record(submission("old", 1003, now - ORDER_RESUBMIT_MAX_AGE_MILLIS));
+        let user_data = UserData {
+            commands: vec![json!({ "clOrdId": "synced", "orderId": 7 })],
+            ..Default::default()
+        };
+        let (_, lookup) = journal.reconcile(&user_data);
+        assert!(lookup);
+        assert_eq!(journal.order_id("synced"), Some(7));
+        assert_eq!(journal.get("missing").unwrap().state, SubmissionState::LookingUp);
+
+        let (_, resubmit) =
+            journal.resolve_lookup(&[json!({ "clOrdId": "listed", "orderId": 8 })], now);
+        assert_eq!(resubmit, vec!["missing".to_string()]);
+        assert_eq!(journal.order_id("listed"), Some(8));
+        assert_eq!(journal.get("missing").unwrap().state, SubmissionState::Queued);
+        assert_eq!(journal.get("old").unwrap().state, SubmissionState::Lost);
+    }
+
+    #[test]
+    fn journals_saved_against_another_server_are_ignored() {
+        let mut demo = OrderJournal {
+            environment: "demo".to_string(),
+            ..OrderJournal::default()
+        };
+        demo.record(submission("demo", 1000, 0));
+        let mut fallback = OrderJournal::default();
+        fallback.record(submission("snapshot", 1001, 0));
+        let journal = OrderJournal::restore(Some(demo.clone()), fallback.clone(), "live", 0);
+        assert_eq!(journal.environment, "live");
+        assert!(journal.get("demo").is_none());
+        assert!(journal.get("snapshot").is_some());
+        let journal = OrderJournal::restore(Some(demo), fallback, "demo", 0);
+        assert!(journal.get("demo").is_some());
+    }
+
+    #[test]
+    fn client_order_ids_are_prefixed_with_the_wall_time() {
+        let started = chrono::Utc::now().timestamp_millis();
+        // a replay clock far in the past
+        let mut journal = OrderJournal::restore(None, OrderJournal::default(), "replay", 1_000);
+        let cl_ord_id = journal.next_cl_ord_id();
+        let (prefix, seq) = cl_ord_id.split_once('-').unwrap();
+        assert!(prefix.parse::<i64>().unwrap() >= started);
+        assert_eq!(seq, "1");
+    }
+
+    #[test]
+    fn a_failed_lookup_waits_for_the_next_sync() {
+        let mut journal = OrderJournal::default();
+        journal.record(submission("missing", 1000, 0));
+        let (_, lookup) = journal.reconcile(&UserData::default());
+        assert!(lookup);
+        journal.lookup_failed();
+        assert_eq!(journal.get("missing").unwrap().state, SubmissionState::AwaitingLookup);
+    }
+}
@@ -0,0 +1,346 @@
+use crate::{
+    accounting::Ledger,
+    models::{UserData, UserDataMessage},
+    price::PriceSpec,
+    settings::{MAX_CONTRACTS, MAX_RISK_PER_TRADE, SIZING_MODEL, STOP_LOSS_TICKS},
+    socket_processing::MarketData,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizingModel {
+    FixedContracts(i64),
+    // risks this many dollars per trade over the stop distance
+    FixedRisk { dollars: f64 },
+    // risks this percent of the cash balance per trade over the stop distance
+    PercentOfEquity { percent: f64 },
+    // risks this many dollars against a move of atr_multiple ATRs
+    VolatilityTarget { dollars: f64, atr_multiple: f64 },
+}
+
+// What kept the size below what the model asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeLimit {
+    // no cash balance, no ATR or no margin per contract yet
+    MissingData,
+    MaxContracts,
+    MaxRisk,
+    Margin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sizing {
+    pub qty: i64,
+    pub limited_by: Option<SizeLimit>,
+    // the free margin is known but not what a contract takes out of it, so it was not capped
+    pub margin_unchecked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SizingInputs {
+    pub spec: PriceSpec,
+    pub stop_ticks: i64,
+    pub equity: Option<f64>,
+    // margin not already used by positions and working orders
+    pub available_margin: Option<f64>,
+    pub margin_per_contract: Option<f64>,
+    // in points
+    pub atr: Option<f64>,
+}
+impl SizingInputs {
+    pub fn stop_risk_per_contract(&self) -> f64 {
+        self.stop_ticks as f64 * self.spec.tick_value()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionSizer {
+    pub model: SizingModel,
+    pub max_contracts: i64,
+    pub max_risk_per_trade: f64,
+}
+impl PositionSizer {
+    pub fn from_settings() -> Self {
+        Self {
+            model: SIZING_MODEL,
+            max_contracts: MAX_CONTRACTS,
+            max_risk_per_trade: MAX_RISK_PER_TRADE,
+        }
+    }
+    pub fn size(&self, inputs: &SizingInputs) -> Sizing {
+        let stop_risk = inputs.stop_risk_per_contract();
+        let wanted = match self.model {
+            SizingModel::FixedContracts(qty) => Some(qty),
+            SizingModel::FixedRisk { dollars } => contracts_for(dollars, stop_risk),
+            SizingModel::PercentOfEquity { percent } => inputs
+                .equity
+                .and_then(|equity| contracts_for(equity * percent / 100.0, stop_risk)),
+            SizingModel::VolatilityTarget {
+                dollars,
+                atr_multiple,
+            } => inputs.atr.and_then(|atr| {
+                contracts_for(dollars, atr * atr_multiple * inputs.spec.value_per_point)
+            }),
+        };
+        let mut sizing = match wanted {
+            Some(qty) => Sizing {
+                qty: qty.max(0),
+                limited_by: None,
+                margin_unchecked: false,
+            },
+            None => {
+                return Sizing {
+                    qty: 0,
+                    limited_by: Some(SizeLimit::MissingData),
+                    margin_unchecked: false,
+                }
+            }
+        };
+        sizing.cap(self.max_contracts, SizeLimit::MaxContracts);
+        if let Some(qty) = contracts_for(self.max_risk_per_trade, stop_risk) {
+            sizing.cap(qty, SizeLimit::MaxRisk);
+        }
+        if let Some(available) = inputs.available_margin {
+            match inputs.margin_per_contract {
+                Some(per_contract) => {
+                    if let Some(qty) = contracts_for(available, per_contract) {
+                        sizing.cap(qty, SizeLimit::Margin);
+                    }
+                }
+                None => sizing.margin_unchecked = true,
+            }
+        }
+        sizing
+    }
+}
+impl Sizing {
+    fn cap(&mut self, limit: i64, reason: SizeLimit) {
+        if self.qty > limit {
+            self.qty = limit.max(0);
+            self.limited_by = Some(reason);
+        }
+    }
+}
+
+// Whole contracts that fit in budget, None when a contract costs nothing to hold
+fn contracts_for(budget: f64, per_contract: f64) -> Option<i64> {
+    if per_contract <= 0.0 || !per_contract.is_finite() {
+        return None;
+    }
+    Some((budget / per_contract).floor().max(0.0) as i64)
+}
+
+// Initial margin of one contract from the catalog's exchange margins, otherwise from the margin
+// snapshot as the margin the account's open contracts take each
+pub fn margin_per_contract(
+    ledger: &Ledger,
+    user_data: Option<&UserData>,
+    account_id: Option<i64>,
+    contract_id: i64,
+) -> Option<f64> {
+    if let Some(margin) = ledger
+        .terms
+        .get(&contract_id)
+        .and_then(|terms| terms.initial_margin)
+    {
+        return Some(margin);
+    }
+    let (user_data, account_id) = (user_data?, account_id?);
+    let open_contracts: i64 = user_data
+        .open_positions(account_id)
+        .map(|position| position.net_pos.abs())
+        .sum();
+    let snapshot = user_data
+        .margin_snapshots
+        .iter()
+        .find(|snapshot| snapshot.id == account_id)?;
+    if open_contracts > 0 && snapshot.initial_margin > 0.0 {
+        Some(snapshot.initial_margin / open_contracts as f64)
+    } else {
+        None
+    }
+}
+
+// The entry size for symbol from the account's cash balance and margin snapshot
+pub fn entry_size(
+    market_data: &MarketData,
+    ledger: &Ledger,
+    user_data: Option<&UserDataMessage>,
+    account_name: &str,
+    symbol: &str,
+) -> Sizing {
+    let contract = match market_data.contract(symbol) {
+        Some(contract) => contract,
+        None => {
+            return Sizing {
+                qty: 0,
+                limited_by: Some(SizeLimit::MissingData),
+                margin_unchecked: false,
+            }
+        }
+    };
+    let account = user_data.and_then(|user_data| {
+        let account = user_data.data.account(account_name)?;
+        let equity = user_data
+            .data
+            .cash_balances
+            .iter()
+            .find(|balance| balance.account_id == account.id)
+            .map(|balance| balance.amount);
+        let used_margin = user_data
+            .data
+            .margin_snapshots
+            .iter()
+            .find(|snapshot| snapshot.id == account.id)
+            .map(|snapshot| snapshot.total_used_margin);
+        Some((equity, used_margin))
+    });
+    let (equity, used_margin) = account.unwrap_or((None, None));
+    let account_id = user_data
+        .and_then(|user_data| user_data.data.account(account_name))
+        .map(|account| account.id);
+    let inputs = SizingInputs {
+        spec: PriceSpec::from_contract(contract),
+        stop_ticks: STOP_LOSS_TICKS,
+        equity,
+        available_margin: equity.zip(used_margin).map(|(equity, used)| equity - used),
+        margin_per_contract: margin_per_contract(
+            ledger,
+            user_data.map(|user_data| &user_data.data),
+            account_id,
+            contract.id,
+        ),
+        atr: market_data
+            .indicators
+            .get(&contract.id)
+            .and_then(|indicators| indicators.atr.value()),
+    };
+    PositionSizer::from_settings().size(&inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ES with an 8 tick stop, 100 dollars of risk per contract
+    fn inputs() -> SizingInputs {
+        SizingInputs {
+            spec: PriceSpec::new(0.25, 50.0),
+            stop_ticks: 8,
+            equity: Some(50_000.0),
+            available_margin: None,
+            margin_per_contract: None,
+            atr: Some(4.0),
+        }
+    }
+
+    fn sizer(model: SizingModel) -> PositionSizer {
+        PositionSizer {
+            model,
+            max_contracts: 10,
+            max_risk_per_trade: 1_000.0,
+        }
+    }
+
+    fn sized(qty: i64, limited_by: Option<SizeLimit>) -> Sizing {
+        Sizing {
+            qty,
+            limited_by,
+            margin_unchecked: false,
+        }
+    }
+
+    #[test]
+    fn fixed_contracts_ignores_the_account() {
+        let sizer = sizer(SizingModel::FixedContracts(3));
+        assert_eq!(sizer.size(&inputs()), sized(3, None));
+        let inputs = SizingInputs {
+            equity: None,
+            atr: None,
+            ..inputs()
+        };
+        assert_eq!(sizer.size(&inputs), sized(3, None));
+    }
+
+    #[test]
+    fn fixed_risk_divides_the_dollars_by_the_stop_risk() {
+        let sizer = sizer(SizingModel::FixedRisk { dollars: 250.0 });
+        assert_eq!(inputs().stop_risk_per_contract(), 100.0);
+        assert_eq!(sizer.size(&inputs()), sized(2, None));
+    }
+
+    #[test]
+    fn percent_of_equity_risks_a_share_of_the_cash_balance() {
+        let sizer = sizer(SizingModel::PercentOfEquity { percent: 1.0 });
+        assert_eq!(sizer.size(&inputs()), sized(5, None));
+        let inputs = SizingInputs {
+            equity: None,
+            ..inputs()
+        };
+        assert_eq!(sizer.size(&inputs), sized(0, Some(SizeLimit::MissingData)));
+    }
+
+    #[test]
+    fn volatility_target_risks_the_dollars_over_a_multiple_of_the_atr() {
+        let sizer = sizer(SizingModel::VolatilityTarget {
+            dollars: 1_000.0,
+            atr_multiple: 2.0,
+        });
+        // 4 points times 2 is 400 dollars a contract
+        assert_eq!(sizer.size(&inputs()), sized(2, None));
+        let inputs = SizingInputs {
+            atr: None,
+            ..inputs()
+        };
+        assert_eq!(sizer.size(&inputs), sized(0, Some(SizeLimit::MissingData)));
+    }
+
+    #[test]
+    fn max_contracts_caps_the_size() {
+        let sizer = sizer(SizingModel::FixedContracts(20));
+        assert_eq!(sizer.size(&inputs()), sized(10, Some(SizeLimit::MaxContracts)));
+    }
+
+    #[test]
+    fn max_risk_caps_the_size() {
+        let sizer = PositionSizer {
+            max_risk_per_trade: 500.0,
+            ..sizer(SizingModel::FixedContracts(9))
+        };
+        assert_eq!(sizer.size(&inputs()), sized(5, Some(SizeLimit::MaxRisk)));
+    }
+
+    #[test]
+    fn free_margin_caps_the_size() {
+        let sizer = sizer(SizingModel::FixedContracts(5));
+        let inputs = SizingInputs {
+            available_margin: Some(12_000.0),
+            margin_per_contract: Some(5_000.0),
+            ..inputs()
+        };
+        assert_eq!(sizer.size(&inputs), sized(2, Some(SizeLimit::Margin)));
+        let inputs = SizingInputs {
+            available_margin: Some(-1_000.0),
+            ..inputs
+        };
+        assert_eq!(sizer.size(&inputs), sized(0, Some(SizeLimit::Margin)));
+    }
+
+    #[test]
+    fn unknown_margin_per_contract_skips_the_margin_cap() {
+        let sizer = sizer(SizingModel::FixedContracts(5));
+        let unknown_margin = SizingInputs {
+            available_margin: Some(12_000.0),
+            ..inputs()
+        };
+        assert_eq!(
+            sizer.size(&unknown_margin),
+            Sizing {
+                qty: 5,
+                limited_by: None,
+                margin_unchecked: true,
+            }
+        );
+        // without the free margin there is nothing to check
+        assert_eq!(sizer.size(&inputs()), sized(5, None));
+    }
+}
@@ -0,0 +1,307 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    instrument_catalog::Instrument,
+    models::{ContractID, ProductInfo},
+    time_and_sales::OrderAction,
+};
+
+// Keeps values like 4512.2499999 on the right side of floor and ceil
+const TICK_EPSILON: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PriceFormat {
+    Decimal { decimals: usize },
+    // 32nds for bonds, "131'16" is 131 and 16/32
+    Fractional { denominator: i64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Nearest,
+    Down,
+    Up,
+}
+impl Rounding {
+    // Never pays more than asked, buy limits round down and sell limits round up
+    pub fn passive(action: OrderAction) -> Self {
+        match action {
+            OrderAction::Buy => Rounding::Down,
+            OrderAction::Sell => Rounding::Up,
+            OrderAction::Unknown => Rounding::Nearest,
+        }
+    }
+    pub fn aggressive(action: OrderAction) -> Self {
+        match action {
+            OrderAction::Buy => Rounding::Up,
+            OrderAction::Sell => Rounding::Down,
+            OrderAction::Unknown => Rounding::Nearest,
+        }
+    }
+}
+
+// How a contract's prices move and what a move is worth
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PriceSpec {
+    pub tick_size: f64,
+    pub value_per_point: f64,
+    pub format: PriceFormat,
+}
+impl PriceSpec {
+    pub fn new(tick_size: f64, value_per_point: f64) -> Self {
+        Self {
+            tick_size,
+            value_per_point,
+            format: PriceFormat::Decimal {
+                decimals: decimals_of(tick_size),
+            },
+        }
+    }
+    // Decimal formats give the number of decimals as a negative number, -2 is 4512.25.
+    // Fractional formats give the denominator as a negative power of two, -5 is 32nds.
+    pub fn from_product(product: &ProductInfo) -> Self {
+        let format = match product.price_format_type.as_str() {
+            "Fractional" => PriceFormat::Fractional {
+                denominator: 2_i64.pow(product.price_format.unsigned_abs() as u32),
+            },
+            _ => PriceFormat::Decimal {
+                decimals: (product.price_format.unsigned_abs() as usize)
+                    .max(decimals_of(product.tick_size)),
+            },
+        };
+        Self {
+            tick_size: product.tick_size,
+            value_per_point: product.value_per_point,
+            format,
+        }
+    }
+    pub fn from_instrument(instrument: &Instrument) -> Self {
+        Self {
+            tick_size: instrument.tick_size(),
+            ..Self::from_product(&instrument.product)
+        }
+    }
+    pub fn from_contract(contract: &ContractID) -> Self {
+        Self::new(contract.provider_tick_size, contract.big_point_value)
+    }
+    pub fn tick_value(&self) -> f64 {
+        self.tick_size * self.value_per_point
+    }
+    pub fn price(&self, raw: f64, rounding: Rounding) -> Price {
+        let ticks = raw / self.tick_size;
+        let ticks = match rounding {
+            Rounding::Nearest => ticks.round(),
+            Rounding::Down => (ticks + TICK_EPSILON).floor(),
+            Rounding::Up => (ticks - TICK_EPSILON).ceil(),
+        };
+        self.at_ticks(ticks as i64)
+    }
+    pub fn at_ticks(&self, ticks: i64) -> Price {
+        Price { ticks, spec: *self }
+    }
+    // Tick count between two raw prices, positive when to is above from
+    pub fn ticks_between(&self, from: f64, to: f64) -> i64 {
+        self.price(to, Rounding::Nearest) - self.price(from, Rounding::Nearest)
+    }
+}
+
+// A price as a whole number of ticks, so arithmetic on it never drifts
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Price {
+    pub ticks: i64,
+    pub spec: PriceSpec,
+}
+impl Price {
+    pub fn to_f64(self) -> f64 {
+        let value = self.ticks as f64 * self.spec.tick_size;
+        let scale = 10_f64.powi(decimals_of(self.spec.tick_size) as i32);
+        (value * scale).round() / scale
+    }
+    pub fn add_ticks(&self, ticks: i64) -> Self {
+        self.spec.at_ticks(self.ticks + ticks)
+    }
+    pub fn points_to(&self, other: &Price) -> f64 {
+        (other.ticks - self.ticks) as f64 * self.spec.tick_size
+    }
+    // What qty contracts make moving from self to other
+    pub fn dollars_to(&self, other: &Price, qty: i64) -> f64 {
+        (other.ticks - self.ticks) as f64 * self.spec.tick_value() * qty as f64
+    }
+    pub fn format(&self) -> String {
+        let value = self.to_f64();
+        match self.spec.format {
+            PriceFormat::Decimal { decimals } => format!("{:.*}", decimals, value),
+            PriceFormat::Fractional { denominator } => {
+                let sign = if value < 0.0 { "-" } else { "" };
+                let value = value.abs();
+                let whole = (value + TICK_EPSILON).floor();
+                let parts = (value - whole) * denominator as f64;
+                let part = (parts + TICK_EPSILON).floor();
+                // a quarter, half or three quarters of a 32nd is shown as a trailing 2, 5 or 7
+                let quarter = match (((parts - part) * 4.0).round() as i64).clamp(0, 3) {
+                    1 => "2",
+                    2 => "5",
+                    3 => "7",
+                    _ if self.spec.tick_size * (denominator as f64) < 1.0 - TICK_EPSILON => "0",
+                    _ => "",
+                };
+                format!("{}{}'{:02}{}", sign, whole as i64, part as i64, quarter)
+            }
+        }
+    }
+}
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.ticks == other.ticks
+    }
+}
+impl Eq for Price {}
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ticks.cmp(&other.ticks)
+    }
+}
+impl std::ops::Add<i64> for Price {
+    type Output = Price;
+    fn add(self, ticks: i64) -> Price {
+        self.add_ticks(ticks)
+    }
+}
+impl std::ops::Sub<i64> for Price {
+    type Output = Price;
+    fn sub(self, ticks: i64) -> Price {
+        self.add_ticks(-ticks)
+    }
+}
+// The difference of two prices in ticks
+impl std::ops::Sub<Price> for Price {
+    type Output = i64;
+    fn sub(self, other: Price) -> i64 {
+        self.ticks - other.ticks
+    }
+}
+impl std::fmt::Display for Price {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format())
+    }
+}
+
+// 0.25 -> 2, 0.03125 -> 5
+fn decimals_of(tick_size: f64) -> usize {
+    (0..10)
+        .find(|decimals| {
+            let scaled = tick_size * 10_f64.powi(*decimals as i32);
+            (scaled - scaled.round()).abs() < TICK_EPSILON
+        })
+        .unwrap_or(10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn es() -> PriceSpec {
+        PriceSpec::new(0.25, 50.0)
+    }
+
+    fn product(price_format_type: &str, price_format: i64, tick_size: f64) -> ProductInfo {
+        ProductInfo {
+            price_format_type: price_format_type.to_string(),
+            price_format,
+            tick_size,
+            value_per_point: 1000.0,
+            ..ProductInfo::default()
+        }
+    }
+
+    #[test]
+    fn raw_prices_round_to_whole_ticks() {
+        let es = es();
+        assert_eq!(es.price(4512.30, Rounding::Nearest).ticks, 18049);
+        assert_eq!(es.price(4512.30, Rounding::Down).to_f64(), 4512.25);
+        assert_eq!(es.price(4512.30, Rounding::Up).to_f64(), 4512.5);
+        assert_eq!(es.price(4512.40, Rounding::Nearest).to_f64(), 4512.5);
+        // a price on a tick stays there whichever way it rounds
+        assert_eq!(es.price(4512.25, Rounding::Down).to_f64(), 4512.25);
+        assert_eq!(es.price(4512.25, Rounding::Up).to_f64(), 4512.25);
+        assert_eq!(es.price(4512.2499999, Rounding::Down).to_f64(), 4512.25);
+        assert_eq!(es.price(4512.2500001, Rounding::Up).to_f64(), 4512.25);
+    }
+
+    #[test]
+    fn limits_round_away_from_or_toward_the_market() {
+        assert_eq!(Rounding::passive(OrderAction::Buy), Rounding::Down);
+        assert_eq!(Rounding::passive(OrderAction::Sell), Rounding::Up);
+        assert_eq!(Rounding::aggressive(OrderAction::Buy), Rounding::Up);
+        assert_eq!(Rounding::aggressive(OrderAction::Sell), Rounding::Down);
+        assert_eq!(Rounding::passive(OrderAction::Unknown), Rounding::Nearest);
+    }
+
+    #[test]
+    fn tick_arithmetic_never_drifts() {
+        let es = es();
+        let price = es.price(4512.25, Rounding::Nearest);
+        assert_eq!((price + 3).to_f64(), 4513.0);
+        assert_eq!((price - 5).to_f64(), 4511.0);
+        assert_eq!((price + 3) - price, 3);
+        assert!(price + 1 > price);
+        assert_eq!(es.ticks_between(4500.0, 4512.25), 49);
+        assert_eq!(es.ticks_between(4512.25, 4500.0), -49);
+        let cl = PriceSpec::new(0.01, 1000.0);
+        let mut price = cl.price(80.0, Rounding::Nearest);
+        for _ in 0..123 {
+            price = price + 1;
+        }
+        assert_eq!(price.to_f64(), 81.23);
+    }
+
+    #[test]
+    fn moves_convert_to_points_and_dollars() {
+        let es = es();
+        assert_eq!(es.tick_value(), 12.5);
+        assert_eq!(PriceSpec::new(0.25, 5.0).tick_value(), 1.25);
+        let entry = es.price(4500.0, Rounding::Nearest);
+        let exit = es.price(4510.0, Rounding::Nearest);
+        assert_eq!(entry.points_to(&exit), 10.0);
+        assert_eq!(entry.dollars_to(&exit, 2), 1000.0);
+        assert_eq!(exit.dollars_to(&entry, 2), -1000.0);
+        assert_eq!(entry.dollars_to(&(entry + 1), 1), 12.5);
+    }
+
+    #[test]
+    fn decimal_prices_show_the_product_decimals() {
+        assert_eq!(es().price(4512.5, Rounding::Nearest).format(), "4512.50");
+        assert_eq!(PriceSpec::new(0.01, 1000.0).at_ticks(8123).format(), "81.23");
+        let spec = PriceSpec::from_product(&product("Decimal", -2, 0.25));
+        assert_eq!(spec.format, PriceFormat::Decimal { decimals: 2 });
+        // never fewer decimals than the tick size needs
+        let spec = PriceSpec::from_product(&product("Decimal", 0, 0.005));
+        assert_eq!(spec.format, PriceFormat::Decimal { decimals: 3 });
+        assert_eq!(spec.price(1.2345, Rounding::Down).to_string(), "1.230");
+    }
+
+    #[test]
+    fn fractional_prices_show_32nds() {
+        // ZB ticks in whole 32nds
+        let zb = PriceSpec::from_product(&product("Fractional", -5, 0.03125));
+        assert_eq!(zb.format, PriceFormat::Fractional { denominator: 32 });
+        assert_eq!(zb.price(131.5, Rounding::Nearest).format(), "131'16");
+        assert_eq!(zb.price(131.03125, Rounding::Nearest).format(), "131'01");
+        assert_eq!(zb.price(-0.5, Rounding::Nearest).format(), "-0'16");
+        // ZN ticks in half 32nds
+        let zn = PriceSpec::from_product(&product("Fractional", -5, 0.015625));
+        assert_eq!(zn.price(110.5, Rounding::Nearest).format(), "110'160");
+        assert_eq!(zn.price(110.515625, Rounding::Nearest).format(), "110'165");
+        // ZF ticks in quarter 32nds
+        let zf = PriceSpec::from_product(&product("Fractional", -5, 0.0078125));
+        assert_eq!(zf.price(108.2578125, Rounding::Nearest).format(), "108'082");
+        assert_eq!(zf.price(108.2734375, Rounding::Nearest).format(), "108'087");
+    }
+}
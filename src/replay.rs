@@ -0,0 +1,187 @@
+use serde_json::{json, Value};
+
+use crate::{
+    md_socket::RequestStatus,
+    settings::{REPLAY_INITIAL_BALANCE, REPLAY_SPEED, REPLAY_START},
+};
+
+// Replay requests share the md socket with subscriptions, so they get an id no subscription reaches
+pub const REPLAY_REQUEST_ID: i64 = 900;
+
+// Speed is a percentage of real time, 100 plays the session back at its original pace
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplaySettings {
+    pub start_timestamp: i64,
+    pub speed: i64,
+    pub initial_balance: f64,
+    // --replay-speed, applied with replay/changeSpeed once the clock is running
+    pub requested_speed: Option<i64>,
+}
+impl ReplaySettings {
+    // Starts from the settings, overridden by --replay-start <rfc3339> and --replay-speed <percent>
+    pub fn from_args() -> Result<Self, String> {
+        let args: Vec<String> = std::env::args().collect();
+        let value_of = |flag: &str| {
+            args.iter()
+                .position(|arg| arg == flag)
+                .and_then(|n| args.get(n + 1))
+                .cloned()
+        };
+        let start = value_of("--replay-start").unwrap_or_else(|| REPLAY_START.to_string());
+        let start_timestamp = chrono::DateTime::parse_from_rfc3339(&start)
+            .map_err(|e| format!("invalid replay start {}: {}", start, e))?
+            .timestamp_millis();
+        let requested_speed = match value_of("--replay-speed") {
+            Some(speed) => Some(
+                speed
+                    .parse::<i64>()
+                    .map_err(|e| format!("invalid replay speed {}: {}", speed, e))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            start_timestamp,
+            speed: REPLAY_SPEED,
+            initial_balance: REPLAY_INITIAL_BALANCE,
+            requested_speed,
+        })
+    }
+}
+
+// Each socket connected to the replay server runs its own replay session, so the md and the
+// trading socket both keep one. The clock is initialized before anything else is sent, and again
+// from the last clock event after a reconnect so the replay resumes where it stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplaySession {
+    pub settings: ReplaySettings,
+    pub status: RequestStatus,
+    pub clock_initialized: bool,
+}
+impl ReplaySession {
+    pub fn new(settings: ReplaySettings) -> Self {
+        Self {
+            settings,
+            status: RequestStatus::Unsent,
+            clock_initialized: false,
+        }
+    }
+    pub fn initialize_clock(&self) -> String {
+        let body = json!({
+            "startTimestamp": to_rfc3339(self.settings.start_timestamp),
+            "speed": self.settings.speed,
+            "initialBalance": self.settings.initial_balance,
+        });
+        format!("replay/initializeClock\n{}\n\n{}", REPLAY_REQUEST_ID, body)
+    }
+    pub fn change_speed(&mut self, speed: i64) -> String {
+        self.settings.speed = speed;
+        format!(
+            "replay/changeSpeed\n{}\n\n{}",
+            REPLAY_REQUEST_ID + 1,
+            json!({ "speed": speed })
+        )
+    }
+    // The response to initializeClock, only expected while the request is out
+    pub fn is_awaiting_clock(&self) -> bool {
+        self.status == RequestStatus::Sent && !self.clock_initialized
+    }
+    // The speed still to be applied once the clock runs. Taken once, a reconnect initializes
+    // the clock at the speed it was changed to.
+    pub fn take_requested_speed(&mut self) -> Option<i64> {
+        let requested = self.settings.requested_speed.take()?;
+        (requested != self.settings.speed).then_some(requested)
+    }
+    pub fn is_ready(&self) -> bool {
+        self.clock_initialized
+    }
+    pub fn reset_session(&mut self) {
+        self.status = RequestStatus::Unsent;
+        self.clock_initialized = false;
+    }
+    pub fn record_clock(&mut self, clock: &ReplayClock) {
+        self.settings.start_timestamp = clock.server_time;
+        self.settings.speed = clock.speed;
+    }
+}
+
+// Server time from the last clock event, moved forward at the replay speed in between events
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReplayClock {
+    pub server_time: i64,
+    pub speed: i64,
+    pub received_at: tokio::time::Instant,
+}
+impl ReplayClock {
+    // Clock events carry their payload as a json string: {"t":"2022-03-14T13:30:00.000Z","s":100}
+    pub fn from_event(data: &Value) -> Option<Self> {
+        let payload: Value = match data {
+            Value::String(payload) => serde_json::from_str(payload).ok()?,
+            payload => payload.clone(),
+        };
+        let server_time = chrono::DateTime::parse_from_rfc3339(payload["t"].as_str()?)
+            .ok()?
+            .timestamp_millis();
+        Some(Self {
+            server_time,
+            speed: payload["s"].as_i64().unwrap_or(100),
+            received_at: tokio::time::Instant::now(),
+        })
+    }
+    pub fn now_millis(&self) -> i64 {
+        self.server_time + self.received_at.elapsed().as_millis() as i64 * self.speed / 100
+    }
+}
+
+fn to_rfc3339(timestamp: i64) -> String {
+    use chrono::TimeZone;
+    chrono::Utc.timestamp_millis(timestamp).to_rfc3339()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(requested_speed: Option<i64>) -> ReplaySettings {
+        ReplaySettings {
+            start_timestamp: 0,
+            speed: REPLAY_SPEED,
+            initial_balance: REPLAY_INITIAL_BALANCE,
+            requested_speed,
+        }
+    }
+
+    #[test]
+    fn requested_speed_is_applied_once() {
+        let mut session = ReplaySession::new(settings(Some(400)));
+        assert_eq!(session.take_requested_speed(), Some(400));
+        session.change_speed(400);
+        assert_eq!(session.take_requested_speed(), None);
+        assert!(session.initialize_clock().contains("\"speed\":400"));
+        let mut unchanged = ReplaySession::new(settings(Some(REPLAY_SPEED)));
+        assert_eq!(unchanged.take_requested_speed(), None);
+    }
+
+    #[test]
+    fn speed_changes_wait_for_the_clock() {
+        let mut outgoing = crate::md_socket::MarketDataOutgoing::new(Vec::new());
+        outgoing.replay = Some(ReplaySession::new(settings(Some(400))));
+        outgoing.replay_clock_initialized(false);
+        assert!(outgoing.outgoing_messages.is_empty());
+        outgoing.replay_clock_initialized(true);
+        assert_eq!(outgoing.outgoing_messages.len(), 1);
+        assert!(outgoing.outgoing_messages[0].starts_with("replay/changeSpeed"));
+    }
+
+    #[test]
+    fn the_trading_socket_waits_for_its_own_clock() {
+        let mut channel = crate::models::AcctCommsChannel::new(Vec::new());
+        channel.replay = Some(ReplaySession::new(settings(Some(400))));
+        channel.reset_session(vec!["authorize".to_string(), "user/syncrequest".to_string()]);
+        assert!(!channel.ready_for_requests());
+        channel.replay.as_mut().unwrap().status = RequestStatus::Sent;
+        assert!(channel.replay.as_ref().unwrap().is_awaiting_clock());
+        channel.replay_clock_initialized(true);
+        assert!(channel.ready_for_requests());
+        assert!(channel.unsent_requests[0].starts_with("replay/changeSpeed"));
+    }
+}
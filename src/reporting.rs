@@ -4,13 +4,20 @@ use serde_json::{Value, json};
 pub type ReportRWL = Arc<tokio::sync::RwLock<String>>;
 use crate::{
     api_client::ResourceType,
+    environment::environment_label,
     credentials::{ASID, EMAIL_PASSWORD, EMAIL_USERNAME, TWILIO_AT, TWILIO_NUMBER},
     settings::{TWILIO_URL, TIMEZONE, LOG_FILENAME, DEBUG_MODE},
 };
 
 pub async fn program_log(item: &str, resource_type: ResourceType, report_rwl:ReportRWL) {
     let time = chrono::Utc::now().with_timezone(&TIMEZONE).to_rfc2822();
-    let report_line = format!("{} - {:#?} - {}\n", time, resource_type, item);
+    let report_line = format!(
+        "{} - {} - {:#?} - {}\n",
+        time,
+        environment_label(),
+        resource_type,
+        item
+    );
     if DEBUG_MODE {println!("{}",report_line)};
     let mut write = report_rwl.write().await;
     write.push_str(&report_line);
@@ -23,10 +30,10 @@ pub async fn send_txt_message(number: &str, contents: &str) -> Result<Value, req
         "From={}&To={}&Body={}",
         urlencoding::encode(TWILIO_NUMBER),
         urlencoding::encode(number),
-        urlencoding::encode(contents)
+        urlencoding::encode(&format!("[{}] {}", environment_label(), contents))
     );
     let url = format!("{}/Accounts/{}/Messages.json", TWILIO_URL, ASID);
-    Ok(reqwest::Client::new()
+    reqwest::Client::new()
         .post(url)
         .header("content-Type", "application/x-www-form-urlencoded")
         .basic_auth(ASID, Some(TWILIO_AT))
@@ -34,7 +41,7 @@ pub async fn send_txt_message(number: &str, contents: &str) -> Result<Value, req
         .send()
         .await?
         .json::<Value>()
-        .await?)
+        .await
 }
 
 pub async fn send_email(recipient_email: &str, email_body: &str, subject: &str) {
@@ -48,7 +55,7 @@ pub async fn send_email(recipient_email: &str, email_body: &str, subject: &str)
     let email = lettre::Message::builder()
         .from("Tradovate App <your@email.com>".parse().unwrap())
         .to(to.parse().unwrap())
-        .subject(subject)
+        .subject(format!("[{}] {}", environment_label(), subject))
         .body(stamped.to_string())
         .unwrap();
     let creds = lettre::transport::smtp::authentication::Credentials::new(
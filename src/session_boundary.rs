@@ -0,0 +1,291 @@
+use serde_json::json;
+
+use crate::{
+    api_client::ResourceType,
+    calendar::TradingCalendar,
+    environment::environment_label,
+    instrument_catalog::InstrumentCatalogRWL,
+    md_socket::MarketDataRWL,
+    models::AcctCommChannelRWL,
+    order_journal::OrderIntent,
+    reporting::{program_log, send_email, send_txt_message, ReportRWL},
+    settings::{
+        ALERT_PHONE_NUMBER, FLATTEN_BEFORE_CLOSE_MINUTES, FLATTEN_RETRY_SECONDS, REPORT_TO_EMAIL,
+        TIMEZONE, TRADING_PRODUCT,
+    },
+    strategy::{StrategyRWL, StrategyStatus},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionPhase {
+    // nothing is known until the first check
+    Unknown,
+    Open,
+    // inside the flatten window before the close
+    ClosingSoon,
+    Closed,
+}
+impl SessionPhase {
+    pub fn at(calendar: &TradingCalendar, product: &str, t: chrono::DateTime<chrono::Utc>) -> Self {
+        let flatten_before = std::time::Duration::from_secs(FLATTEN_BEFORE_CLOSE_MINUTES * 60);
+        match calendar.time_to_close(product, t) {
+            None => SessionPhase::Closed,
+            Some(time_to_close) if time_to_close <= flatten_before => SessionPhase::ClosingSoon,
+            Some(_) => SessionPhase::Open,
+        }
+    }
+    pub fn allows_entries(&self) -> bool {
+        *self == SessionPhase::Open
+    }
+}
+
+// liquidateposition takes no text field, so the environment goes in customTag50
+pub fn create_liquidate_position_request(request_id: i64, account_id: i64, contract_id: i64) -> String {
+    let body = json!({
+        "accountId": account_id,
+        "contractId": contract_id,
+        "admin": false,
+        "customTag50": environment_label()
+    });
+    format!("order/liquidateposition\n{}\n\n{}", request_id, body)
+}
+
+// Checks the trading product's schedule every second. Entering the flatten window blocks new
+// entries and liquidates every position in the product, which also cancels its working orders.
+// Until the next session opens every check liquidates whatever position shows up again, such as
+// an entry that filled after the flatten or positions only known once the first sync arrives.
+pub async fn watch_session_boundaries(
+    market_data_rwl: MarketDataRWL,
+    strategy_rwl: StrategyRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+    catalog_rwl: InstrumentCatalogRWL,
+    report_rwl: ReportRWL,
+) {
+    let product = TRADING_PRODUCT;
+    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let product_contracts = catalog_rwl.read().await.product_contract_ids(product);
+        let market_data = market_data_rwl.read().await;
+        let now = market_data.clock.now();
+        let phase = SessionPhase::at(&market_data.calendar, product, now);
+        let next_close = market_data
+            .calendar
+            .next_close(product, now)
+            .map(|close| close.with_timezone(&TIMEZONE).format("%v %T").to_string())
+            .unwrap_or_default();
+        drop(market_data);
+        let previous_phase = strategy_rwl.read().await.session_phase;
+        let phase_changed = phase != previous_phase;
+        if phase_changed {
+            strategy_rwl.write().await.session_phase = phase;
+        }
+        let liquidating = if phase.allows_entries() || phase == SessionPhase::Unknown {
+            Vec::new()
+        } else {
+            flatten(
+                &product_contracts,
+                strategy_rwl.clone(),
+                acct_comms_channel.clone(),
+            )
+            .await
+        };
+        match phase {
+            SessionPhase::ClosingSoon if phase_changed => {
+                let report = if liquidating.is_empty() {
+                    "No positions to flatten".to_string()
+                } else {
+                    liquidating.join("\n")
+                };
+                let report = format!(
+                    "{} closes at {}. New entries blocked.\n{}",
+                    product, next_close, report
+                );
+                program_log(&report, ResourceType::Trading, report_rwl.clone()).await;
+                let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+                send_email(REPORT_TO_EMAIL, &report, "Flattened Before Close").await;
+            }
+            SessionPhase::ClosingSoon | SessionPhase::Closed if !liquidating.is_empty() => {
+                let report = format!(
+                    "{} position found after the flatten.\n{}",
+                    product,
+                    liquidating.join("\n")
+                );
+                program_log(&report, ResourceType::Trading, report_rwl.clone()).await;
+                let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+            }
+            SessionPhase::Open if phase_changed => {
+                program_log(
+                    &format!("{} session is open. Entries allowed.", product),
+                    ResourceType::Trading,
+                    report_rwl.clone(),
+                )
+                .await
+            }
+            SessionPhase::Closed if phase_changed => {
+                program_log(
+                    &format!("{} session is closed.", product),
+                    ResourceType::Trading,
+                    report_rwl.clone(),
+                )
+                .await
+            }
+            _ => {}
+        }
+    }
+}
+
+// The account positions come from the last user sync, so the strategy's own open position is
+// liquidated as well in case the sync is older than it. A position whose last liquidation is still
+// working, or was sent less than FLATTEN_RETRY_SECONDS ago, is left alone. Returns a line per
+// liquidation sent.
+async fn flatten(
+    product_contracts: &[i64],
+    strategy_rwl: StrategyRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+) -> Vec<String> {
+    let mut open = acct_comms_channel.write().await;
+    let mut strategy = strategy_rwl.write().await;
+    let mut to_liquidate: Vec<(i64, i64)> = Vec::new();
+    let own_position = strategy
+        .positions
+        .last()
+        .map(|position| (position.account_id, position.contract_id));
+    if let Some(user_data) = &open.user_data {
+        for position in user_data.data.positions.iter().filter(|position| {
+            position.net_pos != 0 && product_contracts.contains(&position.contract_id)
+        }) {
+            to_liquidate.push((position.account_id, position.contract_id));
+        }
+    }
+    // an entry that has not filled yet has no position to hand over to the exit flow
+    if matches!(strategy.status, StrategyStatus::InATrade) {
+        if let Some(position) = strategy.positions.last() {
+            let key = (position.account_id, position.contract_id);
+            if position.net_pos != 0 && !to_liquidate.contains(&key) {
+                to_liquidate.push(key);
+            }
+        }
+        if !strategy.sent_exit_orders {
+            strategy.sent_exit_orders = true;
+            strategy.status = StrategyStatus::SentExitOrders;
+            let index = strategy.positions.len() - 1;
+            strategy.positions[index].exit_trigger = "Session close".to_string();
+        }
+    }
+    let now_millis = open.clock.now_millis();
+    let mut liquidating = Vec::new();
+    for (account_id, contract_id) in to_liquidate {
+        let intent = OrderIntent::liquidate(account_id, contract_id);
+        if let Some(last) = open.journal.last_submission(&intent) {
+            if !last.state.is_final()
+                || now_millis - last.submitted_at < FLATTEN_RETRY_SECONDS * 1000
+            {
+                continue;
+            }
+        }
+        let submitted = open.submit_order(intent, |request_id, _| {
+            create_liquidate_position_request(request_id, account_id, contract_id)
+        });
+        let cl_ord_id = match submitted {
+            Ok(cl_ord_id) => cl_ord_id,
+            Err(_) => continue,
+        };
+        // the strategy waits for the fill of the order that closes its own position
+        if own_position == Some((account_id, contract_id)) {
+            strategy.order_cl_ord_id = Some(cl_ord_id);
+        }
+        liquidating.push(format!(
+            "Liquidating contract {} on account {}",
+            contract_id, account_id
+        ));
+    }
+    liquidating
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        clock::ManualClock,
+        models::{AcctCommsChannel, Position, UserDataMessage},
+        order_journal::SubmissionState,
+        strategy::Strategy,
+    };
+
+    const ES_CONTRACT: i64 = 2665267;
+
+    fn synced_channel(clock: Arc<ManualClock>, net_pos: i64) -> AcctCommsChannel {
+        let mut open = AcctCommsChannel::new(Vec::new());
+        open.clock = clock;
+        open.user_data = Some(UserDataMessage::default());
+        set_net_pos(&mut open, net_pos);
+        open
+    }
+
+    fn set_net_pos(open: &mut AcctCommsChannel, net_pos: i64) {
+        open.user_data.as_mut().unwrap().data.positions = vec![Position {
+            account_id: 1,
+            contract_id: ES_CONTRACT,
+            net_pos,
+            ..Position::default()
+        }];
+    }
+
+    async fn check(acct_comms_channel: &AcctCommChannelRWL) -> Vec<String> {
+        let strategy_rwl = Arc::new(tokio::sync::RwLock::new(Strategy::new()));
+        flatten(&[ES_CONTRACT], strategy_rwl, acct_comms_channel.clone()).await
+    }
+
+    #[tokio::test]
+    async fn positions_synced_after_the_first_check_are_liquidated() {
+        let clock = Arc::new(ManualClock::new(0));
+        let mut open = synced_channel(clock.clone(), 1);
+        open.user_data = None;
+        let acct_comms_channel = Arc::new(tokio::sync::RwLock::new(open));
+        assert!(check(&acct_comms_channel).await.is_empty());
+        clock.advance(1000);
+        {
+            let mut open = acct_comms_channel.write().await;
+            open.user_data = Some(UserDataMessage::default());
+            set_net_pos(&mut open, 1);
+        }
+        assert_eq!(check(&acct_comms_channel).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn entries_filled_after_the_flatten_are_liquidated_again() {
+        let clock = Arc::new(ManualClock::new(0));
+        let open = synced_channel(clock.clone(), 1);
+        let acct_comms_channel = Arc::new(tokio::sync::RwLock::new(open));
+        assert_eq!(check(&acct_comms_channel).await.len(), 1);
+        // the liquidation is still working
+        clock.advance(FLATTEN_RETRY_SECONDS * 1000);
+        assert!(check(&acct_comms_channel).await.is_empty());
+        {
+            let mut open = acct_comms_channel.write().await;
+            open.journal.submissions[0].state = SubmissionState::Filled;
+            set_net_pos(&mut open, 0);
+        }
+        assert!(check(&acct_comms_channel).await.is_empty());
+        // an entry fills after the flatten
+        set_net_pos(&mut *acct_comms_channel.write().await, -2);
+        assert_eq!(check(&acct_comms_channel).await.len(), 1);
+        assert_eq!(acct_comms_channel.read().await.journal.submissions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_liquidations_are_retried_after_a_pause() {
+        let clock = Arc::new(ManualClock::new(0));
+        let open = synced_channel(clock.clone(), 1);
+        let acct_comms_channel = Arc::new(tokio::sync::RwLock::new(open));
+        assert_eq!(check(&acct_comms_channel).await.len(), 1);
+        acct_comms_channel.write().await.journal.submissions[0].state = SubmissionState::Rejected;
+        clock.advance(FLATTEN_RETRY_SECONDS * 1000 - 1);
+        assert!(check(&acct_comms_channel).await.is_empty());
+        clock.advance(1);
+        assert_eq!(check(&acct_comms_channel).await.len(), 1);
+    }
+}
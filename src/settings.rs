@@ -1,29 +1,167 @@
+use crate::{
+    accounting::FeeSchedule,
+    api_client::Server,
+    position_sizing::SizingModel,
+    time_and_sales::{Retention, TimeWindow},
+    trade_classifier::ClassificationRule,
+};
+
 //LIVE SERVERS
-pub const LIVE_TRADING_URL: &str = ""; //"live.tradovateapi.com"
+pub const LIVE_TRADING_URL: &str = "live.tradovateapi.com";
 pub const LIVE_MARKET_DATA_URL: &str = "md.tradovateapi.com";
 // DEMO SERVERS
 pub const DEMO_TRADING_URL: &str = "demo.tradovateapi.com";
-//pub const DEMO_MARKET_DATA_URL:&str = "md-demo.tradovateapi.com";
+pub const DEMO_MARKET_DATA_URL: &str = "md-demo.tradovateapi.com";
+// REPLAY SERVER
+pub const REPLAY_URL: &str = "replay.tradovateapi.com";
+// Overridden with --replay-start and --replay-speed. Speed is a percentage of real time.
+pub const REPLAY_START: &str = "2022-03-14T13:30:00Z";
+pub const REPLAY_SPEED: i64 = 100;
+pub const REPLAY_INITIAL_BALANCE: f64 = 50000.0;
+// SERVER SELECTION. Overridden with --server live|demo|replay|custom
+pub const DEFAULT_SERVER: Server = Server::Demo;
+pub const CUSTOM_SERVER: Server = Server::Custom {
+    trading_url: "localhost:8080",
+    market_data_url: "localhost:8081",
+    secure: false,
+};
+// must be typed to start on the live server unless --confirm-live is passed
+pub const LIVE_CONFIRMATION_PHRASE: &str = "TRADE LIVE";
 // AUTH FILENAME
 pub const AUTH_FILENAME: &str = "tradovate_auth.json";
 pub const LOG_FILENAME: &str = "tradovate-algo.txt";
+// products, maturities and contracts are cached here and fetched again once older than the ttl
+pub const INSTRUMENT_CATALOG_FILENAME: &str = "instrument_catalog.json";
+pub const INSTRUMENT_CATALOG_TTL_HOURS: i64 = 24;
+// holidays and early closes, see calendar.rs for the format
+pub const TRADING_CALENDAR_FILENAME: &str = "trading_calendar.json";
+pub const CONNECT_BEFORE_OPEN_SECONDS: u64 = 30;
+// entries stop and positions are flattened this long before the session closes
+pub const FLATTEN_BEFORE_CLOSE_MINUTES: u64 = 5;
+// a liquidation that did not flatten the position is sent again after this long
+pub const FLATTEN_RETRY_SECONDS: i64 = 30;
+// tokens are renewed this long before they expire
+pub const TOKEN_RENEWAL_MARGIN_MILLIS: i64 = 10 * 60 * 1000;
+pub const TOKEN_CHECK_SECONDS: u64 = 60;
 pub const TWILIO_URL: &str = "https://api.twilio.com/2010-04-01";
 
 // TRADE THRESHOLDS
 pub const DOM_THRESHOLD: f64 = 35.0;
 pub const TS_PRESSURE_THRESHOLD: f64 = 150.0;
 pub const LARGE_TRADE: i64 = 400;
+pub const TS_PRESSURE_WINDOW: TimeWindow = TimeWindow::OneSecond;
+pub const TRADE_CLASSIFICATION_RULE: ClassificationRule = ClassificationRule::LeeReady;
 
-pub const TRADING_SYMBOL: &str = "ESH2";
+// product roots. The front month of each is resolved at startup and rolled automatically.
+pub const TRADING_PRODUCT: &str = "ES";
+pub const MARKET_DATA_PRODUCTS: [&str; 2] = ["ES", "NQ"];
+// the account the strategy trades
 pub const ACCOUNT_NUMBER: &str = "your account num";
+// TRADE COPIER. Every change in the positions of ACCOUNT_NUMBER is mirrored on each follower
+// account, scaled by its multiplier and rounded to whole contracts.
+pub const COPY_FOLLOWERS: [(&str, f64); 0] = [];
+pub const COPY_CHECK_MILLIS: u64 = 250;
+// a follower whose copy order has not filled within this long stops copying
+pub const COPY_ORDER_TIMEOUT_SECONDS: u64 = 10;
+// ORDER JOURNAL. Every order carries a client order id and is saved here until it is settled, so
+// orders in flight when the connection or the process dies are found on the next sync.
+pub const ORDER_JOURNAL_FILENAME: &str = "order_journal.json";
+pub const ORDER_JOURNAL_RETENTION_HOURS: i64 = 24;
+// the same order submitted again within this long is refused
+pub const DUPLICATE_ORDER_WINDOW_MILLIS: i64 = 2000;
+// an order that never reached the broker is sent again only if it is younger than this
+pub const ORDER_RESUBMIT_MAX_AGE_MILLIS: i64 = 30 * 1000;
+// how often the journal is written when it changed
+pub const ORDER_JOURNAL_SAVE_MILLIS: u64 = 250;
+// request ids below this are the auth, the sync and the replay clock requests
+pub const FIRST_ORDER_REQUEST_ID: i64 = 1000;
+// STATE SNAPSHOT. The strategy's positions, triggers and extremes, the order journal and the
+// session statistics are saved here and restored on startup within the same trading session.
+pub const STATE_SNAPSHOT_FILENAME: &str = "state_snapshot.json";
+pub const STATE_SNAPSHOT_MILLIS: u64 = 5000;
 
 pub const TRADE_CLOSE_TRIGGER: f64 = 500.0;
 
+// PROTECTIVE ORDERS. Entries go out as order/placeOSO with a stop loss and a take profit.
+pub const USE_BRACKETS: bool = true;
+pub const STOP_LOSS_TICKS: i64 = 16;
+pub const TAKE_PROFIT_TICKS: i64 = 32;
+// the stop follows the best price since entry by this many ticks
+pub const TRAILING_STOP_TICKS: Option<i64> = None;
+// this many ticks in profit moves the stop to entry plus BREAKEVEN_OFFSET_TICKS
+pub const BREAKEVEN_TRIGGER_TICKS: Option<i64> = Some(12);
+pub const BREAKEVEN_OFFSET_TICKS: i64 = 1;
+
+// POSITION SIZING. Risk is measured over STOP_LOSS_TICKS.
+pub const SIZING_MODEL: SizingModel = SizingModel::FixedContracts(1);
+pub const MAX_CONTRACTS: i64 = 5;
+pub const MAX_RISK_PER_TRADE: f64 = 500.0;
+
 pub const SOCKET_INTERVAL_SPEED: u64 = 1;
-pub const SINGLE_LEG_COMMISSION: f64 = 2.05;
+pub const RECONNECT_INITIAL_BACKOFF_MILLIS: u64 = 500;
+pub const RECONNECT_MAX_BACKOFF_MILLIS: u64 = 60 * 1000;
+pub const RECONNECT_ALERT_ATTEMPTS: u32 = 5;
+pub const STABLE_CONNECTION_SECONDS: u64 = 60;
+// subscriptions not acknowledged within this long are sent again
+pub const SUBSCRIPTION_ACK_TIMEOUT_MILLIS: u64 = 10 * 1000;
+// a socket with no frames (heartbeats included) for this long is dropped and reconnected
+pub const FRAME_STALE_MILLIS: u64 = 10 * 1000;
+// no chart, quote or dom events for a contract for this long blocks new entries on it
+pub const MARKET_EVENT_STALE_MILLIS: u64 = 30 * 1000;
+// FEES per contract per side. Products not listed pay DEFAULT_FEES.
+pub const FEE_SCHEDULES: [(&str, FeeSchedule); 4] = [
+    ("ES", FeeSchedule { exchange: 1.38, clearing: 0.19, nfa: 0.02, commission: 0.46 }),
+    ("NQ", FeeSchedule { exchange: 1.38, clearing: 0.19, nfa: 0.02, commission: 0.46 }),
+    ("MES", FeeSchedule { exchange: 0.35, clearing: 0.19, nfa: 0.02, commission: 0.13 }),
+    ("MNQ", FeeSchedule { exchange: 0.35, clearing: 0.19, nfa: 0.02, commission: 0.13 }),
+];
+pub const DEFAULT_FEES: FeeSchedule = FeeSchedule { exchange: 1.38, clearing: 0.19, nfa: 0.02, commission: 0.46 };
+// ledger and Tradovate realized pnl further apart than this are reported
+pub const RECONCILE_TOLERANCE: f64 = 0.01;
+
+// ACCOUNT MONITOR
+pub const ACCOUNT_MONITOR_MILLIS: u64 = 1000;
+// alerts when used margin over equity crosses each level
+pub const MARGIN_UTILIZATION_ALERTS: [f64; 2] = [0.5, 0.8];
+// alerts when the day's loss, realized plus open, crosses each level
+pub const DAILY_LOSS_ALERTS: [f64; 2] = [500.0, 1000.0];
+// alerts when the account is this close to an auto liquidation level
+pub const AUTO_LIQ_DISTANCE_ALERT: f64 = 500.0;
 
 pub const TIMEZONE: chrono_tz::Tz = chrono_tz::US::Pacific;
 pub const REPORT_TO_EMAIL: &str = "the email for peridic reporting";
+pub const ALERT_PHONE_NUMBER: &str = "your phone number";
 pub const ONE_SECOND:i64 = 1000;
 
+// ORDER FLOW INDICATORS
+pub const INDICATOR_BAR_MILLIS: i64 = 60 * 1000;
+pub const INDICATOR_LOOKBACK: usize = 14;
+pub const ABSORPTION_WINDOW: i64 = 10 * 1000;
+pub const ABSORPTION_VOLUME: i64 = 2000;
+pub const ABSORPTION_MAX_TICKS: i64 = 1;
+pub const IMBALANCE_RATIO: f64 = 3.0;
+pub const STACKED_IMBALANCE_LEVELS: usize = 3;
+// book levels per side used for the DOM imbalance ratio
+pub const DOM_IMBALANCE_LEVELS: usize = 5;
+pub const VWAP_BAND_DEVIATIONS: f64 = 2.0;
+
+// TIME AND SALES STORAGE
+pub const TS_RETENTION: Retention = Retention::Millis(60 * 60 * 1000);
+
+// CONTRACT ROLLOVER
+// the front month rolls this many days before it expires
+pub const ROLL_DAYS_BEFORE_EXPIRATION: i64 = 8;
+// this close to the roll date the next contract is watched, and rolled to early once it trades more
+pub const ROLL_VOLUME_WATCH_DAYS: i64 = 3;
+pub const ROLL_VOLUME_WINDOW_MILLIS: i64 = 30 * 60 * 1000;
+// when false an open position is left to exit on the old contract before the strategy rolls
+pub const ROLL_POSITIONS: bool = false;
+pub const ROLL_CHECK_SECONDS: u64 = 60;
+
+// HISTORICAL TICK BACKFILL
+pub const BACKFILL_MINUTES: i64 = 30;
+pub const BACKFILL_PAGE_SIZE: i64 = 5000;
+// a page rejected this many times fails the backfill
+pub const BACKFILL_MAX_ATTEMPTS: i64 = 3;
+
 pub const DEBUG_MODE: bool = false;
\ No newline at end of file
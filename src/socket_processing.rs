@@ -1,10 +1,18 @@
 use crate::{
     api_client::ResourceType,
-    md_socket::{MarketDataIncomingRWL, MarketDataOutGoingRWL, MarketDataRWL},
-    models::{ContractID, DOMMessage, QuoteMessage, TickChart, WebsocketResponse},
+    md_socket::{MarketDataIncomingRWL, MarketDataOutGoingRWL, MarketDataRWL, RequestStatus},
+    models::{ContractID, DOMMessage, Dom, QuoteMessage, TickChart, WebsocketResponse},
     time_and_sales::TimeAndSalesCollection, reporting::{program_log, ReportRWL},
+    trade_classifier::TradeClassifier,
+    replay::{ReplayClock, REPLAY_REQUEST_ID},
+    clock::ClockRef,
+    calendar::TradingCalendarRef,
+    indicators::OrderFlowIndicators,
+    time_and_sales::TimeAndSalesItem,
+    watchdog::FeedWatchdogRWL,
 };
 use serde_json::Value;
+use std::collections::HashMap;
 
 #[derive(Debug,Clone)]
 pub struct MarketData {
@@ -12,24 +20,52 @@ pub struct MarketData {
     pub market_depth: Vec<DOMMessage>,
     pub time_and_sales: TimeAndSalesCollection,
     pub contract_ids: Vec<ContractID>,
+    pub trade_classifier: TradeClassifier,
+    pub indicators: HashMap<i64, OrderFlowIndicators>,
+    pub clock: ClockRef,
+    pub calendar: TradingCalendarRef,
 }
 impl MarketData {
-    pub fn new(contract_ids: Vec<ContractID>) -> Self {
+    pub fn new(contract_ids: Vec<ContractID>, clock: ClockRef, calendar: TradingCalendarRef) -> Self {
         Self {
             quote: None,
             market_depth: Vec::new(),
             time_and_sales: TimeAndSalesCollection::new(),
             contract_ids,
+            trade_classifier: TradeClassifier::default(),
+            indicators: HashMap::new(),
+            clock,
+            calendar,
+        }
+    }
+    pub fn update_indicators(&mut self, items: &[TimeAndSalesItem]) {
+        for item in items {
+            let tick_size = match self.contract_ids.iter().find(|contract| contract.id == item.contract_id) {
+                Some(contract) => contract.provider_tick_size,
+                None => continue,
+            };
+            self.indicators
+                .entry(item.contract_id)
+                .or_insert_with(|| OrderFlowIndicators::new(tick_size))
+                .update(item);
+        }
+    }
+    pub fn update_dom_indicators(&mut self, doms: &[Dom]) {
+        for dom in doms {
+            let tick_size = match self.contract_ids.iter().find(|contract| contract.id == dom.contract_id) {
+                Some(contract) => contract.provider_tick_size,
+                None => continue,
+            };
+            self.indicators
+                .entry(dom.contract_id)
+                .or_insert_with(|| OrderFlowIndicators::new(tick_size))
+                .update_dom(dom);
         }
     }
     pub fn summarize_ts(&self) -> String {
         let mut output = String::new();
-        if let Some(largest_buy) = self.time_and_sales.largest_buy() {
-            if let Some(contract) = self
-                .contract_ids
-                .iter()
-                .find(|contract| contract.historical_id == largest_buy.historical_id)
-            {
+        for contract in &self.contract_ids {
+            if let Some(largest_buy) = self.time_and_sales.largest_buy(contract.id) {
                 output.push_str(&format!(
                     "{}-{} Largest Buy {:#?} \n",
                     contract.symbol,
@@ -37,15 +73,9 @@ impl MarketData {
                     largest_buy
                 ));
             }
-        }
-        if let Some(largest_sell) = self.time_and_sales.largest_sell() {
-            if let Some(contract) = self
-                .contract_ids
-                .iter()
-                .find(|contract| contract.historical_id == largest_sell.historical_id)
-            {
+            if let Some(largest_sell) = self.time_and_sales.largest_sell(contract.id) {
                 output.push_str(&format!(
-                    "{}-{} Largest Sell {:#?}",
+                    "{}-{} Largest Sell {:#?} \n",
                     contract.symbol,
                     largest_sell.get_normal_time(),
                     largest_sell
@@ -54,12 +84,34 @@ impl MarketData {
         }
         output
     }
+    // Chart ids from the previous session must not match packets on the new one
+    pub fn reset_session(&mut self) {
+        for contract in self.contract_ids.iter_mut() {
+            contract.historical_id = 0;
+        }
+    }
+    pub fn now_millis(&self) -> i64 {
+        self.clock.now_millis()
+    }
+    pub fn last_tick_timestamp(&self, symbol: &str) -> Option<i64> {
+        let contract = self.contract(symbol)?;
+        self.time_and_sales
+            .partition(contract.id)?
+            .last()
+            .map(|item| item.timestamp)
+    }
+    pub fn contract(&self, symbol: &str) -> Option<&ContractID> {
+        self.contract_ids
+            .iter()
+            .find(|contract| contract.symbol == symbol)
+    }
 }
 pub async fn process_message(
     message: String,
     market_data_incoming_rwl: MarketDataIncomingRWL,
     market_data_outgoing_rwl: MarketDataOutGoingRWL,
     market_data_rwl: MarketDataRWL,
+    watchdog_rwl: FeedWatchdogRWL,
     resource_type: ResourceType,
     report_rwl:ReportRWL
 ) {
@@ -69,7 +121,21 @@ pub async fn process_message(
             Value::Array(array) => {
                 for json in array {
                     if let Value::Object(obj) = json {
-                        if obj.contains_key("s") {
+                        if obj.contains_key("s") && obj["i"].as_i64() == Some(REPLAY_REQUEST_ID) {
+                            let status = obj["s"].as_i64().unwrap_or_default();
+                            let mut outgoing_open = market_data_outgoing_rwl.write().await;
+                            outgoing_open.replay_clock_initialized(status == 200);
+                            drop(outgoing_open);
+                            program_log(
+                                &format!("Replay clock initialization response {}", json),
+                                resource_type,report_rwl.clone()).await;
+                            return;
+                        } else if obj.contains_key("s") && obj["i"].as_i64() == Some(REPLAY_REQUEST_ID + 1) {
+                            program_log(
+                                &format!("Replay speed change response {}", json),
+                                resource_type,report_rwl.clone()).await;
+                            return;
+                        } else if obj.contains_key("s") {
                             if obj.keys().len() == 2 {
                                 if let Some(s_value) = obj["s"].as_i64() {
                                     if let Some(i_value) = obj["i"].as_i64() {
@@ -79,6 +145,18 @@ pub async fn process_message(
                                             drop(open);
                                             program_log("Succesfully authorized", resource_type,report_rwl.clone()).await;
                                             return;
+                                        } else if i_value > 1 {
+                                            // dom, quote and histogram subscriptions are acknowledged without a body
+                                            acknowledge_subscription(
+                                                market_data_outgoing_rwl.clone(),
+                                                i_value,
+                                                s_value,
+                                                0,
+                                                resource_type,
+                                                report_rwl.clone(),
+                                            )
+                                            .await;
+                                            return;
                                         }
                                     }
                                 }
@@ -86,29 +164,98 @@ pub async fn process_message(
                                 let parsed: Result<WebsocketResponse, serde_json::Error> =
                                     serde_json::from_value(json.clone());
                                 if let Ok(ws_response) = parsed {
-                                    if ws_response.data.historical_id > 0 {
+                                    let mut outgoing_open = market_data_outgoing_rwl.write().await;
+                                    if let Some(index) =
+                                        outgoing_open.backfill_index(ws_response.request_id)
+                                    {
+                                        if ws_response.status == 200 {
+                                            outgoing_open.backfill_requests[index].historical_id =
+                                                ws_response.data.historical_id;
+                                            drop(outgoing_open);
+                                        } else {
+                                            drop(outgoing_open);
+                                            acknowledge_subscription(
+                                                market_data_outgoing_rwl.clone(),
+                                                ws_response.request_id,
+                                                ws_response.status,
+                                                0,
+                                                resource_type,
+                                                report_rwl.clone(),
+                                            )
+                                            .await;
+                                        }
+                                    } else if ws_response.data.historical_id > 0 {
+                                        drop(outgoing_open);
+                                        acknowledge_subscription(
+                                            market_data_outgoing_rwl.clone(),
+                                            ws_response.request_id,
+                                            ws_response.status,
+                                            ws_response.data.historical_id,
+                                            resource_type,
+                                            report_rwl.clone(),
+                                        )
+                                        .await;
                                         let mut market_data_open = market_data_rwl.write().await;
                                         let outgoing_open = market_data_outgoing_rwl.read().await;
-                                        let symbol = &outgoing_open.data_requests
-                                            [(ws_response.request_id - 2) as usize]
-                                            .symbol;
-                                        for n in 0..market_data_open.contract_ids.len() {
-                                            if market_data_open.contract_ids[n].symbol == *symbol {
-                                                market_data_open.contract_ids[n].historical_id =
-                                                    ws_response.data.historical_id;
+                                        if let Some(index) =
+                                            outgoing_open.subscription_index(ws_response.request_id)
+                                        {
+                                            let symbol = &outgoing_open.data_requests[index].symbol;
+                                            for n in 0..market_data_open.contract_ids.len() {
+                                                if market_data_open.contract_ids[n].symbol == *symbol {
+                                                    market_data_open.contract_ids[n].historical_id =
+                                                        ws_response.data.historical_id;
+                                                }
                                             }
                                         }
                                         drop(market_data_open);
                                         drop(outgoing_open);
+                                    } else {
+                                        drop(outgoing_open);
+                                        acknowledge_subscription(
+                                            market_data_outgoing_rwl.clone(),
+                                            ws_response.request_id,
+                                            ws_response.status,
+                                            0,
+                                            resource_type,
+                                            report_rwl.clone(),
+                                        )
+                                        .await;
                                     }
                                     let mut open = market_data_incoming_rwl.write().await;
                                     open.request_responses.push(ws_response);
                                     drop(open);
                                     return;
+                                } else if let (Some(s_value), Some(i_value)) =
+                                    (obj["s"].as_i64(), obj["i"].as_i64())
+                                {
+                                    // error responses carry a plain string body
+                                    acknowledge_subscription(
+                                        market_data_outgoing_rwl.clone(),
+                                        i_value,
+                                        s_value,
+                                        0,
+                                        resource_type,
+                                        report_rwl.clone(),
+                                    )
+                                    .await;
+                                    return;
                                 }
                             }
                         } else if let Some(e_contens) = obj["e"].as_str() {
-                            if matches!(e_contens, "md" | "chart") {
+                            if e_contens == "clock" {
+                                if let Some(clock) = ReplayClock::from_event(&obj["d"]) {
+                                    let open = market_data_rwl.read().await;
+                                    let mut outgoing_open = market_data_outgoing_rwl.write().await;
+                                    open.clock.sync(clock.server_time, clock.speed);
+                                    if let Some(replay) = &mut outgoing_open.replay {
+                                        replay.record_clock(&clock);
+                                    }
+                                    drop(outgoing_open);
+                                    drop(open);
+                                    return;
+                                }
+                            } else if matches!(e_contens, "md" | "chart") {
                                 if let Some(obj) = obj["d"].as_object() {
                                     if let Some(key) = obj.keys().next() {
                                         match key.as_ref() {
@@ -119,16 +266,60 @@ pub async fn process_message(
                                                 > = serde_json::from_value(json.clone());
                                                 match parsed {
                                                     Ok(tick_chart) => {
-                                                        let mut ts_items =
-                                                            tick_chart.get_all_ts_items();
-                                                        ts_items
-                                                            .sort_by_key(|item| item.timestamp);
                                                         let mut open =
                                                             market_data_rwl.write().await;
-                                                        open.time_and_sales
-                                                            .items
-                                                            .extend(ts_items);
+                                                        let mut outgoing_open =
+                                                            market_data_outgoing_rwl.write().await;
+                                                        let mut live_contracts = Vec::new();
+                                                        for chart in &tick_chart.data.charts {
+                                                            let mut chart = chart.clone();
+                                                            let backfill = outgoing_open
+                                                                .backfill_in_progress(
+                                                                    chart.historical_id,
+                                                                );
+                                                            // backfill charts have their own id, so their ticks are matched to the contract by symbol
+                                                            let contract = match &backfill {
+                                                                Some(backfill) => open
+                                                                    .contract_ids
+                                                                    .iter()
+                                                                    .find(|contract| contract.symbol == backfill.symbol),
+                                                                None => open
+                                                                    .contract_ids
+                                                                    .iter()
+                                                                    .find(|contract| contract.historical_id == chart.historical_id),
+                                                            };
+                                                            if let Some(contract) = contract {
+                                                                chart.contract_id = contract.id;
+                                                                if backfill.is_none() {
+                                                                    live_contracts.push(contract.id);
+                                                                }
+                                                            }
+                                                            let mut ts_items = chart.get_ts_items(open.now_millis());
+                                                            let is_live = backfill.is_none();
+                                                            if let Some(backfill) = backfill {
+                                                                // history arrives out of order with the live stream, so it gets its own tick rule state
+                                                                TradeClassifier::default()
+                                                                    .classify_all(&mut ts_items);
+                                                                backfill
+                                                                    .record_page(&ts_items, chart.eoh);
+                                                            } else {
+                                                                open.trade_classifier
+                                                                    .classify_all(&mut ts_items);
+                                                            }
+                                                            let added = open.time_and_sales.merge(ts_items);
+                                                            // indicators are streaming, so only the in-order live ticks feed them
+                                                            if is_live {
+                                                                open.update_indicators(&added);
+                                                            }
+                                                        }
+                                                        outgoing_open.advance_backfills();
+                                                        drop(outgoing_open);
                                                         drop(open);
+                                                        let mut watchdog = watchdog_rwl.write().await;
+                                                        for contract_id in live_contracts {
+                                                            watchdog.record_market_event(contract_id);
+                                                        }
+                                                        drop(watchdog);
                                                         return;
                                                     }
 
@@ -147,6 +338,11 @@ pub async fn process_message(
                                                 > = serde_json::from_value(json.clone());
                                                 match parsed {
                                                     Ok(qt_msg) => {
+                                                        let mut watchdog = watchdog_rwl.write().await;
+                                                        for quote in &qt_msg.data.quotes {
+                                                            watchdog.record_market_event(quote.contract_id);
+                                                        }
+                                                        drop(watchdog);
                                                         let mut open =
                                                             market_data_rwl.write().await;
                                                         open.quote = Some(qt_msg);
@@ -166,6 +362,11 @@ pub async fn process_message(
                                                 > = serde_json::from_value(json.clone());
                                                 match parsed {
                                                     Ok(dom_msg) => {
+                                                        let mut watchdog = watchdog_rwl.write().await;
+                                                        for dom in &dom_msg.data.doms {
+                                                            watchdog.record_market_event(dom.contract_id);
+                                                        }
+                                                        drop(watchdog);
                                                         let mut open =
                                                             market_data_rwl.write().await;
                                                         if let Some(position) = open
@@ -180,6 +381,7 @@ pub async fn process_message(
                                                             open.market_depth
                                                                 .swap_remove(position);
                                                         }
+                                                        open.update_dom_indicators(&dom_msg.data.doms);
                                                         open.market_depth.push(dom_msg);
                                                         drop(open);
                                                         return;
@@ -218,3 +420,48 @@ pub async fn process_message(
             resource_type,report_rwl.clone()).await,
     }
 }
+
+async fn acknowledge_subscription(
+    market_data_outgoing_rwl: MarketDataOutGoingRWL,
+    request_id: i64,
+    status: i64,
+    historical_id: i64,
+    resource_type: ResourceType,
+    report_rwl: ReportRWL,
+) {
+    let mut outgoing_open = market_data_outgoing_rwl.write().await;
+    if let Some(index) = outgoing_open.backfill_index(request_id) {
+        if status == 200 {
+            return;
+        }
+        let backfill = &mut outgoing_open.backfill_requests[index];
+        backfill.reject();
+        let message = format!(
+            "{} was rejected with status {}{}",
+            backfill.summarize(),
+            status,
+            if backfill.status == RequestStatus::Failed {
+                ", backfill failed"
+            } else {
+                ", sending it again"
+            }
+        );
+        drop(outgoing_open);
+        program_log(&message, resource_type, report_rwl).await;
+        return;
+    }
+    let summary = outgoing_open
+        .acknowledge(request_id, status, historical_id)
+        .map(|request| request.summarize());
+    drop(outgoing_open);
+    if let Some(summary) = summary {
+        if status != 200 {
+            program_log(
+                &format!("{} subscription was rejected with status {}", summary, status),
+                resource_type,
+                report_rwl,
+            )
+            .await;
+        }
+    }
+}
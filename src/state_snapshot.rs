@@ -0,0 +1,198 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    api_client::ResourceType,
+    bracket::Bracket,
+    calendar::TradingCalendar,
+    clock::ClockRef,
+    environment::environment_label,
+    models::{AcctCommChannelRWL, DOMSummary},
+    order_journal::OrderJournal,
+    others::{create_json_file_atomically, open_json},
+    reporting::{program_log, ReportRWL},
+    settings::{ACCOUNT_NUMBER, STATE_SNAPSHOT_FILENAME, STATE_SNAPSHOT_MILLIS, TRADING_PRODUCT},
+    strategy::{MarketPosition, Strategy, StrategyRWL},
+    time_and_sales::{OrderAction, TimeAndSalesItem, TimeAndSalesPressure},
+};
+
+// Closed trades of the session
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub trades: i64,
+    pub winners: i64,
+    pub losers: i64,
+    pub realized_pnl: f64,
+    pub largest_win: f64,
+    pub largest_loss: f64,
+}
+impl SessionStats {
+    pub fn from_positions(positions: &[MarketPosition]) -> Self {
+        let mut stats = Self::default();
+        for position in positions
+            .iter()
+            .filter(|position| position.settled)
+        {
+            let pnl = position.realized_pnl;
+            stats.trades += 1;
+            stats.realized_pnl += pnl;
+            if pnl > 0.0 {
+                stats.winners += 1;
+                stats.largest_win = stats.largest_win.max(pnl);
+            } else if pnl < 0.0 {
+                stats.losers += 1;
+                stats.largest_loss = stats.largest_loss.min(pnl);
+            }
+        }
+        stats
+    }
+}
+
+// The part of the strategy that outlives a restart. Order flags and the status are left out, the
+// status is worked out again from the account on the first sync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StrategySnapshot {
+    pub dom_extreme: (String, DOMSummary),
+    pub ts_pressure_extreme: TimeAndSalesPressure,
+    pub largest_single_item: Option<TimeAndSalesItem>,
+    pub positions: Vec<MarketPosition>,
+    pub pending_roll_entry: Option<(OrderAction, i64)>,
+    pub bracket: Option<Bracket>,
+}
+impl StrategySnapshot {
+    pub fn capture(strategy: &Strategy) -> Self {
+        Self {
+            dom_extreme: strategy.dom_extreme.clone(),
+            ts_pressure_extreme: strategy.ts_pressure_extreme.clone(),
+            largest_single_item: strategy.largest_single_item.clone(),
+            positions: strategy.positions.clone(),
+            pending_roll_entry: strategy.pending_roll_entry,
+            bracket: strategy.bracket.clone(),
+        }
+    }
+    pub fn restore_into(self, strategy: &mut Strategy) {
+        strategy.dom_extreme = self.dom_extreme;
+        strategy.ts_pressure_extreme = self.ts_pressure_extreme;
+        strategy.largest_single_item = self.largest_single_item;
+        strategy.positions = self.positions;
+        strategy.pending_roll_entry = self.pending_roll_entry;
+        strategy.bracket = self.bracket;
+    }
+}
+
+// Everything needed to pick up where the last run stopped, saved in STATE_SNAPSHOT_FILENAME
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    // clock time, so a replay session is compared with its own clock
+    pub saved_at: i64,
+    pub environment: String,
+    pub account: String,
+    pub strategy: StrategySnapshot,
+    pub journal: OrderJournal,
+    pub stats: SessionStats,
+}
+impl StateSnapshot {
+    pub fn capture(strategy: &Strategy, journal: &OrderJournal, now_millis: i64) -> Self {
+        Self {
+            saved_at: now_millis,
+            environment: environment_label().to_string(),
+            account: strategy.account.clone(),
+            strategy: StrategySnapshot::capture(strategy),
+            journal: journal.clone(),
+            stats: SessionStats::from_positions(&strategy.positions),
+        }
+    }
+    pub fn load() -> Option<Self> {
+        open_json(STATE_SNAPSHOT_FILENAME)
+            .ok()
+            .and_then(|file| serde_json::from_value(file).ok())
+    }
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        create_json_file_atomically(STATE_SNAPSHOT_FILENAME, self)
+    }
+    // The strategy's state only carries over within the trading session it was saved in
+    pub fn session_is_current(
+        &self,
+        calendar: &TradingCalendar,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> bool {
+        calendar
+            .current_session(TRADING_PRODUCT, now)
+            .is_some_and(|session| session.open.timestamp_millis() <= self.saved_at)
+    }
+}
+
+// Restores the saved strategy state when it was saved on this server and account during the
+// current session. Returns the saved journal, which stands in for a missing journal file, and a
+// line to log.
+pub async fn restore_state(
+    strategy_rwl: &StrategyRWL,
+    calendar: &TradingCalendar,
+    clock: &ClockRef,
+) -> (OrderJournal, String) {
+    let snapshot = match StateSnapshot::load() {
+        Some(snapshot) => snapshot,
+        None => {
+            return (
+                OrderJournal::default(),
+                "No saved state to restore".to_string(),
+            )
+        }
+    };
+    if snapshot.environment != environment_label() {
+        return (
+            OrderJournal::default(),
+            format!(
+                "Saved state is from the {} server, not restoring it",
+                snapshot.environment
+            ),
+        );
+    }
+    if snapshot.account != ACCOUNT_NUMBER || !snapshot.session_is_current(calendar, clock.now()) {
+        return (
+            snapshot.journal,
+            "Saved state is from another account or session, starting the strategy fresh"
+                .to_string(),
+        );
+    }
+    let log = format!(
+        "Restored state saved at {} with {} positions\n{:#?}",
+        snapshot.saved_at,
+        snapshot.strategy.positions.len(),
+        snapshot.stats
+    );
+    snapshot
+        .strategy
+        .restore_into(&mut *strategy_rwl.write().await);
+    (snapshot.journal, log)
+}
+
+// Saves the snapshot every STATE_SNAPSHOT_MILLIS. A failure is logged once until saving works
+// again.
+pub async fn keep_state_saved(
+    strategy_rwl: StrategyRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+    clock: ClockRef,
+    report_rwl: ReportRWL,
+) {
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_millis(STATE_SNAPSHOT_MILLIS));
+    let mut last_error: Option<String> = None;
+    loop {
+        interval.tick().await;
+        let open = acct_comms_channel.read().await;
+        let strategy = strategy_rwl.read().await;
+        let snapshot = StateSnapshot::capture(&strategy, &open.journal, clock.now_millis());
+        drop(strategy);
+        drop(open);
+        let error = snapshot
+            .save()
+            .err()
+            .map(|e| format!("Could not save the state snapshot: {}", e));
+        if error != last_error {
+            if let Some(error) = &error {
+                program_log(error, ResourceType::Any, report_rwl.clone()).await;
+            }
+            last_error = error;
+        }
+    }
+}
@@ -1,23 +1,32 @@
 #![allow(dead_code)]
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::{cmp::Ordering, sync::Arc};
 pub type StrategyRWL = Arc<tokio::sync::RwLock<Strategy>>;
 use crate::{
+    accounting::Ledger,
     api_client::ResourceType,
-    credentials::USERNAME,
+    environment::environment_label,
+    bracket::{manage_bracket, Bracket, BracketSettings},
+    instrument_catalog::InstrumentCatalogRWL,
     md_socket::MarketDataRWL,
+    order_journal::{OrderIntent, OrderJournal},
+    position_sizing::entry_size,
+    price::{PriceSpec, Rounding},
     models::{
-        AcctCommChannelRWL, DOMSummary, OrderStatus, OrderUpdateMessage, Position, SimpleQuote,
+        AcctCommChannelRWL, AcctCommsChannel, DOMSummary, OrderStatus, OrderUpdateMessage, Position, SimpleQuote,
     },
     reporting::{program_log, ReportRWL},
+    session_boundary::SessionPhase,
     settings::{
-        ACCOUNT_NUMBER, DOM_THRESHOLD, LARGE_TRADE, ONE_SECOND, SINGLE_LEG_COMMISSION,
-        TRADE_CLOSE_TRIGGER, TRADING_SYMBOL, TS_PRESSURE_THRESHOLD,
+        ACCOUNT_NUMBER, DOM_THRESHOLD, LARGE_TRADE, TRADE_CLOSE_TRIGGER,
+        TS_PRESSURE_THRESHOLD, TS_PRESSURE_WINDOW,
     },
     time_and_sales::{OrderAction, TimeAndSalesItem, TimeAndSalesPressure},
+    watchdog::{session_open_for, FeedWatchdogRWL},
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MarketPosition {
     pub account_id: i64,
     pub contract_id: i64,
@@ -26,7 +35,13 @@ pub struct MarketPosition {
     pub entry_fill: Option<OrderStatus>,
     pub exit_trigger: String,
     pub exit_fill: Option<OrderStatus>,
+    // net of fees, set from the ledger once the exit fills are in it
     pub realized_pnl: f64,
+    // the ledger's gross realized pnl in the contract when the position was opened
+    #[serde(default)]
+    pub gross_baseline: f64,
+    #[serde(default)]
+    pub settled: bool,
 }
 impl MarketPosition {
     pub fn new_with_trigger(entry_trigger: String) -> Self {
@@ -39,10 +54,16 @@ impl MarketPosition {
             exit_trigger: String::new(),
             exit_fill: None,
             realized_pnl: 0.0,
+            gross_baseline: 0.0,
+            settled: false,
         }
     }
-    pub fn update_from_order_update(self, update: &OrderUpdateMessage) -> Self {
+    pub fn update_from_order_update(self, update: &OrderUpdateMessage, ledger: &Ledger) -> Self {
+        let entity = &update.data.entity;
         Self {
+            gross_baseline: ledger
+                .gross_realized(entity.account_id, entity.contract_id)
+                .unwrap_or_default(),
             account_id: update.data.entity.account_id,
             contract_id: update.data.entity.contract_id,
             net_pos: if update.data.entity.action == OrderAction::Buy {
@@ -56,59 +77,67 @@ impl MarketPosition {
             ..self
         }
     }
-    pub fn from_reversal(update: &OrderUpdateMessage) -> Self {
+    // The exit is recorded here, the realized pnl is only known once settle finds its fills in the
+    // ledger
+    pub fn close(self, update: &OrderUpdateMessage) -> Self {
         Self {
-            account_id: update.data.entity.account_id,
-            contract_id: update.data.entity.contract_id,
-            net_pos: if update.data.entity.action == OrderAction::Buy {
-                update.data.entity.cum_qty / 2
-            } else {
-                -update.data.entity.cum_qty / 2
-            },
-            entry_fill: Some(update.data.entity.to_owned()),
-            exit_fill: None,
-            realized_pnl: 0.0,
-            entry_trigger: String::new(),
-            exit_trigger: String::new(),
-        }
-    }
-    pub fn close(
-        self,
-        update: &OrderUpdateMessage,
-        big_point_value: f64,
-        single_leg_commission: f64,
-    ) -> Self {
-        let difference = update.data.entity.avg_px - self.entry_fill.as_ref().unwrap().avg_px;
-        Self {
-            realized_pnl: (self.net_pos as f64 * (big_point_value * difference))
-                - (2.0 * single_leg_commission) * (self.net_pos.abs() as f64),
             exit_fill: Some(update.data.entity.to_owned()),
             ..self
         }
     }
-    pub fn create_closing_ticket(&self, request_id: i64) -> String {
-        let action = match self.net_pos.cmp(&0) {
+    // Takes the realized pnl from the ledger once the book is flat again, the gross pnl realized
+    // since the entry less the fees of both sides. Returns it the one time it is settled.
+    pub fn settle(&mut self, ledger: &Ledger) -> Option<f64> {
+        self.exit_fill.as_ref()?;
+        self.settle_closed(ledger)
+    }
+    // Settles a position known to be closed without its exit order, like one closed while the
+    // strategy was stopped
+    pub fn settle_closed(&mut self, ledger: &Ledger) -> Option<f64> {
+        if self.settled {
+            return None;
+        }
+        let book = ledger.book(self.account_id, self.contract_id)?;
+        if book.net_pos() != 0 {
+            return None;
+        }
+        let terms = ledger.terms.get(&self.contract_id)?;
+        let gross = book.gross_realized(terms) - self.gross_baseline;
+        let fees = 2.0 * terms.fees.per_side() * self.net_pos.abs() as f64;
+        self.realized_pnl = ((gross - fees) * 100.0).round() / 100.0;
+        self.settled = true;
+        Some(self.realized_pnl)
+    }
+    pub fn is_open(&self) -> bool {
+        self.exit_fill.is_none() && !self.settled
+    }
+    pub fn closing_action(&self) -> OrderAction {
+        match self.net_pos.cmp(&0) {
             Ordering::Greater => OrderAction::Sell,
             Ordering::Less => OrderAction::Buy,
             Ordering::Equal => OrderAction::Unknown,
-        };
-        let order_ticket = create_market_order_ticket(action, self.net_pos.abs(), self.account_id);
+        }
+    }
+    pub fn create_closing_ticket(
+        &self,
+        request_id: i64,
+        cl_ord_id: &str,
+        account_spec: &str,
+        symbol: &str,
+    ) -> String {
+        let order_ticket = create_market_order_ticket(
+            self.closing_action(),
+            self.net_pos.abs(),
+            account_spec,
+            self.account_id,
+            symbol,
+            cl_ord_id,
+        );
         create_order_request(request_id, order_ticket)
     }
-    pub fn unrealized_pnl(&self, quote: &SimpleQuote, point_value: f64) -> f64 {
-        match self.net_pos.cmp(&0) {
-            Ordering::Less => {
-                self.net_pos.abs() as f64
-                    * point_value
-                    * (self.entry_fill.as_ref().unwrap().avg_px - quote.ask)
-            }
-            Ordering::Equal => 0.0,
-            Ordering::Greater => {
-                self.net_pos as f64
-                    * point_value
-                    * (quote.bid - self.entry_fill.as_ref().unwrap().avg_px)
-            }
-        }
+    // Open pnl of the account's book in the contract, which the strategy alone trades
+    pub fn unrealized_pnl(&self, ledger: &Ledger, quote: &SimpleQuote) -> Option<f64> {
+        ledger.unrealized(self.account_id, self.contract_id, quote)
     }
     pub fn matches_account_position(&self, account_position: &Position) -> bool {
         self.net_pos == account_position.net_pos
@@ -127,6 +156,17 @@ pub struct Strategy {
     pub status: StrategyStatus,
     pub positions: Vec<MarketPosition>,
     pub last_report_instant: tokio::time::Instant,
+    pub session_phase: SessionPhase,
+    // the front month of TRADING_PRODUCT, set by the contract resolver
+    pub symbol: String,
+    // a position closed by a contract roll, reopened on the new contract before any signal
+    pub pending_roll_entry: Option<(OrderAction, i64)>,
+    // the protective orders of the open position
+    pub bracket: Option<Bracket>,
+    // the account the strategy trades, the trade copier mirrors it onto its followers
+    pub account: String,
+    // client order id of the latest entry or exit, the journal names the order to wait for
+    pub order_cl_ord_id: Option<String>,
 }
 impl Strategy {
     pub fn is_opposite(&self, action: OrderAction) -> bool {
@@ -145,11 +185,62 @@ impl Strategy {
     }
     pub fn has_open_position(&self) -> bool {
         if let Some(position) = self.positions.last() {
-            position.exit_fill.is_none()
+            position.is_open()
         } else {
             false
         }
     }
+    // Squares the positions with the one the account holds. A restored position the account still
+    // holds keeps its triggers and bracket, one it no longer holds was closed while stopped and is
+    // settled from the ledger's fills.
+    pub fn adopt_account_position(
+        &mut self,
+        held: Option<MarketPosition>,
+        ledger: &Ledger,
+    ) -> Vec<String> {
+        let mut log = Vec::new();
+        if let Some(last) = self.positions.last_mut() {
+            if last.is_open() {
+                let still_held = held.as_ref().is_some_and(|held| {
+                    held.account_id == last.account_id
+                        && held.contract_id == last.contract_id
+                        && held.net_pos == last.net_pos
+                });
+                if still_held {
+                    log.push(format!(
+                        "Resumed position of {} in contract {}",
+                        last.net_pos, last.contract_id
+                    ));
+                    return log;
+                }
+                if last.net_pos == 0 {
+                    self.positions.pop();
+                } else {
+                    last.exit_trigger = "Closed while stopped".to_string();
+                    match last.settle_closed(ledger) {
+                        Some(realized_pnl) => log.push(format!(
+                            "Position of {} in contract {} was closed while the strategy was stopped. Trade Realized Pnl {}",
+                            last.net_pos, last.contract_id, realized_pnl
+                        )),
+                        None => {
+                            log.push(format!(
+                                "Position of {} in contract {} was closed while the strategy was stopped and its pnl is not in the ledger",
+                                last.net_pos, last.contract_id
+                            ));
+                            self.positions.pop();
+                        }
+                    }
+                }
+            }
+        }
+        if self.bracket.take().is_some() {
+            log.push("Dropped the bracket of a position the account no longer holds".to_string());
+        }
+        if let Some(held) = held {
+            self.positions.push(held);
+        }
+        log
+    }
     pub fn new() -> Self {
         Self {
             dom_extreme: (String::new(), DOMSummary::new()),
@@ -160,31 +251,48 @@ impl Strategy {
             positions: Vec::new(),
             last_report_instant: tokio::time::Instant::now(),
             largest_single_item: None,
+            session_phase: SessionPhase::Unknown,
+            symbol: String::new(),
+            pending_roll_entry: None,
+            bracket: None,
+            account: ACCOUNT_NUMBER.to_string(),
+            order_cl_ord_id: None,
         }
     }
+    pub fn order_id(&self, journal: &OrderJournal) -> Option<i64> {
+        journal.order_id(self.order_cl_ord_id.as_ref()?)
+    }
     pub fn total_pnl(&self) -> f64 {
         self.positions
             .iter()
             .fold(0.0, |acc, x| acc + x.realized_pnl)
     }
-    pub fn open_pnl(&self, quote: SimpleQuote, point_value: f64) -> Option<f64> {
-        if let Some(position) = self.positions.last() {
-            if position.exit_fill.is_none() {
-                Some(position.unrealized_pnl(&quote, point_value))
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+    pub fn open_pnl(&self, ledger: &Ledger, quote: &SimpleQuote) -> Option<f64> {
+        self.positions
+            .last()
+            .filter(|position| position.is_open())
+            .and_then(|position| position.unrealized_pnl(ledger, quote))
     }
-    pub fn summary(&self, quote: SimpleQuote, point_value: f64) -> String {
+    pub fn summary(&self, ledger: &Ledger, quote: &SimpleQuote) -> String {
         format!(
             "Total Session Pnl {}\n Open Pnl {:#?}",
             self.total_pnl(),
-            self.open_pnl(quote, point_value)
+            self.open_pnl(ledger, quote)
         )
     }
+    // Settles closed positions whose exit fills have reached the ledger. Returns lines to log.
+    pub fn settle_positions(&mut self, ledger: &Ledger) -> Vec<String> {
+        self.positions
+            .iter_mut()
+            .filter_map(|position| {
+                let realized_pnl = position.settle(ledger)?;
+                Some(format!(
+                    "Trade Realized Pnl {} ({})",
+                    realized_pnl, position.exit_trigger
+                ))
+            })
+            .collect()
+    }
 }
 impl Default for Strategy {
     fn default() -> Self {
@@ -195,23 +303,110 @@ pub async fn calculate(
     market_data_rwl: MarketDataRWL,
     strategy_rwl: StrategyRWL,
     account_data_rwl: AcctCommChannelRWL,
+    catalog_rwl: InstrumentCatalogRWL,
+    watchdog_rwl: FeedWatchdogRWL,
     report_rwl: ReportRWL,
 ) {
-    let signal = generate_signal(market_data_rwl.read().await.clone(), strategy_rwl.clone()).await;
+    // computed under the read guard, the market data is far too large to copy every tick
+    let market_data = market_data_rwl.read().await;
+    let signal = generate_signal(&market_data, strategy_rwl.clone()).await;
+    drop(market_data);
+    if let Ok(account_data) = account_data_rwl.try_read() {
+        let settled = match strategy_rwl.try_write() {
+            Ok(mut strategy) => strategy.settle_positions(&account_data.ledger),
+            Err(_) => Vec::new(),
+        };
+        drop(account_data);
+        for line in settled {
+            program_log(&line, ResourceType::Trading, report_rwl.clone()).await;
+        }
+    }
     if let Ok(strategy) = strategy_rwl.try_read() {
         match strategy.status {
             StrategyStatus::AwaitingTrades => {
                 if let Ok(market_data) = market_data_rwl.try_read() {
                     drop(strategy);
-                    if !strategy_rwl.read().await.sent_entry_orders && !signal.0.is_unknown() {
+                    // never enter on frozen data or outside the trading session
+                    let strategy = strategy_rwl.read().await;
+                    let entries_allowed = strategy.session_phase.allows_entries();
+                    let symbol = strategy.symbol.clone();
+                    let pending_roll_entry = strategy.pending_roll_entry;
+                    let account = strategy.account.clone();
+                    drop(strategy);
+                    let watchdog = watchdog_rwl.read().await;
+                    let catalog = catalog_rwl.read().await;
+                    // a contract missing from the catalog has no session its feed can be judged by
+                    let feed_is_stale = market_data.contract(&symbol).is_none_or(|contract| {
+                        catalog.product_of(contract.id).is_none_or(|product| {
+                            let open_for = session_open_for(
+                                &market_data.calendar,
+                                &product.symbol,
+                                market_data.clock.now(),
+                            );
+                            watchdog.is_stale(contract.id, open_for)
+                        })
+                    });
+                    drop(catalog);
+                    drop(watchdog);
+                    let (signal, amount, margin_unchecked) = match pending_roll_entry {
+                        Some((action, qty)) => ((action, "Contract roll".to_string()), qty, false),
+                        None if signal.0.is_unknown() => (signal, 0, false),
+                        None => {
+                            let account_data = account_data_rwl.read().await;
+                            let sizing =
+                                entry_size(
+                                    &market_data,
+                                    &account_data.ledger,
+                                    account_data.user_data.as_ref(),
+                                    &account,
+                                    &symbol,
+                                );
+                            (signal, sizing.qty, sizing.margin_unchecked)
+                        }
+                    };
+                    if !strategy_rwl.read().await.sent_entry_orders
+                        && !signal.0.is_unknown()
+                        && amount > 0
+                        && !feed_is_stale
+                        && entries_allowed
+                    {
+                        if margin_unchecked {
+                            program_log(
+                                &format!(
+                                    "No initial margin known for {}, {} contracts sized without the margin cap",
+                                    symbol, amount
+                                ),
+                                ResourceType::Trading,
+                                report_rwl.clone(),
+                            )
+                            .await;
+                        }
+                        // the bracket is priced from the side of the book the entry takes
+                        let bracket = BracketSettings::from_settings().and_then(|settings| {
+                            let contract = market_data.contract(&symbol)?;
+                            let quote = market_data.time_and_sales.get_quote(contract.id)?;
+                            let reference = if signal.0 == OrderAction::Buy {
+                                quote.ask
+                            } else {
+                                quote.bid
+                            };
+                            let price = PriceSpec::from_contract(contract)
+                                .price(reference, Rounding::Nearest);
+                            Some(Bracket::new(settings, signal.0, amount, price))
+                        });
                         send_orders(
                             signal,
-                            1,
+                            amount,
                             account_data_rwl.clone(),
                             strategy_rwl.clone(),
                             LegType::Entry,
+                            bracket,
                         )
-                        .await
+                        .await;
+                        let mut write = strategy_rwl.write().await;
+                        if write.sent_entry_orders {
+                            write.pending_roll_entry = None;
+                        }
                     }
                     drop(market_data)
                 }
@@ -219,17 +414,15 @@ pub async fn calculate(
             StrategyStatus::SentEntryOrders => {
                 if let Ok(account_data) = account_data_rwl.try_read() {
                     if let Some(order_update) = &account_data.order_update {
-                        if !account_data.order_id_messages.is_empty()
-                            && order_update.is_filled()
-                            && order_update.id()
-                                == account_data.order_id_messages.last().unwrap().data.order_id
+                        if order_update.is_filled()
+                            && strategy.order_id(&account_data.journal) == Some(order_update.id())
                         {
                             drop(strategy);
                             if let Ok(mut write) = strategy_rwl.try_write() {
                                 let index = write.positions.len() - 1;
                                 write.positions[index] = write.positions[index]
                                     .clone()
-                                    .update_from_order_update(order_update);
+                                    .update_from_order_update(order_update, &account_data.ledger);
                                 write.status = StrategyStatus::InATrade;
                                 program_log(
                                     &format!("Strategy Status -> {:#?}", StrategyStatus::InATrade),
@@ -247,15 +440,27 @@ pub async fn calculate(
             StrategyStatus::InATrade => {
                 if let Ok(market_data) = market_data_rwl.try_read() {
                     drop(strategy);
+                    if manage_bracket(
+                        &market_data,
+                        strategy_rwl.clone(),
+                        account_data_rwl.clone(),
+                        report_rwl.clone(),
+                    )
+                    .await
+                    {
+                        return;
+                    }
                     let strategy = strategy_rwl.read().await;
                     if strategy.is_opposite(signal.0) {
+                        let qty = strategy.net_pos().abs();
                         drop(strategy);
                         send_orders(
                             signal,
-                            1,
+                            qty,
                             account_data_rwl.clone(),
                             strategy_rwl.clone(),
                             LegType::Exit,
+                            None,
                         )
                         .await;
                         program_log(
@@ -272,10 +477,16 @@ pub async fn calculate(
                         .iter()
                         .find(|contract| contract.id == position.contract_id)
                         .unwrap();
-                    if let Some(quote) =
-                        &market_data.time_and_sales.get_quote(contract.historical_id)
-                    {
-                        let pnl = position.unrealized_pnl(quote, contract.big_point_value);
+                    let pnl = match (
+                        market_data.time_and_sales.get_quote(contract.id),
+                        account_data_rwl.try_read(),
+                    ) {
+                        (Some(quote), Ok(account_data)) => {
+                            position.unrealized_pnl(&account_data.ledger, &quote)
+                        }
+                        _ => None,
+                    };
+                    if let Some(pnl) = pnl {
                         if pnl < -TRADE_CLOSE_TRIGGER && !strategy.sent_exit_orders {
                             drop(strategy);
                             if let Ok(mut write) = strategy_rwl.try_write() {
@@ -286,10 +497,25 @@ pub async fn calculate(
                                         report_rwl.clone(),
                                     )
                                     .await;
-                                    let request_id = (data.sent_requests.len() + 2) as i64;
-                                    let request = position.create_closing_ticket(request_id);
                                     if !write.sent_exit_orders {
-                                        data.unsent_requests.push(request);
+                                        cancel_bracket(&mut write, &mut data);
+                                        let intent = OrderIntent::market(
+                                            position.account_id,
+                                            &write.symbol,
+                                            position.closing_action(),
+                                            position.net_pos.abs(),
+                                        );
+                                        let account = write.account.clone();
+                                        let symbol = write.symbol.clone();
+                                        if let Ok(cl_ord_id) =
+                                            data.submit_order(intent, |request_id, cl_ord_id| {
+                                                position.create_closing_ticket(
+                                                    request_id, cl_ord_id, &account, &symbol,
+                                                )
+                                            })
+                                        {
+                                            write.order_cl_ord_id = Some(cl_ord_id);
+                                        }
                                     }
                                     write.sent_exit_orders = true;
                                     let index = write.positions.len()-1;
@@ -316,9 +542,8 @@ pub async fn calculate(
             StrategyStatus::SentExitOrders => {
                 if let Ok(mut data) = account_data_rwl.try_write() {
                     if let Some(order_update) = data.order_update.clone() {
-                        if !data.order_id_messages.is_empty()
-                            && order_update.is_filled() 
-                            && order_update.id() == data.order_id_messages.last().unwrap().data.order_id
+                        if order_update.is_filled()
+                            && strategy.order_id(&data.journal) == Some(order_update.id())
                         {
                             drop(strategy);
                             if let Ok(mut write) = strategy_rwl.try_write() {
@@ -340,31 +565,9 @@ pub async fn calculate(
                                     .await;
                                     write.sent_entry_orders = false;
                                     write.sent_exit_orders = false;
-                                    let big_point_value = market_data_rwl
-                                        .read()
-                                        .await
-                                        .contract_ids
-                                        .iter()
-                                        .find(|contract| {
-                                            contract.id
-                                                == write.positions.last().unwrap().contract_id
-                                        })
-                                        .unwrap()
-                                        .big_point_value;
-                                    let closed_position = write.positions[index].clone().close(
-                                        &order_update,
-                                        big_point_value,
-                                        SINGLE_LEG_COMMISSION,
-                                    );
-                                    program_log(
-                                        &format!(
-                                            "Trade Realized Pnl {}",
-                                            closed_position.realized_pnl
-                                        ),
-                                        ResourceType::Trading,
-                                        report_rwl.clone(),
-                                    )
-                                    .await;
+                                    write.bracket = None;
+                                    let closed_position =
+                                        write.positions[index].clone().close(&order_update);
                                     write.positions[index] = closed_position;
                                 }
                                 drop(write);
@@ -379,21 +582,37 @@ pub async fn calculate(
             StrategyStatus::Unaware => {
                 drop(strategy);
                 let mut write = strategy_rwl.write().await;
-                if let Some(user_data) = &account_data_rwl.read().await.user_data {
-                    if !user_data.is_flat() {
-                        let market_position = user_data
-                            .data
-                            .positions
-                            .last()
-                            .unwrap()
-                            .to_market_position();
-                        program_log(
-                            &format!("Account has position {:#?}", market_position),
-                            ResourceType::Trading,
-                            report_rwl.clone(),
-                        )
-                        .await;
-                        write.positions.push(market_position);
+                let account_data = account_data_rwl.read().await;
+                if let Some(user_data) = &account_data.user_data {
+                    let position = user_data
+                        .data
+                        .account(&write.account)
+                        .and_then(|account| user_data.data.open_positions(account.id).last());
+                    let held = position
+                        .map(|position| {
+                            position.to_market_position(
+                                &account_data.ledger,
+                                account_data.clock.as_ref(),
+                            )
+                        });
+                    match &held {
+                        Some(market_position) => {
+                            program_log(
+                                &format!("Account has position {:#?}", market_position),
+                                ResourceType::Trading,
+                                report_rwl.clone(),
+                            )
+                            .await
+                        }
+                        None => {
+                            program_log("Account is flat", ResourceType::Trading, report_rwl.clone())
+                                .await
+                        }
+                    }
+                    for line in write.adopt_account_position(held, &account_data.ledger) {
+                        program_log(&line, ResourceType::Trading, report_rwl.clone()).await;
+                    }
+                    if write.has_open_position() {
                         program_log(
                             &format!("Strategy Status -> {:#?}", StrategyStatus::InATrade),
                             ResourceType::Trading,
@@ -402,12 +621,6 @@ pub async fn calculate(
                         .await;
                         write.status = StrategyStatus::InATrade;
                     } else {
-                        program_log(
-                            &"Account is flat".to_string(),
-                            ResourceType::Trading,
-                            report_rwl.clone(),
-                        )
-                        .await;
                         program_log(
                             &format!("Strategy Status -> {:#?}", StrategyStatus::AwaitingTrades),
                             ResourceType::Trading,
@@ -417,20 +630,30 @@ pub async fn calculate(
                         write.status = StrategyStatus::AwaitingTrades
                     }
                 };
+                drop(account_data);
                 drop(write);
             }
         }
     }
 }
 
-pub fn create_market_order_ticket(order_action: OrderAction, qty: i64, acct_id: i64) -> Value {
+pub fn create_market_order_ticket(
+    order_action: OrderAction,
+    qty: i64,
+    account_spec: &str,
+    acct_id: i64,
+    symbol: &str,
+    cl_ord_id: &str,
+) -> Value {
     json!({
         "action": order_action,
-        "symbol": TRADING_SYMBOL,
+        "symbol": symbol,
         "orderQty": qty,
         "orderType": "Market",
-        "accountSpec": USERNAME,
+        "accountSpec": account_spec,
         "accountId": acct_id,
+        "clOrdId": cl_ord_id,
+        "text": environment_label(),
         "isAutomated": true
     })
 }
@@ -449,7 +672,7 @@ pub enum StrategyStatus {
 }
 
 async fn generate_signal(
-    market_data: crate::socket_processing::MarketData,
+    market_data: &crate::socket_processing::MarketData,
     strategy_rwl: StrategyRWL,
 ) -> (OrderAction, String) {
     if let Ok(mut strategy) = strategy_rwl.try_write() {
@@ -458,23 +681,23 @@ async fn generate_signal(
         } else {
             LARGE_TRADE
         };
-        let now = chrono::Utc::now().timestamp_millis() - ONE_SECOND;
-        if let Some(large_item) = market_data
+        let contract_id = match market_data.contract(&strategy.symbol) {
+            Some(contract) => contract.id,
+            None => return (OrderAction::Unknown, String::new()),
+        };
+        let ts_stats = market_data
             .time_and_sales
-            .items
-            .iter()
-            .rev()
-            .find(|item| item.qty >= size_trigger && item.timestamp >= now)
-        {
+            .get_stats(contract_id, TS_PRESSURE_WINDOW, size_trigger, market_data.clock.as_ref());
+        if let Some(large_item) = ts_stats.large_prints.last() {
             if large_item.action != OrderAction::Unknown {
                 return (large_item.action, format!("Large Trade {}", serde_json::to_string_pretty(large_item).unwrap()));
             }
         }
         let mut dom_summary = DOMSummary::new();
         for dom in &market_data.market_depth {
-            dom_summary = dom_summary.sum(dom.combine());
+            dom_summary = dom_summary.sum(dom.combine(market_data.clock.as_ref()));
         }
-        let ts_pressure = market_data.time_and_sales.get_pressure();
+        let ts_pressure = ts_stats.pressure;
         let ts_pressure_threshold =
             if TS_PRESSURE_THRESHOLD > strategy.ts_pressure_extreme.net.abs() {
                 TS_PRESSURE_THRESHOLD
@@ -490,7 +713,16 @@ async fn generate_signal(
         if dom_summary.net.abs() > strategy.dom_extreme.1.net.abs() {
             strategy.dom_extreme = (dom_summary.get_time(), dom_summary.clone())
         }
-        let signal_string = format!("{} \n{}", serde_json::to_string_pretty(&ts_pressure).unwrap(), serde_json::to_string_pretty(&dom_summary).unwrap());
+        let indicators = market_data
+            .indicators
+            .get(&contract_id)
+            .map(|indicators| indicators.snapshot());
+        let signal_string = format!(
+            "{} \n{} \n{}",
+            serde_json::to_string_pretty(&ts_pressure).unwrap(),
+            serde_json::to_string_pretty(&dom_summary).unwrap(),
+            serde_json::to_string_pretty(&indicators).unwrap()
+        );
         drop(strategy);
         if buy_signal {
             (
@@ -510,33 +742,57 @@ async fn generate_signal(
     }
 }
 
+// Entries with a bracket go out as one order/placeOSO
 pub async fn send_orders(
     action: (OrderAction, String),
     amount: i64,
     account_data_rwl: AcctCommChannelRWL,
     strategy_rwl: StrategyRWL,
     leg_type: LegType,
+    entry_bracket: Option<Bracket>,
 ) {
     if let Ok(mut open) = account_data_rwl.try_write() {
         if let Some(user_data) = &open.user_data {
             if let Ok(mut write) = strategy_rwl.try_write() {
-                let ticket = create_market_order_ticket(
-                    action.0,
-                    amount,
-                    user_data
-                        .data
-                        .accounts
-                        .iter()
-                        .find(|acct| acct.name == ACCOUNT_NUMBER)
-                        .unwrap()
-                        .id,
-                );
-                let id = (open.sent_requests.len() + 1) as i64;
-                let request = create_order_request(id, ticket);
+                let account_id = match user_data.data.account(&write.account) {
+                    Some(account) => account.id,
+                    None => return,
+                };
+                let account = write.account.clone();
+                let symbol = write.symbol.clone();
+                let market_order = |request_id: i64, cl_ord_id: &str| {
+                    let ticket = create_market_order_ticket(
+                        action.0, amount, &account, account_id, &symbol, cl_ord_id,
+                    );
+                    create_order_request(request_id, ticket)
+                };
                 match leg_type {
                     LegType::Entry => {
                         if !write.sent_entry_orders {
-                            open.unsent_requests.push(request);
+                            let submitted = match entry_bracket {
+                                Some(mut bracket) => {
+                                    let intent =
+                                        OrderIntent::oso(account_id, &symbol, action.0, amount);
+                                    let submitted =
+                                        open.submit_order(intent, |request_id, cl_ord_id| {
+                                            bracket.request_id = request_id;
+                                            bracket.oso_request(&account, account_id, &symbol, cl_ord_id)
+                                        });
+                                    if submitted.is_ok() {
+                                        write.bracket = Some(bracket);
+                                    }
+                                    submitted
+                                }
+                                None => open.submit_order(
+                                    OrderIntent::market(account_id, &symbol, action.0, amount),
+                                    market_order,
+                                ),
+                            };
+                            let cl_ord_id = match submitted {
+                                Ok(cl_ord_id) => cl_ord_id,
+                                Err(_) => return,
+                            };
+                            write.order_cl_ord_id = Some(cl_ord_id);
                             write.sent_entry_orders = true;
                             write.status = StrategyStatus::SentEntryOrders;
                             write
@@ -546,7 +802,16 @@ pub async fn send_orders(
                     }
                     LegType::Exit => {
                         if !write.sent_exit_orders {
-                            open.unsent_requests.push(request);
+                            let intent = OrderIntent::market(account_id, &symbol, action.0, amount);
+                            // the brackets stay in place when the exit was already sent
+                            let now = open.clock.now_millis();
+                            if open.journal.duplicate_of(&intent, now).is_some() {
+                                return;
+                            }
+                            cancel_bracket(&mut write, &mut open);
+                            if let Ok(cl_ord_id) = open.submit_order(intent, market_order) {
+                                write.order_cl_ord_id = Some(cl_ord_id);
+                            }
                             write.status = StrategyStatus::SentExitOrders;
                             let index = write.positions.len() - 1;
                             write.sent_exit_orders = true;
@@ -558,12 +823,142 @@ pub async fn send_orders(
             }
         }
         drop(open);
-        return;
     }
 }
 
+// Queues cancels for the open position's protective orders, ahead of an exit of our own
+pub fn cancel_bracket(strategy: &mut Strategy, open: &mut AcctCommsChannel) {
+    if let Some(bracket) = strategy.bracket.take() {
+        let request_id = open.reserve_request_ids(2);
+        open.unsent_requests
+            .extend(bracket.cancel_requests(request_id));
+    }
+}
 
 pub enum LegType {
     Entry,
     Exit,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        accounting::{ContractTerms, FeeSchedule},
+        clock::ManualClock,
+        models::Fill,
+        state_snapshot::SessionStats,
+    };
+
+    fn ledger() -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.terms.insert(
+            1,
+            ContractTerms {
+                symbol: "ESM2".to_string(),
+                product: "ES".to_string(),
+                spec: PriceSpec::new(0.25, 50.0),
+                fees: FeeSchedule {
+                    exchange: 1.0,
+                    clearing: 0.0,
+                    nfa: 0.0,
+                    commission: 0.0,
+                },
+                initial_margin: None,
+            },
+        );
+        ledger.record_order(10, 7);
+        ledger
+    }
+
+    fn fill(id: i64, action: OrderAction, qty: i64, price: f64) -> Fill {
+        Fill {
+            id,
+            order_id: 10,
+            contract_id: 1,
+            timestamp: String::new(),
+            action,
+            qty,
+            price,
+            active: true,
+        }
+    }
+
+    #[test]
+    fn positions_settle_from_the_ledger_once_their_exit_is_in_it() {
+        let mut ledger = ledger();
+        // a trade before the position's own
+        ledger.apply_fill(&fill(1, OrderAction::Buy, 1, 100.0));
+        ledger.apply_fill(&fill(2, OrderAction::Sell, 1, 101.0));
+        let mut position = MarketPosition {
+            account_id: 7,
+            contract_id: 1,
+            net_pos: 2,
+            gross_baseline: ledger.gross_realized(7, 1).unwrap(),
+            ..MarketPosition::new_with_trigger(String::new())
+        };
+        ledger.apply_fill(&fill(3, OrderAction::Buy, 2, 102.0));
+        let quote = SimpleQuote {
+            bid: 102.5,
+            ask: 102.75,
+        };
+        assert_eq!(position.unrealized_pnl(&ledger, &quote), Some(50.0));
+        position.exit_fill = Some(OrderStatus::new(103.0, OrderAction::Sell, &ManualClock::new(0)));
+        assert_eq!(position.settle(&ledger), None);
+        ledger.apply_fill(&fill(4, OrderAction::Sell, 2, 103.0));
+        // 100 gross less 1 per contract per side
+        assert_eq!(position.settle(&ledger), Some(96.0));
+        assert_eq!(position.settle(&ledger), None);
+        assert_eq!(position.realized_pnl, 96.0);
+    }
+
+    fn restored(ledger: &Ledger) -> Strategy {
+        let mut strategy = Strategy::new();
+        strategy.positions.push(MarketPosition {
+            account_id: 7,
+            contract_id: 1,
+            net_pos: 2,
+            gross_baseline: ledger.gross_realized(7, 1).unwrap(),
+            ..MarketPosition::new_with_trigger(String::new())
+        });
+        strategy
+    }
+
+    #[test]
+    fn positions_closed_while_stopped_are_settled_from_the_ledger() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, OrderAction::Buy, 2, 100.0));
+        let mut strategy = restored(&ledger);
+        ledger.apply_fill(&fill(2, OrderAction::Sell, 2, 101.0));
+        let log = strategy.adopt_account_position(None, &ledger);
+        // 100 gross less 1 per contract per side
+        assert!(log[0].ends_with("Trade Realized Pnl 96"));
+        assert_eq!(strategy.positions.len(), 1);
+        assert!(strategy.positions[0].settled);
+        assert!(!strategy.has_open_position());
+        let stats = SessionStats::from_positions(&strategy.positions);
+        assert_eq!((stats.trades, stats.realized_pnl), (1, 96.0));
+    }
+
+    #[test]
+    fn positions_the_account_still_holds_are_resumed() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, OrderAction::Buy, 2, 100.0));
+        let mut strategy = restored(&ledger);
+        let held = strategy.positions[0].clone();
+        strategy.adopt_account_position(Some(held), &ledger);
+        assert_eq!(strategy.positions.len(), 1);
+        assert!(!strategy.positions[0].settled);
+        assert!(strategy.has_open_position());
+    }
+
+    #[test]
+    fn closed_positions_missing_from_the_ledger_are_dropped() {
+        let mut ledger = ledger();
+        ledger.apply_fill(&fill(1, OrderAction::Buy, 2, 100.0));
+        let mut strategy = restored(&ledger);
+        let log = strategy.adopt_account_position(None, &ledger);
+        assert!(log[0].ends_with("its pnl is not in the ledger"));
+        assert!(strategy.positions.is_empty());
+    }
+}
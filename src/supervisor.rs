@@ -0,0 +1,398 @@
+use core::time;
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+use futures_util::StreamExt;
+use tokio::sync::watch;
+use tokio_stream::wrappers::IntervalStream;
+use tokio_tungstenite::connect_async;
+
+use crate::{
+    acct_socket::{incoming_acct_socket, outgoing_acct_socket},
+    api_client::{Endpoint, ResourceType},
+    md_socket::{
+        process_market_data_messages, process_outgoing_md_messages, APIClientRWL,
+        MarketDataIncomingRWL, MarketDataOutGoingRWL, MarketDataRWL,
+    },
+    models::AcctCommChannelRWL,
+    reporting::{program_log, send_txt_message, ReportRWL},
+    settings::{
+        ALERT_PHONE_NUMBER, RECONNECT_ALERT_ATTEMPTS, RECONNECT_INITIAL_BACKOFF_MILLIS,
+        RECONNECT_MAX_BACKOFF_MILLIS, SOCKET_INTERVAL_SPEED, STABLE_CONNECTION_SECONDS,
+    },
+    watchdog::{watch_staleness, FeedWatchdogRWL},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Authorized,
+    Backoff { attempt: u32, delay_millis: u64 },
+    Terminated,
+}
+
+// Holds both ends of a watch channel so the state can be published even when nobody is
+// listening yet. Anyone interested calls subscribe() and awaits changes.
+#[derive(Debug)]
+pub struct ConnectionMonitor {
+    sender: watch::Sender<ConnectionState>,
+    receiver: watch::Receiver<ConnectionState>,
+}
+impl ConnectionMonitor {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(ConnectionState::Disconnected);
+        Self { sender, receiver }
+    }
+    pub fn set(&self, state: ConnectionState) {
+        if *self.receiver.borrow() != state {
+            let _ = self.sender.send(state);
+        }
+    }
+    pub fn state(&self) -> ConnectionState {
+        *self.receiver.borrow()
+    }
+    pub fn subscribe(&self) -> watch::Receiver<ConnectionState> {
+        self.receiver.clone()
+    }
+    pub fn is_authorized(&self) -> bool {
+        self.state() == ConnectionState::Authorized
+    }
+}
+impl Default for ConnectionMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+pub type ConnectionMonitorRef = Arc<ConnectionMonitor>;
+
+// Exponential backoff with jitter. Each delay is drawn between half and all of the current
+// exponential step so that both sockets do not retry in lockstep.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    pub initial_millis: u64,
+    pub max_millis: u64,
+    pub attempt: u32,
+}
+impl Backoff {
+    pub fn new(initial_millis: u64, max_millis: u64) -> Self {
+        Self {
+            initial_millis,
+            max_millis,
+            attempt: 0,
+        }
+    }
+    pub fn next_delay(&mut self) -> time::Duration {
+        let step = self
+            .initial_millis
+            .saturating_mul(2u64.saturating_pow(self.attempt))
+            .min(self.max_millis);
+        self.attempt += 1;
+        let jittered = (step as f64 * (0.5 + 0.5 * jitter_fraction())) as u64;
+        time::Duration::from_millis(jittered)
+    }
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(RECONNECT_INITIAL_BACKOFF_MILLIS, RECONNECT_MAX_BACKOFF_MILLIS)
+    }
+}
+
+fn jitter_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    (hasher.finish() % 10_000) as f64 / 10_000.0
+}
+
+async fn wait_before_retry(
+    backoff: &mut Backoff,
+    monitor: &ConnectionMonitor,
+    resource_type: ResourceType,
+    report_rwl: ReportRWL,
+) {
+    let delay = backoff.next_delay();
+    monitor.set(ConnectionState::Backoff {
+        attempt: backoff.attempt,
+        delay_millis: delay.as_millis() as u64,
+    });
+    let report = format!(
+        "Reconnect attempt {} in {} ms",
+        backoff.attempt,
+        delay.as_millis()
+    );
+    program_log(&report, resource_type, report_rwl.clone()).await;
+    if backoff.attempt == RECONNECT_ALERT_ATTEMPTS {
+        let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+    }
+    tokio::time::sleep(delay).await;
+}
+
+// Publishes Authorized once the socket has acknowledged the authorize request
+async fn watch_authorization<F: Fn() -> Option<bool>>(is_authorized: F, monitor: &ConnectionMonitor) {
+    let mut interval = tokio::time::interval(time::Duration::from_millis(100));
+    loop {
+        interval.tick().await;
+        if let Some(true) = is_authorized() {
+            monitor.set(ConnectionState::Authorized);
+        }
+    }
+}
+
+// Queues a new authorize message on the live session every time the token is replaced
+async fn reauthorize_on_renewal<F, Fut>(mut token_changes: watch::Receiver<u64>, reauthorize: F)
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    while token_changes.changed().await.is_ok() {
+        reauthorize().await;
+    }
+    std::future::pending::<()>().await
+}
+
+// Keeps the market data socket connected on its own. Every new session re-authorizes with the
+// client's current token and replays all subscriptions. Returns once the socket is terminated.
+#[allow(clippy::too_many_arguments)]
+pub async fn supervise_market_data(
+    md_url: &str,
+    market_data_incoming_rwl: MarketDataIncomingRWL,
+    market_data_outgoing_rwl: MarketDataOutGoingRWL,
+    market_data: MarketDataRWL,
+    api_client_rwl: APIClientRWL,
+    watchdog_rwl: FeedWatchdogRWL,
+    monitor: ConnectionMonitorRef,
+    report_rwl: ReportRWL,
+) {
+    let resource_type = ResourceType::MarketData;
+    let mut backoff = Backoff::default();
+    let mut connected_before = false;
+    loop {
+        if market_data_outgoing_rwl.read().await.terminated {
+            break;
+        }
+        monitor.set(ConnectionState::Connecting);
+        match connect_async(md_url).await {
+            Ok((stream, response)) => {
+                program_log(
+                    &format!("Websocket connection status: {:#?}", response.status()),
+                    resource_type,
+                    report_rwl.clone(),
+                )
+                .await;
+                market_data.write().await.reset_session();
+                market_data_outgoing_rwl.write().await.reset_session();
+                market_data_incoming_rwl.write().await.reset_session();
+                // the first session is backfilled by whoever built the subscriptions
+                if connected_before {
+                    let market_data = market_data.read().await;
+                    market_data_outgoing_rwl
+                        .write()
+                        .await
+                        .backfill_gaps(|symbol| market_data.last_tick_timestamp(symbol));
+                }
+                connected_before = true;
+                watchdog_rwl.write().await.start_session(resource_type);
+                monitor.set(ConnectionState::Connected);
+                let started = tokio::time::Instant::now();
+                let (md_write, md_read) = stream.split();
+                let md_read_future = md_read.for_each(|msg| async {
+                    process_market_data_messages(
+                        market_data_incoming_rwl.clone(),
+                        market_data_outgoing_rwl.clone(),
+                        market_data.clone(),
+                        watchdog_rwl.clone(),
+                        msg,
+                        resource_type,
+                        report_rwl.clone(),
+                    )
+                    .await;
+                });
+                let interval =
+                    tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
+                let md_write_future =
+                    IntervalStream::new(interval).fold(md_write, |pmd_write, _| async {
+                        process_outgoing_md_messages(
+                            market_data_incoming_rwl.clone(),
+                            market_data_outgoing_rwl.clone(),
+                            api_client_rwl.clone(),
+                            pmd_write,
+                            resource_type,
+                            report_rwl.clone(),
+                        )
+                        .await
+                    });
+                let token_changes = api_client_rwl.read().await.tokens.read().await.subscribe();
+                let reauthorization = reauthorize_on_renewal(token_changes, || async {
+                    let auth = api_client_rwl.read().await.ws_auth_msg(Endpoint::MarketData).await;
+                    market_data_outgoing_rwl.write().await.outgoing_messages.push(auth);
+                    program_log("Re-authorizing with renewed token", resource_type, report_rwl.clone())
+                        .await;
+                });
+                let authorization = watch_authorization(
+                    || {
+                        market_data_incoming_rwl
+                            .try_read()
+                            .ok()
+                            .map(|incoming| incoming.is_authorized)
+                    },
+                    &monitor,
+                );
+                // a half-open connection never errors, so staleness is what ends the session
+                let stale = watch_staleness(watchdog_rwl.clone(), resource_type, report_rwl.clone());
+                tokio::select! {
+                    () = md_read_future => {},
+                    _ = md_write_future => {},
+                    () = authorization => {},
+                    () = reauthorization => {},
+                    () = stale => {},
+                };
+                if started.elapsed().as_secs() >= STABLE_CONNECTION_SECONDS {
+                    backoff.reset();
+                }
+                program_log("Market data socket disconnected", resource_type, report_rwl.clone())
+                    .await;
+            }
+            Err(e) => {
+                program_log(
+                    &format!("Could not connect to market data socket {}", e),
+                    resource_type,
+                    report_rwl.clone(),
+                )
+                .await
+            }
+        }
+        monitor.set(ConnectionState::Disconnected);
+        if market_data_outgoing_rwl.read().await.terminated {
+            break;
+        }
+        wait_before_retry(&mut backoff, &monitor, resource_type, report_rwl.clone()).await;
+    }
+    monitor.set(ConnectionState::Terminated);
+}
+
+// Same as supervise_market_data for the trading socket. Every new session authorizes with the
+// current token and sends a fresh user/syncrequest before any pending requests.
+#[allow(clippy::too_many_arguments)]
+pub async fn supervise_account(
+    account_url: &str,
+    acct_comms_channel: AcctCommChannelRWL,
+    api_client_rwl: APIClientRWL,
+    watchdog_rwl: FeedWatchdogRWL,
+    monitor: ConnectionMonitorRef,
+    report_rwl: ReportRWL,
+) {
+    let resource_type = ResourceType::Trading;
+    let mut backoff = Backoff::default();
+    loop {
+        if acct_comms_channel.read().await.terminated {
+            break;
+        }
+        monitor.set(ConnectionState::Connecting);
+        match connect_async(account_url).await {
+            Ok((trading_stream, trading_response)) => {
+                program_log(
+                    &format!(
+                        "Websocket connection status: {:#?}",
+                        trading_response.status()
+                    ),
+                    resource_type,
+                    report_rwl.clone(),
+                )
+                .await;
+                let session_requests = {
+                    let api_client = api_client_rwl.read().await;
+                    vec![
+                        api_client.ws_auth_msg(Endpoint::Trading).await,
+                        api_client.get_user_sync_request().await,
+                    ]
+                };
+                acct_comms_channel
+                    .write()
+                    .await
+                    .reset_session(session_requests);
+                watchdog_rwl.write().await.start_session(resource_type);
+                monitor.set(ConnectionState::Connected);
+                let started = tokio::time::Instant::now();
+                let (account_write, account_read) = trading_stream.split();
+                let account_read_future = account_read.for_each(|msg| async {
+                    incoming_acct_socket(
+                        acct_comms_channel.clone(),
+                        watchdog_rwl.clone(),
+                        resource_type,
+                        msg,
+                        report_rwl.clone(),
+                    )
+                    .await;
+                });
+                let interval =
+                    tokio::time::interval(time::Duration::from_micros(SOCKET_INTERVAL_SPEED));
+                let account_write_future =
+                    IntervalStream::new(interval).fold(account_write, |pmd_write, _| async {
+                        outgoing_acct_socket(
+                            acct_comms_channel.clone(),
+                            pmd_write,
+                            resource_type,
+                            report_rwl.clone(),
+                        )
+                        .await
+                    });
+                let token_changes = api_client_rwl.read().await.tokens.read().await.subscribe();
+                let reauthorization = reauthorize_on_renewal(token_changes, || async {
+                    let auth = api_client_rwl.read().await.ws_auth_msg(Endpoint::Trading).await;
+                    acct_comms_channel.write().await.unsent_requests.insert(0, auth);
+                    program_log("Re-authorizing with renewed token", resource_type, report_rwl.clone())
+                        .await;
+                });
+                let authorization = watch_authorization(
+                    || {
+                        acct_comms_channel
+                            .try_read()
+                            .ok()
+                            .map(|channel| channel.is_authorized)
+                    },
+                    &monitor,
+                );
+                // a half-open connection never errors, so staleness is what ends the session
+                let stale = watch_staleness(watchdog_rwl.clone(), resource_type, report_rwl.clone());
+                tokio::select! {
+                    () = account_read_future => {},
+                    _ = account_write_future => {},
+                    () = authorization => {},
+                    () = reauthorization => {},
+                    () = stale => {},
+                };
+                if started.elapsed().as_secs() >= STABLE_CONNECTION_SECONDS {
+                    backoff.reset();
+                }
+                program_log("Account socket disconnected", resource_type, report_rwl.clone())
+                    .await;
+            }
+            Err(e) => {
+                program_log(
+                    &format!("Could not connect to account socket {}", e),
+                    resource_type,
+                    report_rwl.clone(),
+                )
+                .await
+            }
+        }
+        monitor.set(ConnectionState::Disconnected);
+        if acct_comms_channel.read().await.terminated {
+            break;
+        }
+        wait_before_retry(&mut backoff, &monitor, resource_type, report_rwl.clone()).await;
+    }
+    monitor.set(ConnectionState::Terminated);
+}
@@ -1,18 +1,23 @@
 use chrono::DateTime;
 use chrono::NaiveDateTime;
+use chrono::TimeZone;
 use chrono::Utc;
-use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::clock::Clock;
 use crate::models::SimpleQuote;
+use crate::trade_classifier::ClassificationRule;
 use crate::settings::ONE_SECOND;
 use crate::settings::TIMEZONE;
+use crate::settings::TS_RETENTION;
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Copy,Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Copy,Eq, PartialOrd, Ord, Default)]
 pub enum OrderAction {
     Buy,
     Sell,
+    #[default]
     Unknown,
 }
 impl OrderAction {
@@ -23,6 +28,10 @@ impl OrderAction {
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TimeAndSalesItem {
     pub historical_id: i64,
+    #[serde(default)]
+    pub contract_id: i64,
+    #[serde(default)]
+    pub tick_id: i64,
     pub action: OrderAction,
     pub qty: i64,
     pub price: f64,
@@ -30,6 +39,13 @@ pub struct TimeAndSalesItem {
     pub ask: f64,
     pub timestamp: i64,
     pub receipt_delay: i64,
+    // the exchange trade date of the tick's session, YYYYMMDD
+    #[serde(default)]
+    pub trade_date: i64,
+    #[serde(default)]
+    pub classified_by: ClassificationRule,
+    #[serde(default)]
+    pub confidence: f64,
 }
 impl TimeAndSalesItem {
     pub fn get_normal_time(&self) -> String {
@@ -40,13 +56,18 @@ impl TimeAndSalesItem {
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TimeAndSalesPressure {
     pub bids_per_second: f64,
     pub asks_per_second: f64,
     pub net: f64,
     pub time: String,
     pub price: f64,
+    // prints no rule could classify, left out of bids, asks and net
+    #[serde(default)]
+    pub unknown_prints: i64,
+    #[serde(default)]
+    pub unknown_per_second: f64,
 }
 impl TimeAndSalesPressure {
     pub fn new() -> Self {
@@ -56,6 +77,8 @@ impl TimeAndSalesPressure {
             net: 0.0,
             time: String::new(),
             price: 0.0,
+            unknown_prints: 0,
+            unknown_per_second: 0.0,
         }
     }
 }
@@ -64,74 +87,394 @@ impl Default for TimeAndSalesPressure {
         Self::new()
     }
 }
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum TimeWindow {
+    OneSecond,
+    FiveSeconds,
+    ThirtySeconds,
+    Custom(i64),
+}
+impl TimeWindow {
+    pub fn millis(&self) -> i64 {
+        match self {
+            TimeWindow::OneSecond => ONE_SECOND,
+            TimeWindow::FiveSeconds => 5 * ONE_SECOND,
+            TimeWindow::ThirtySeconds => 30 * ONE_SECOND,
+            TimeWindow::Custom(millis) => *millis,
+        }
+    }
+    pub fn seconds(&self) -> f64 {
+        self.millis() as f64 / ONE_SECOND as f64
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TimeAndSalesStats {
+    pub contract_id: i64,
+    pub window: TimeWindow,
+    pub pressure: TimeAndSalesPressure,
+    pub vwap: f64,
+    pub trade_count: i64,
+    pub volume: i64,
+    pub average_trade_size: f64,
+    pub large_prints: Vec<TimeAndSalesItem>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    Count(usize),
+    Millis(i64),
+}
+
+// Items for a single contract, kept in timestamp order. Ticks are deduplicated by tick id alone
+// rather than by (historical_id, tick_id): historical_id is the chart packet's `id`, which is the
+// subscription's historicalId/realtimeId from the md/getChart response, so a backfill page, the
+// live chart and the chart resubscribed after a reconnect each deliver the same tick under a
+// different one. The tick's own `id` is the same in all of them, and per-contract partitioning
+// keeps ids of different contracts apart. Dedup keys are kept in sync with whatever the ring
+// buffer holds.
+#[derive(Debug, Clone)]
+pub struct TimeAndSalesPartition {
+    items: VecDeque<TimeAndSalesItem>,
+    seen: HashSet<i64>,
+}
+impl TimeAndSalesPartition {
+    pub fn new() -> Self {
+        Self {
+            items: VecDeque::new(),
+            seen: HashSet::new(),
+        }
+    }
+    pub fn insert(&mut self, item: TimeAndSalesItem) -> bool {
+        if !self.seen.insert(item.tick_id) {
+            return false;
+        }
+        match self.items.back() {
+            Some(last) if item.timestamp < last.timestamp => {
+                let index = self
+                    .items
+                    .partition_point(|existing| existing.timestamp <= item.timestamp);
+                self.items.insert(index, item);
+            }
+            _ => self.items.push_back(item),
+        }
+        true
+    }
+    pub fn apply_retention(&mut self, retention: Retention) {
+        match retention {
+            Retention::Count(max_items) => {
+                while self.items.len() > max_items {
+                    self.pop_oldest();
+                }
+            }
+            Retention::Millis(millis) => {
+                if let Some(newest) = self.items.back().map(|item| item.timestamp) {
+                    while self
+                        .items
+                        .front()
+                        .is_some_and(|oldest| oldest.timestamp < newest - millis)
+                    {
+                        self.pop_oldest();
+                    }
+                }
+            }
+        }
+    }
+    fn pop_oldest(&mut self) {
+        if let Some(item) = self.items.pop_front() {
+            self.seen.remove(&item.tick_id);
+        }
+    }
+    // Items with from <= timestamp < to, found by binary search
+    pub fn range(&self, from: i64, to: i64) -> impl DoubleEndedIterator<Item = &TimeAndSalesItem> {
+        let start = self.items.partition_point(|item| item.timestamp < from);
+        let end = std::cmp::max(start, self.items.partition_point(|item| item.timestamp < to));
+        self.items.range(start..end)
+    }
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &TimeAndSalesItem> {
+        self.items.iter()
+    }
+    pub fn last(&self) -> Option<&TimeAndSalesItem> {
+        self.items.back()
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+impl Default for TimeAndSalesPartition {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct TimeAndSalesCollection {
-    pub items: Vec<TimeAndSalesItem>,
+    pub partitions: HashMap<i64, TimeAndSalesPartition>,
+    pub retention: Retention,
 }
 impl TimeAndSalesCollection {
     pub fn new() -> Self {
-        Self { items: Vec::new() }
+        Self::with_retention(TS_RETENTION)
     }
-    pub fn get_pressure(&self) -> TimeAndSalesPressure {
-        let now = chrono::Local::now();
-        let ts_items = self
-            .items
-            .iter()
-            .rev()
-            .filter(|ts_item| ts_item.timestamp >= now.timestamp_millis() - ONE_SECOND)
-            .collect_vec();
-        let mut asks_per_second = 0.0;
-        let mut bids_per_second = 0.0;
-        for item in &ts_items {
-            match item.action {
-                OrderAction::Sell => bids_per_second += item.qty as f64,
-                OrderAction::Buy => asks_per_second += item.qty as f64,
-                OrderAction::Unknown => todo!(),
+    pub fn with_retention(retention: Retention) -> Self {
+        Self {
+            partitions: HashMap::new(),
+            retention,
+        }
+    }
+    // Adds items that are not already stored, partitioned by contract id.
+    // Returns the items that were actually added.
+    pub fn merge(&mut self, new_items: Vec<TimeAndSalesItem>) -> Vec<TimeAndSalesItem> {
+        let mut added = Vec::new();
+        let mut touched = HashSet::new();
+        for item in new_items {
+            touched.insert(item.contract_id);
+            if self
+                .partitions
+                .entry(item.contract_id)
+                .or_default()
+                .insert(item.clone())
+            {
+                added.push(item);
             }
         }
-        let price = if let Some(last) = ts_items.last() {
-            last.price
-        } else {0.0};
-        TimeAndSalesPressure {
-            bids_per_second,
-            asks_per_second,
-            net: asks_per_second - bids_per_second,
-            time: now.with_timezone(&TIMEZONE).format("%F %T").to_string(),
-            price,
+        for contract_id in touched {
+            if let Some(partition) = self.partitions.get_mut(&contract_id) {
+                partition.apply_retention(self.retention);
+            }
         }
+        added
     }
-    pub fn get_quote(&self, historical_id: i64) -> Option<SimpleQuote> {
-        if let Some(last_item) = self
-            .items
-            .iter()
-            .rev()
-            .find(|item| item.historical_id == historical_id)
-        {
-            return Some(SimpleQuote {
+    pub fn partition(&self, contract_id: i64) -> Option<&TimeAndSalesPartition> {
+        self.partitions.get(&contract_id)
+    }
+    pub fn range(&self, contract_id: i64, from: i64, to: i64) -> Vec<&TimeAndSalesItem> {
+        match self.partitions.get(&contract_id) {
+            Some(partition) => partition.range(from, to).collect(),
+            None => Vec::new(),
+        }
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &TimeAndSalesItem> {
+        self.partitions.values().flat_map(|partition| partition.iter())
+    }
+    pub fn len(&self) -> usize {
+        self.partitions.values().map(|partition| partition.len()).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.partitions.values().all(|partition| partition.is_empty())
+    }
+    pub fn get_pressure(&self, contract_id: i64, window: TimeWindow, clock: &dyn Clock) -> TimeAndSalesPressure {
+        let now = clock.now_millis();
+        pressure_of(&self.window(contract_id, window, now), window, now)
+    }
+    pub fn get_stats(&self, contract_id: i64, window: TimeWindow, large_trade: i64, clock: &dyn Clock) -> TimeAndSalesStats {
+        let now = clock.now_millis();
+        let ts_items = self.window(contract_id, window, now);
+        let volume: i64 = ts_items.iter().map(|item| item.qty).sum();
+        let notional: f64 = ts_items.iter().map(|item| item.price * item.qty as f64).sum();
+        let trade_count = ts_items.len() as i64;
+        TimeAndSalesStats {
+            contract_id,
+            window,
+            pressure: pressure_of(&ts_items, window, now),
+            vwap: if volume > 0 { notional / volume as f64 } else { 0.0 },
+            trade_count,
+            volume,
+            average_trade_size: if trade_count > 0 { volume as f64 / trade_count as f64 } else { 0.0 },
+            large_prints: ts_items
+                .into_iter()
+                .filter(|item| item.qty >= large_trade)
+                .cloned()
+                .collect(),
+        }
+    }
+    // Items for one contract inside the window ending at now
+    pub fn window(&self, contract_id: i64, window: TimeWindow, now: i64) -> Vec<&TimeAndSalesItem> {
+        self.range(contract_id, now - window.millis(), i64::MAX)
+    }
+    pub fn get_quote(&self, contract_id: i64) -> Option<SimpleQuote> {
+        self.partitions
+            .get(&contract_id)
+            .and_then(|partition| partition.last())
+            .map(|last_item| SimpleQuote {
                 bid: last_item.bid,
                 ask: last_item.ask,
-            });
-        }
-        None
+            })
     }
-    pub fn largest_buy(&self) -> Option<TimeAndSalesItem> {
-        self.items
-            .iter()
-            .filter(|item| item.action == OrderAction::Buy)
-            .max_by_key(|item| item.qty)
-            .cloned()
+    pub fn largest_buy(&self, contract_id: i64) -> Option<TimeAndSalesItem> {
+        self.largest(contract_id, OrderAction::Buy)
+    }
+    pub fn largest_sell(&self, contract_id: i64) -> Option<TimeAndSalesItem> {
+        self.largest(contract_id, OrderAction::Sell)
     }
-    pub fn largest_sell(&self) -> Option<TimeAndSalesItem> {
-        self.items
+    fn largest(&self, contract_id: i64, action: OrderAction) -> Option<TimeAndSalesItem> {
+        self.partitions
+            .get(&contract_id)?
             .iter()
-            .filter(|item| item.action == OrderAction::Sell)
+            .filter(|item| item.action == action)
             .max_by_key(|item| item.qty)
             .cloned()
     }
 }
 
+fn pressure_of(ts_items: &[&TimeAndSalesItem], window: TimeWindow, now: i64) -> TimeAndSalesPressure {
+    let seconds = window.seconds();
+    let mut asks = 0.0;
+    let mut bids = 0.0;
+    let mut unknown = 0.0;
+    let mut unknown_prints = 0;
+    for item in ts_items {
+        match item.action {
+            OrderAction::Sell => bids += item.qty as f64,
+            OrderAction::Buy => asks += item.qty as f64,
+            OrderAction::Unknown => {
+                unknown += item.qty as f64;
+                unknown_prints += 1;
+            }
+        }
+    }
+    let price = if let Some(first) = ts_items.first() {
+        first.price
+    } else {0.0};
+    TimeAndSalesPressure {
+        bids_per_second: bids / seconds,
+        asks_per_second: asks / seconds,
+        net: (asks - bids) / seconds,
+        time: Utc.timestamp_millis(now).with_timezone(&TIMEZONE).format("%F %T").to_string(),
+        price,
+        unknown_prints,
+        unknown_per_second: unknown / seconds,
+    }
+}
+
 impl Default for TimeAndSalesCollection {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    fn item(tick_id: i64, action: OrderAction, qty: i64) -> TimeAndSalesItem {
+        TimeAndSalesItem {
+            historical_id: 1,
+            contract_id: 1,
+            tick_id,
+            action,
+            qty,
+            price: 100.0,
+            bid: 100.0,
+            ask: 100.25,
+            timestamp: 1_000 + tick_id,
+            receipt_delay: 0,
+            trade_date: 0,
+            classified_by: ClassificationRule::Unclassified,
+            confidence: 0.0,
+        }
+    }
+
+    #[test]
+    fn pressure_counts_unknown_prints_apart() {
+        let mut collection = TimeAndSalesCollection::new();
+        collection.merge(vec![
+            item(1, OrderAction::Buy, 3),
+            item(2, OrderAction::Sell, 1),
+            item(3, OrderAction::Unknown, 4),
+            item(4, OrderAction::Unknown, 2),
+        ]);
+        let pressure = collection.get_pressure(1, TimeWindow::OneSecond, &ManualClock::new(1_500));
+        assert_eq!(pressure.asks_per_second, 3.0);
+        assert_eq!(pressure.bids_per_second, 1.0);
+        assert_eq!(pressure.net, 2.0);
+        assert_eq!(pressure.unknown_prints, 2);
+        assert_eq!(pressure.unknown_per_second, 6.0);
+    }
+
+    #[test]
+    fn ticks_delivered_again_under_another_chart_are_dropped() {
+        let mut collection = TimeAndSalesCollection::new();
+        collection.merge(vec![item(1, OrderAction::Buy, 3)]);
+        let again = TimeAndSalesItem {
+            historical_id: 2,
+            ..item(1, OrderAction::Buy, 3)
+        };
+        assert!(collection.merge(vec![again]).is_empty());
+        assert_eq!(collection.len(), 1);
+    }
+
+    fn at(tick_id: i64, timestamp: i64) -> TimeAndSalesItem {
+        TimeAndSalesItem {
+            timestamp,
+            ..item(tick_id, OrderAction::Buy, 1)
+        }
+    }
+
+    fn tick_ids<'a>(items: impl Iterator<Item = &'a TimeAndSalesItem>) -> Vec<i64> {
+        items.map(|item| item.tick_id).collect()
+    }
+
+    #[test]
+    fn count_retention_keeps_the_newest_items() {
+        let mut collection = TimeAndSalesCollection::with_retention(Retention::Count(3));
+        collection.merge((1..=5).map(|tick_id| at(tick_id, tick_id * 10)).collect());
+        assert_eq!(tick_ids(collection.iter()), vec![3, 4, 5]);
+    }
+
+    #[test]
+    fn time_retention_is_measured_from_the_newest_item() {
+        let mut collection = TimeAndSalesCollection::with_retention(Retention::Millis(100));
+        collection.merge(vec![at(1, 1_000), at(2, 1_050), at(3, 1_100)]);
+        assert_eq!(collection.len(), 3);
+        collection.merge(vec![at(4, 1_150)]);
+        assert_eq!(tick_ids(collection.iter()), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn evicted_ticks_can_be_stored_again() {
+        let mut collection = TimeAndSalesCollection::with_retention(Retention::Count(1));
+        collection.merge(vec![at(1, 10)]);
+        collection.merge(vec![at(2, 20)]);
+        assert_eq!(collection.merge(vec![at(1, 30)]).len(), 1);
+    }
+
+    #[test]
+    fn ranges_include_from_and_exclude_to() {
+        let mut collection = TimeAndSalesCollection::new();
+        collection.merge((1..=5).map(|tick_id| at(tick_id, tick_id * 10)).collect());
+        let ids = |from, to| tick_ids(collection.range(1, from, to).into_iter());
+        assert_eq!(ids(20, 40), vec![2, 3]);
+        assert_eq!(ids(15, 45), vec![2, 3, 4]);
+        assert_eq!(ids(0, 10), Vec::<i64>::new());
+        assert_eq!(ids(50, 1_000), vec![5]);
+        assert_eq!(ids(40, 20), Vec::<i64>::new());
+        assert!(collection.range(2, 0, 1_000).is_empty());
+    }
+
+    #[test]
+    fn out_of_order_ticks_are_stored_in_timestamp_order() {
+        let mut collection = TimeAndSalesCollection::new();
+        collection.merge(vec![at(1, 10), at(4, 40)]);
+        // a backfill page arriving after the live ticks
+        collection.merge(vec![at(3, 30), at(2, 20), at(5, 40)]);
+        assert_eq!(tick_ids(collection.iter()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(tick_ids(collection.range(1, 20, 40).into_iter()), vec![2, 3]);
+    }
+
+    #[test]
+    fn contracts_are_partitioned_apart() {
+        let mut collection = TimeAndSalesCollection::new();
+        let other = TimeAndSalesItem {
+            contract_id: 2,
+            ..at(1, 10)
+        };
+        assert_eq!(collection.merge(vec![at(1, 10), other]).len(), 2);
+        assert_eq!(collection.partition(1).map(|partition| partition.len()), Some(1));
+        assert_eq!(collection.partition(2).map(|partition| partition.len()), Some(1));
+    }
+}
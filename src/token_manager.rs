@@ -0,0 +1,221 @@
+use std::sync::Arc;
+
+use chrono::TimeZone;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+
+use crate::{
+    api_client::ResourceType,
+    md_socket::APIClientRWL,
+    others::{create_json_file_atomically, open_json},
+    reporting::{program_log, send_txt_message, ReportRWL},
+    settings::{ALERT_PHONE_NUMBER, TIMEZONE, TOKEN_CHECK_SECONDS, TOKEN_RENEWAL_MARGIN_MILLIS},
+};
+
+pub type TokenManagerRWL = Arc<tokio::sync::RwLock<TokenManager>>;
+
+#[derive(Debug)]
+pub enum TokenError {
+    Http(reqwest::Error),
+    Rejected(String),
+    Persist(std::io::Error),
+}
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenError::Http(e) => write!(f, "token request failed: {}", e),
+            TokenError::Rejected(reason) => write!(f, "token request rejected: {}", reason),
+            TokenError::Persist(e) => write!(f, "could not save token: {}", e),
+        }
+    }
+}
+impl From<reqwest::Error> for TokenError {
+    fn from(e: reqwest::Error) -> Self {
+        TokenError::Http(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Token {
+    pub access_token: String,
+    pub md_access_token: String,
+    pub expiration_time: i64,
+    pub user_id: i64,
+}
+impl Token {
+    // Reads the body of accesstokenrequest and renewaccesstoken, which is also the cache format
+    pub fn from_response(response: &Value) -> Result<Self, TokenError> {
+        let access_token = match response["accessToken"].as_str() {
+            Some(access_token) => access_token.to_string(),
+            None => {
+                return Err(TokenError::Rejected(
+                    response["errorText"]
+                        .as_str()
+                        .unwrap_or("no access token in response")
+                        .to_string(),
+                ))
+            }
+        };
+        let expiration_time = response["expirationTime"]
+            .as_str()
+            .and_then(|expiration| chrono::DateTime::parse_from_rfc3339(expiration).ok())
+            .map(|expiration| expiration.timestamp_millis())
+            .ok_or_else(|| TokenError::Rejected("no expiration time in response".to_string()))?;
+        Ok(Self {
+            access_token,
+            md_access_token: response["mdAccessToken"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            expiration_time,
+            user_id: response["userId"].as_i64().unwrap_or_default(),
+        })
+    }
+    pub fn to_json(&self) -> Value {
+        json!({
+            "accessToken": self.access_token,
+            "mdAccessToken": self.md_access_token,
+            "expirationTime": chrono::Utc.timestamp_millis(self.expiration_time).to_rfc3339(),
+            "userId": self.user_id,
+        })
+    }
+    pub fn millis_until_expiry(&self) -> i64 {
+        self.expiration_time - chrono::Utc::now().timestamp_millis()
+    }
+    pub fn is_valid(&self, margin_millis: i64) -> bool {
+        self.millis_until_expiry() > margin_millis
+    }
+    pub fn local_expiry(&self) -> String {
+        chrono::Utc
+            .timestamp_millis(self.expiration_time)
+            .with_timezone(&TIMEZONE)
+            .format("%v %T")
+            .to_string()
+    }
+}
+
+// Owns the tokens used by REST calls and both websockets. Every new token is written to the
+// cache file and bumps a generation number that connected sockets watch to re-authorize.
+#[derive(Debug)]
+pub struct TokenManager {
+    pub token: Option<Token>,
+    pub cache_filename: String,
+    pub renewal_margin_millis: i64,
+    generation: watch::Sender<u64>,
+    generation_receiver: watch::Receiver<u64>,
+}
+impl TokenManager {
+    pub fn new(cache_filename: &str) -> Self {
+        let (generation, generation_receiver) = watch::channel(0);
+        Self {
+            token: None,
+            cache_filename: cache_filename.to_string(),
+            renewal_margin_millis: TOKEN_RENEWAL_MARGIN_MILLIS,
+            generation,
+            generation_receiver,
+        }
+    }
+    // Uses the cached token only if it is not already due for renewal
+    pub fn load_cached(&mut self) -> bool {
+        let cached = open_json(&self.cache_filename)
+            .ok()
+            .and_then(|cache| Token::from_response(&cache).ok())
+            .filter(|token| token.is_valid(self.renewal_margin_millis));
+        match cached {
+            Some(token) => {
+                self.token = Some(token);
+                true
+            }
+            None => false,
+        }
+    }
+    // The token is kept even if it could not be saved, the error is only for reporting
+    pub fn set_token(&mut self, mut token: Token) -> Result<(), TokenError> {
+        // a response without an md token keeps the one we have
+        if token.md_access_token.is_empty() {
+            if let Some(current) = &self.token {
+                token.md_access_token = current.md_access_token.clone();
+            }
+        }
+        let persisted = create_json_file_atomically(&self.cache_filename, &token.to_json());
+        self.token = Some(token);
+        let next_generation = *self.generation_receiver.borrow() + 1;
+        let _ = self.generation.send(next_generation);
+        persisted.map_err(TokenError::Persist)
+    }
+    pub fn token_is_valid(&self) -> bool {
+        self.token.as_ref().is_some_and(|token| token.is_valid(0))
+    }
+    pub fn needs_renewal(&self) -> bool {
+        self.token
+            .as_ref()
+            .is_none_or(|token| !token.is_valid(self.renewal_margin_millis))
+    }
+    pub fn access_token(&self) -> String {
+        self.token
+            .as_ref()
+            .map(|token| token.access_token.clone())
+            .unwrap_or_default()
+    }
+    // Accounts without a separate market data token authorize md with the access token
+    pub fn md_access_token(&self) -> String {
+        self.token
+            .as_ref()
+            .map(|token| {
+                if token.md_access_token.is_empty() {
+                    token.access_token.clone()
+                } else {
+                    token.md_access_token.clone()
+                }
+            })
+            .unwrap_or_default()
+    }
+    pub fn user_id(&self) -> i64 {
+        self.token.as_ref().map(|token| token.user_id).unwrap_or_default()
+    }
+    // Only sees tokens set after this call
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.generation.subscribe()
+    }
+}
+
+// Renews the token ahead of expiry. If renewal fails the client authenticates from scratch.
+pub async fn keep_token_fresh(api_client_rwl: APIClientRWL, report_rwl: ReportRWL) {
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_secs(TOKEN_CHECK_SECONDS));
+    loop {
+        interval.tick().await;
+        let api_client = api_client_rwl.read().await.clone();
+        if !api_client.tokens.read().await.needs_renewal() {
+            continue;
+        }
+        let (token, action) = match api_client.renew_access_token().await {
+            Ok(token) => (token, "Renewed access token"),
+            Err(e) => {
+                program_log(
+                    &format!("Could not renew access token, authenticating again. {}", e),
+                    ResourceType::Any,
+                    report_rwl.clone(),
+                )
+                .await;
+                match api_client.request_token().await {
+                    Ok(token) => (token, "Authenticated again"),
+                    Err(e) => {
+                        let report = format!("Could not obtain a new access token. {}", e);
+                        program_log(&report, ResourceType::Any, report_rwl.clone()).await;
+                        let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+                        continue;
+                    }
+                }
+            }
+        };
+        let report = format!("{}. Now expiring at {}", action, token.local_expiry());
+        if let Err(e) = api_client.tokens.write().await.set_token(token) {
+            let report = format!("Could not store the new access token. {}", e);
+            program_log(&report, ResourceType::Any, report_rwl.clone()).await;
+            let _ = send_txt_message(ALERT_PHONE_NUMBER, &report).await;
+            continue;
+        }
+        program_log(&report, ResourceType::Any, report_rwl.clone()).await;
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    settings::TRADE_CLASSIFICATION_RULE,
+    time_and_sales::{OrderAction, TimeAndSalesItem},
+};
+
+const PRICE_EPSILON: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum ClassificationRule {
+    Quote,
+    Tick,
+    LeeReady,
+    Emo,
+    // items saved before classification was recorded
+    #[default]
+    Unclassified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Classification {
+    pub action: OrderAction,
+    pub rule: ClassificationRule,
+    pub confidence: f64,
+}
+impl Classification {
+    pub fn unknown() -> Self {
+        Self {
+            action: OrderAction::Unknown,
+            rule: ClassificationRule::Unclassified,
+            confidence: 0.0,
+        }
+    }
+    pub fn is_unknown(&self) -> bool {
+        self.action.is_unknown()
+    }
+}
+
+// Trades above the midpoint are buys, below are sells. Confidence grows with the distance
+// from the midpoint and is 1.0 at or through the touch.
+pub fn quote_rule(price: f64, bid: f64, ask: f64) -> Classification {
+    let mid_price = 0.5 * (ask + bid);
+    let half_spread = 0.5 * (ask - bid);
+    let distance = price - mid_price;
+    if distance.abs() < PRICE_EPSILON || half_spread <= 0.0 {
+        return Classification::unknown();
+    }
+    Classification {
+        action: if distance > 0.0 {
+            OrderAction::Buy
+        } else {
+            OrderAction::Sell
+        },
+        rule: ClassificationRule::Quote,
+        confidence: (0.5 + 0.5 * distance.abs() / half_spread).min(1.0),
+    }
+}
+
+// Only trades exactly at the bid or ask are decided, everything else is left to the tick rule
+pub fn at_touch_rule(price: f64, bid: f64, ask: f64) -> Classification {
+    if (price - ask).abs() < PRICE_EPSILON {
+        Classification {
+            action: OrderAction::Buy,
+            rule: ClassificationRule::Emo,
+            confidence: 1.0,
+        }
+    } else if (price - bid).abs() < PRICE_EPSILON {
+        Classification {
+            action: OrderAction::Sell,
+            rule: ClassificationRule::Emo,
+            confidence: 1.0,
+        }
+    } else {
+        Classification::unknown()
+    }
+}
+
+// Keeps the previous trade per contract so the tick rule can be applied to a stream of trades.
+// Items must be fed in timestamp order.
+#[derive(Debug, Clone)]
+pub struct TradeClassifier {
+    pub rule: ClassificationRule,
+    last_price: HashMap<i64, f64>,
+    last_tick: HashMap<i64, OrderAction>,
+}
+impl TradeClassifier {
+    pub fn new(rule: ClassificationRule) -> Self {
+        Self {
+            rule,
+            last_price: HashMap::new(),
+            last_tick: HashMap::new(),
+        }
+    }
+    pub fn classify(&mut self, item: &TimeAndSalesItem) -> Classification {
+        let tick = self.tick_rule(item.contract_id, item.price);
+        let primary = match self.rule {
+            ClassificationRule::Quote => quote_rule(item.price, item.bid, item.ask),
+            ClassificationRule::Tick => tick,
+            ClassificationRule::LeeReady => {
+                let quote = quote_rule(item.price, item.bid, item.ask);
+                if quote.is_unknown() {
+                    tick
+                } else {
+                    Classification {
+                        rule: ClassificationRule::LeeReady,
+                        ..quote
+                    }
+                }
+            }
+            ClassificationRule::Emo => at_touch_rule(item.price, item.bid, item.ask),
+            ClassificationRule::Unclassified => Classification::unknown(),
+        };
+        if primary.is_unknown() {
+            tick
+        } else {
+            primary
+        }
+    }
+    pub fn classify_all(&mut self, items: &mut [TimeAndSalesItem]) {
+        items.sort_by_key(|item| (item.timestamp, item.tick_id));
+        for item in items.iter_mut() {
+            let classification = self.classify(item);
+            item.action = classification.action;
+            item.classified_by = classification.rule;
+            item.confidence = classification.confidence;
+        }
+    }
+    // Upticks are buys and downticks are sells. Zero ticks inherit the direction of the last
+    // price change with a lower confidence.
+    fn tick_rule(&mut self, contract_id: i64, price: f64) -> Classification {
+        let previous = self.last_price.insert(contract_id, price);
+        let (action, confidence) = match previous {
+            Some(previous) if price - previous > PRICE_EPSILON => (OrderAction::Buy, 0.75),
+            Some(previous) if previous - price > PRICE_EPSILON => (OrderAction::Sell, 0.75),
+            Some(_) => match self.last_tick.get(&contract_id) {
+                Some(last_tick) => (*last_tick, 0.55),
+                None => return Classification::unknown(),
+            },
+            None => return Classification::unknown(),
+        };
+        self.last_tick.insert(contract_id, action);
+        Classification {
+            action,
+            rule: ClassificationRule::Tick,
+            confidence,
+        }
+    }
+}
+impl Default for TradeClassifier {
+    fn default() -> Self {
+        Self::new(TRADE_CLASSIFICATION_RULE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time_and_sales::OrderAction::{Buy, Sell, Unknown};
+    use ClassificationRule::{Emo, LeeReady, Quote, Tick, Unclassified};
+
+    const BID: f64 = 100.0;
+    const ASK: f64 = 101.0;
+
+    fn item(contract_id: i64, tick_id: i64, price: f64) -> TimeAndSalesItem {
+        TimeAndSalesItem {
+            historical_id: 1,
+            contract_id,
+            tick_id,
+            action: Unknown,
+            qty: 1,
+            price,
+            bid: BID,
+            ask: ASK,
+            timestamp: tick_id,
+            receipt_delay: 0,
+            trade_date: 0,
+            classified_by: Unclassified,
+            confidence: 0.0,
+        }
+    }
+
+    fn assert_classified(
+        classification: Classification,
+        expected: (OrderAction, ClassificationRule, f64),
+        case: &str,
+    ) {
+        assert_eq!(classification.action, expected.0, "{}", case);
+        assert_eq!(classification.rule, expected.1, "{}", case);
+        assert!(
+            (classification.confidence - expected.2).abs() < 1e-9,
+            "{}: confidence {}",
+            case,
+            classification.confidence
+        );
+    }
+
+    // Feeds prices in order through a classifier and checks every result
+    fn check_stream(rule: ClassificationRule, cases: &[(f64, (OrderAction, ClassificationRule, f64))]) {
+        let mut classifier = TradeClassifier::new(rule);
+        for (n, (price, expected)) in cases.iter().enumerate() {
+            let classification = classifier.classify(&item(1, n as i64, *price));
+            assert_classified(
+                classification,
+                *expected,
+                &format!("{:?} trade {} at {}", rule, n, price),
+            );
+        }
+    }
+
+    #[test]
+    fn quote_rule_cases() {
+        let cases = [
+            (100.75, (Buy, Quote, 0.75)),
+            (100.25, (Sell, Quote, 0.75)),
+            (101.0, (Buy, Quote, 1.0)),
+            (100.0, (Sell, Quote, 1.0)),
+            (101.5, (Buy, Quote, 1.0)),
+            (99.5, (Sell, Quote, 1.0)),
+            (100.5, (Unknown, Unclassified, 0.0)),
+        ];
+        for (price, expected) in cases {
+            assert_classified(quote_rule(price, BID, ASK), expected, &format!("price {}", price));
+        }
+    }
+
+    #[test]
+    fn quote_rule_needs_a_spread() {
+        assert!(quote_rule(100.0, 100.0, 100.0).is_unknown());
+        assert!(quote_rule(100.5, 101.0, 100.0).is_unknown());
+    }
+
+    #[test]
+    fn at_touch_rule_cases() {
+        let cases = [
+            (101.0, (Buy, Emo, 1.0)),
+            (100.0, (Sell, Emo, 1.0)),
+            (100.75, (Unknown, Unclassified, 0.0)),
+            (101.25, (Unknown, Unclassified, 0.0)),
+        ];
+        for (price, expected) in cases {
+            assert_classified(at_touch_rule(price, BID, ASK), expected, &format!("price {}", price));
+        }
+    }
+
+    #[test]
+    fn tick_rule_stream() {
+        check_stream(
+            Tick,
+            &[
+                // the first trade has nothing to compare with
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (100.75, (Buy, Tick, 0.75)),
+                // zero ticks inherit the last direction
+                (100.75, (Buy, Tick, 0.55)),
+                (100.25, (Sell, Tick, 0.75)),
+                (100.25, (Sell, Tick, 0.55)),
+                (100.25, (Sell, Tick, 0.55)),
+            ],
+        );
+    }
+
+    #[test]
+    fn zero_tick_without_a_previous_change_is_unknown() {
+        check_stream(
+            Tick,
+            &[
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (100.25, (Sell, Tick, 0.75)),
+            ],
+        );
+    }
+
+    #[test]
+    fn quote_rule_stream_falls_back_to_tick_rule_at_the_midpoint() {
+        check_stream(
+            Quote,
+            &[
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (100.75, (Buy, Quote, 0.75)),
+                (100.5, (Sell, Tick, 0.75)),
+                (100.5, (Sell, Tick, 0.55)),
+            ],
+        );
+    }
+
+    #[test]
+    fn lee_ready_stream() {
+        check_stream(
+            LeeReady,
+            &[
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (101.0, (Buy, LeeReady, 1.0)),
+                (100.5, (Sell, Tick, 0.75)),
+                (100.75, (Buy, LeeReady, 0.75)),
+                (100.5, (Sell, Tick, 0.75)),
+                (100.5, (Sell, Tick, 0.55)),
+            ],
+        );
+    }
+
+    #[test]
+    fn emo_stream() {
+        check_stream(
+            Emo,
+            &[
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (101.0, (Buy, Emo, 1.0)),
+                (100.75, (Sell, Tick, 0.75)),
+                (100.75, (Sell, Tick, 0.55)),
+                (100.0, (Sell, Emo, 1.0)),
+                (100.25, (Buy, Tick, 0.75)),
+            ],
+        );
+    }
+
+    #[test]
+    fn unclassified_rule_still_uses_the_tick_rule() {
+        check_stream(
+            Unclassified,
+            &[
+                (100.5, (Unknown, Unclassified, 0.0)),
+                (100.75, (Buy, Tick, 0.75)),
+            ],
+        );
+    }
+
+    #[test]
+    fn tick_state_is_kept_per_contract() {
+        let mut classifier = TradeClassifier::new(Tick);
+        assert!(classifier.classify(&item(1, 0, 100.0)).is_unknown());
+        // the first trade of another contract is not compared with contract 1
+        assert!(classifier.classify(&item(2, 1, 105.0)).is_unknown());
+        assert_eq!(classifier.classify(&item(1, 2, 100.25)).action, Buy);
+        assert_eq!(classifier.classify(&item(2, 3, 104.75)).action, Sell);
+    }
+
+    #[test]
+    fn classify_all_works_in_time_order() {
+        let mut items = vec![item(1, 2, 100.75), item(1, 0, 100.5), item(1, 1, 100.25)];
+        TradeClassifier::new(Tick).classify_all(&mut items);
+        let classified: Vec<(i64, OrderAction, ClassificationRule)> = items
+            .iter()
+            .map(|item| (item.tick_id, item.action, item.classified_by))
+            .collect();
+        assert_eq!(
+            classified,
+            vec![(0, Unknown, Unclassified), (1, Sell, Tick), (2, Buy, Tick)]
+        );
+    }
+
+    #[test]
+    fn items_saved_before_classification_still_load() {
+        let saved = r#"{"historical_id":1,"action":"Buy","qty":2,"price":100.5,"bid":100.0,
+            "ask":101.0,"timestamp":1,"receipt_delay":0}"#;
+        let item: TimeAndSalesItem = serde_json::from_str(saved).unwrap();
+        assert_eq!(item.classified_by, Unclassified);
+        assert_eq!(item.confidence, 0.0);
+    }
+}
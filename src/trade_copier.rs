@@ -0,0 +1,245 @@
+use std::{collections::{BTreeSet, HashMap}, sync::Arc};
+
+use crate::{
+    api_client::ResourceType,
+    models::{AcctCommChannelRWL, AcctCommsChannel},
+    order_journal::OrderIntent,
+    reporting::{program_log, send_txt_message, ReportRWL},
+    settings::{
+        ACCOUNT_NUMBER, ALERT_PHONE_NUMBER, COPY_CHECK_MILLIS, COPY_FOLLOWERS,
+        COPY_ORDER_TIMEOUT_SECONDS,
+    },
+    strategy::{create_market_order_ticket, create_order_request},
+    time_and_sales::OrderAction,
+};
+
+pub type TradeCopierRWL = Arc<tokio::sync::RwLock<TradeCopier>>;
+
+// A market order sent to a follower, done once the follower's own fills reach expected_net
+#[derive(Debug, Clone)]
+pub struct CopyOrder {
+    pub cl_ord_id: String,
+    pub qty: i64,
+    pub expected_net: i64,
+    pub sent_at: tokio::time::Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct Follower {
+    pub name: String,
+    pub multiplier: f64,
+    pub account_id: Option<i64>,
+    // signed contracts copied so far per contract
+    pub copied: HashMap<i64, i64>,
+    pub working: HashMap<i64, CopyOrder>,
+    // why the follower stopped copying
+    pub halted: Option<String>,
+}
+impl Follower {
+    pub fn new(name: &str, multiplier: f64) -> Self {
+        Self {
+            name: name.to_string(),
+            multiplier,
+            account_id: None,
+            copied: HashMap::new(),
+            working: HashMap::new(),
+            halted: None,
+        }
+    }
+    // Rounded on the leader's whole change, so fractional multipliers never drift
+    fn to_copy(&self, contract_id: i64, leader_change: i64) -> i64 {
+        let target = (leader_change as f64 * self.multiplier).round() as i64;
+        target - self.copied.get(&contract_id).copied().unwrap_or(0)
+    }
+}
+
+// Mirrors every change in the leader's positions onto the followers. Positions are read from
+// each account's own fills in the ledger, so a follower that lags or fails affects no other.
+#[derive(Debug, Clone)]
+pub struct TradeCopier {
+    pub leader: String,
+    pub leader_id: Option<i64>,
+    // the leader's positions when copying started, what was there before is not copied
+    leader_baselines: HashMap<i64, i64>,
+    pub followers: Vec<Follower>,
+}
+impl TradeCopier {
+    pub fn new(leader: &str, followers: Vec<Follower>) -> Self {
+        Self {
+            leader: leader.to_string(),
+            leader_id: None,
+            leader_baselines: HashMap::new(),
+            followers,
+        }
+    }
+    pub fn from_settings() -> Self {
+        Self::new(
+            ACCOUNT_NUMBER,
+            COPY_FOLLOWERS
+                .iter()
+                .map(|(name, multiplier)| Follower::new(name, *multiplier))
+                .collect(),
+        )
+    }
+    fn bind(&mut self, open: &AcctCommsChannel) -> Vec<String> {
+        let mut log = Vec::new();
+        let user_data = match &open.user_data {
+            Some(user_data) => &user_data.data,
+            None => return log,
+        };
+        let leader = match user_data.account(&self.leader) {
+            Some(leader) => leader,
+            None => return log,
+        };
+        self.leader_id = Some(leader.id);
+        self.leader_baselines = open
+            .ledger
+            .books
+            .values()
+            .filter(|book| book.account_id == leader.id)
+            .map(|book| (book.contract_id, book.net_pos()))
+            .collect();
+        for follower in &mut self.followers {
+            match user_data.account(&follower.name) {
+                Some(account) => follower.account_id = Some(account.id),
+                None => {
+                    let reason = format!("Follower account {} not found", follower.name);
+                    log.push(reason.clone());
+                    follower.halted = Some(reason);
+                }
+            }
+        }
+        log.push(format!(
+            "Copying {} to {}",
+            self.leader,
+            self.followers
+                .iter()
+                .map(|follower| format!("{} x{}", follower.name, follower.multiplier))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+        log
+    }
+    // Queues the orders that bring each follower in line with the leader. Returns lines to log
+    // and alerts for followers that stopped copying.
+    pub fn copy(
+        &mut self,
+        open: &mut AcctCommsChannel,
+        now: tokio::time::Instant,
+    ) -> (Vec<String>, Vec<String>) {
+        let mut log = Vec::new();
+        let mut alerts = Vec::new();
+        // a new connection has no ledger until its first sync, and an empty ledger reads as flat
+        if self.followers.is_empty() || open.user_data.is_none() {
+            return (log, alerts);
+        }
+        if self.leader_id.is_none() {
+            log.extend(self.bind(open));
+        }
+        let leader_id = match self.leader_id {
+            Some(leader_id) => leader_id,
+            None => return (log, alerts),
+        };
+        let contracts: BTreeSet<i64> = open
+            .ledger
+            .books
+            .values()
+            .filter(|book| book.account_id == leader_id)
+            .map(|book| book.contract_id)
+            .chain(self.leader_baselines.keys().copied())
+            .collect();
+        for follower in self.followers.iter_mut().filter(|follower| follower.halted.is_none()) {
+            let follower_id = match follower.account_id {
+                Some(follower_id) => follower_id,
+                None => continue,
+            };
+            for contract_id in &contracts {
+                let actual = open
+                    .ledger
+                    .book(follower_id, *contract_id)
+                    .map_or(0, |book| book.net_pos());
+                if let Some(order) = follower.working.get(contract_id) {
+                    if actual == order.expected_net {
+                        follower.working.remove(contract_id);
+                    } else if now.duration_since(order.sent_at).as_secs() >= COPY_ORDER_TIMEOUT_SECONDS {
+                        let reason = format!(
+                            "Copy order {} for {} on {} did not fill, copying stopped",
+                            order.cl_ord_id, order.qty, follower.name
+                        );
+                        alerts.push(reason.clone());
+                        follower.halted = Some(reason);
+                        break;
+                    } else {
+                        continue;
+                    }
+                }
+                let leader_change = open
+                    .ledger
+                    .book(leader_id, *contract_id)
+                    .map_or(0, |book| book.net_pos())
+                    - self.leader_baselines.get(contract_id).copied().unwrap_or(0);
+                let qty = follower.to_copy(*contract_id, leader_change);
+                if qty == 0 {
+                    continue;
+                }
+                let symbol = match open.ledger.terms.get(contract_id) {
+                    Some(terms) => terms.symbol.clone(),
+                    None => continue,
+                };
+                let action = if qty > 0 {
+                    OrderAction::Buy
+                } else {
+                    OrderAction::Sell
+                };
+                let intent = OrderIntent::market(follower_id, &symbol, action, qty.abs());
+                let name = &follower.name;
+                let submitted = open.submit_order(intent, |request_id, cl_ord_id| {
+                    let ticket = create_market_order_ticket(
+                        action, qty.abs(), name, follower_id, &symbol, cl_ord_id,
+                    );
+                    create_order_request(request_id, ticket)
+                });
+                let cl_ord_id = match submitted {
+                    Ok(cl_ord_id) => cl_ord_id,
+                    Err(_) => continue,
+                };
+                follower.working.insert(
+                    *contract_id,
+                    CopyOrder {
+                        cl_ord_id,
+                        qty,
+                        expected_net: actual + qty,
+                        sent_at: now,
+                    },
+                );
+                *follower.copied.entry(*contract_id).or_default() += qty;
+                log.push(format!("Copying {} {} to {}", qty, symbol, follower.name));
+            }
+        }
+        (log, alerts)
+    }
+}
+
+pub async fn watch_copier(
+    copier_rwl: TradeCopierRWL,
+    acct_comms_channel: AcctCommChannelRWL,
+    report_rwl: ReportRWL,
+) {
+    let mut interval =
+        tokio::time::interval(tokio::time::Duration::from_millis(COPY_CHECK_MILLIS));
+    loop {
+        interval.tick().await;
+        let mut open = acct_comms_channel.write().await;
+        let mut copier = copier_rwl.write().await;
+        let (log, alerts) = copier.copy(&mut open, tokio::time::Instant::now());
+        drop(copier);
+        drop(open);
+        for line in log {
+            program_log(&line, ResourceType::Trading, report_rwl.clone()).await;
+        }
+        for alert in alerts {
+            program_log(&alert, ResourceType::Trading, report_rwl.clone()).await;
+            let _ = send_txt_message(ALERT_PHONE_NUMBER, &alert).await;
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    api_client::ResourceType,
+    calendar::TradingCalendar,
+    reporting::{program_log, ReportRWL},
+    settings::{FRAME_STALE_MILLIS, MARKET_EVENT_STALE_MILLIS},
+};
+
+pub type FeedWatchdogRWL = Arc<tokio::sync::RwLock<FeedWatchdog>>;
+
+// Tracks when each socket last received any frame (heartbeats included) and when each
+// contract last received a chart, quote or dom event.
+#[derive(Debug, Clone)]
+pub struct FeedWatchdog {
+    pub frame_threshold: Duration,
+    pub market_event_threshold: Duration,
+    pub last_frame: HashMap<ResourceType, Instant>,
+    pub last_market_event: HashMap<i64, Instant>,
+    // watched but not traded, like the next contract before a roll. Their silence never drops the socket.
+    pub quiet_contracts: HashSet<i64>,
+}
+impl FeedWatchdog {
+    pub fn new() -> Self {
+        Self {
+            frame_threshold: Duration::from_millis(FRAME_STALE_MILLIS),
+            market_event_threshold: Duration::from_millis(MARKET_EVENT_STALE_MILLIS),
+            last_frame: HashMap::new(),
+            last_market_event: HashMap::new(),
+            quiet_contracts: HashSet::new(),
+        }
+    }
+    // A new connection gets a full threshold before it can be declared stale
+    pub fn start_session(&mut self, resource_type: ResourceType) {
+        let now = Instant::now();
+        self.last_frame.insert(resource_type, now);
+        if resource_type == ResourceType::MarketData {
+            for last_event in self.last_market_event.values_mut() {
+                *last_event = now;
+            }
+        }
+    }
+    pub fn record_frame(&mut self, resource_type: ResourceType) {
+        self.last_frame.insert(resource_type, Instant::now());
+    }
+    pub fn record_market_event(&mut self, contract_id: i64) {
+        self.last_market_event.insert(contract_id, Instant::now());
+    }
+    pub fn set_quiet(&mut self, contract_id: i64, quiet: bool) {
+        if quiet {
+            self.quiet_contracts.insert(contract_id);
+        } else {
+            self.quiet_contracts.remove(&contract_id);
+        }
+    }
+    // For contracts that are no longer subscribed
+    pub fn forget_contract(&mut self, contract_id: i64) {
+        self.last_market_event.remove(&contract_id);
+        self.quiet_contracts.remove(&contract_id);
+    }
+    pub fn socket_is_stale(&self, resource_type: ResourceType) -> bool {
+        self.last_frame
+            .get(&resource_type)
+            .is_some_and(|last_frame| last_frame.elapsed() > self.frame_threshold)
+    }
+    // `open_for` is how long the contract's session has been open, None while the market is closed.
+    // Silence only counts from the open, and a contract that never received an event is stale
+    // once the open is further back than the threshold.
+    pub fn contract_is_stale(&self, contract_id: i64, open_for: Option<Duration>) -> bool {
+        let open_for = match open_for {
+            Some(open_for) => open_for,
+            None => return false,
+        };
+        let silence = self
+            .last_market_event
+            .get(&contract_id)
+            .map_or(open_for, |last_event| last_event.elapsed().min(open_for));
+        silence > self.market_event_threshold
+    }
+    pub fn stale_contracts(&self, open_for: Option<Duration>) -> Vec<i64> {
+        self.last_market_event
+            .keys()
+            .filter(|contract_id| {
+                !self.quiet_contracts.contains(contract_id)
+                    && self.contract_is_stale(**contract_id, open_for)
+            })
+            .copied()
+            .collect()
+    }
+    // What strategies should check before entering a trade on a contract. Only this gates on the
+    // contract's own events, the socket is reconnected on frame silence alone.
+    pub fn is_stale(&self, contract_id: i64, open_for: Option<Duration>) -> bool {
+        self.socket_is_stale(ResourceType::MarketData) || self.contract_is_stale(contract_id, open_for)
+    }
+    pub fn stale_reason(&self, resource_type: ResourceType) -> Option<String> {
+        if self.socket_is_stale(resource_type) {
+            Some(format!(
+                "No frames received for more than {} ms",
+                self.frame_threshold.as_millis()
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+// How long the product's current session has been open, None while the market is closed
+pub fn session_open_for(
+    calendar: &TradingCalendar,
+    product: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Option<Duration> {
+    calendar
+        .current_session(product, now)
+        .map(|session| (now - session.open).to_std().unwrap_or_default())
+}
+impl Default for FeedWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Completes once the socket goes without frames for FRAME_STALE_MILLIS so the supervisor drops and reconnects it
+pub async fn watch_staleness(
+    watchdog: FeedWatchdogRWL,
+    resource_type: ResourceType,
+    report_rwl: ReportRWL,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        let reason = watchdog.read().await.stale_reason(resource_type);
+        if let Some(reason) = reason {
+            program_log(
+                &format!("Feed is stale, reconnecting. {}", reason),
+                resource_type,
+                report_rwl.clone(),
+            )
+            .await;
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watchdog_with_event(contract_id: i64, ago: Duration) -> FeedWatchdog {
+        let mut watchdog = FeedWatchdog::new();
+        watchdog.start_session(ResourceType::MarketData);
+        watchdog
+            .last_market_event
+            .insert(contract_id, Instant::now().checked_sub(ago).unwrap());
+        watchdog
+    }
+
+    #[test]
+    fn silent_contracts_never_drop_the_socket() {
+        let watchdog = watchdog_with_event(1, Duration::from_secs(3600));
+        assert!(watchdog.is_stale(1, Some(Duration::from_secs(7200))));
+        assert_eq!(watchdog.stale_reason(ResourceType::MarketData), None);
+    }
+
+    #[test]
+    fn contracts_are_not_stale_while_the_market_is_closed() {
+        let watchdog = watchdog_with_event(1, Duration::from_secs(3600));
+        assert!(!watchdog.is_stale(1, None));
+        assert!(!watchdog.is_stale(2, None));
+    }
+
+    #[test]
+    fn silence_counts_from_the_session_open() {
+        let watchdog = watchdog_with_event(1, Duration::from_secs(3600));
+        let threshold = watchdog.market_event_threshold;
+        assert!(!watchdog.is_stale(1, Some(threshold / 2)));
+        assert!(!watchdog.is_stale(2, Some(threshold / 2)));
+        assert!(watchdog.is_stale(2, Some(threshold * 2)));
+    }
+}